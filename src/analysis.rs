@@ -0,0 +1,318 @@
+use anyhow::{anyhow, Context, Result};
+use cache::Cache;
+use diagnostic::{Diagnostic, Location, Severity};
+use directive::{self, RuleLevel};
+use expression;
+use pest_duckyscript::mallardscript::ast::Statement;
+use preprocessor;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use {load, CompileOptions};
+
+/// Rules checked by variable analysis.
+pub static RULES: &[&str] = &[
+    "undeclared-variable",
+    "assignment-before-declaration",
+    "duplicate-declaration",
+    "unused-variable",
+];
+
+/// Use of a variable found while walking the program.
+#[derive(Debug, PartialEq)]
+enum Usage {
+    Declaration,
+    Assignment,
+    Read,
+}
+
+/// Variable usages of a program and its imports, in the order they're compiled.
+struct Analyzer<'a> {
+    /// Files being analyzed, used to detect circular dependencies.
+    imports_stack: Vec<PathBuf>,
+    cache: &'a mut Cache,
+    usages: Vec<(Usage, String, Location)>,
+    levels: Vec<RuleLevel>,
+}
+
+/// Analyze variables of MallardScript input path and its imports.
+/// Reports variables used or assigned before they're declared, variables declared more than
+/// once and variables that are never read.
+/// Internal variables such as `$_RANDOM_INT` are provided by the device and always declared.
+pub fn analyze_variables(
+    current_directory: PathBuf,
+    input_path: &str,
+    options: &CompileOptions,
+    cache: &mut Cache,
+) -> Result<Vec<Diagnostic>> {
+    let mut analyzer = Analyzer {
+        imports_stack: vec![],
+        cache,
+        usages: vec![],
+        levels: vec![],
+    };
+    analyzer.analyze_file(&current_directory, input_path, &HashMap::new(), options)?;
+
+    // Find where each variable is first declared.
+    let mut declarations_first: HashMap<&str, &Location> = HashMap::new();
+    for (usage, name, location) in &analyzer.usages {
+        if *usage == Usage::Declaration {
+            declarations_first.entry(name).or_insert(location);
+        }
+    }
+
+    let mut diagnostics = vec![];
+    let mut declarations: Vec<(&str, &Location)> = vec![];
+    let mut reads: HashSet<&str> = HashSet::new();
+    for (usage, name, location) in &analyzer.usages {
+        if is_internal_variable(name) {
+            continue;
+        }
+        let declared = declarations
+            .iter()
+            .find(|(declaration_name, _)| declaration_name == name);
+
+        match (usage, declared) {
+            (Usage::Declaration, Some((_, declaration_location))) => {
+                diagnostics.push(Diagnostic {
+                    rule: "duplicate-declaration",
+                    severity: Severity::Warning,
+                    location: location.clone(),
+                    message: format!(
+                        "Variable '${}' is already declared at '{}'.",
+                        name, declaration_location
+                    ),
+                });
+            }
+            (Usage::Declaration, None) => declarations.push((name, location)),
+            (_, Some(_)) => {}
+            (usage, None) => {
+                let action = if *usage == Usage::Read {
+                    "used"
+                } else {
+                    "assigned"
+                };
+                let (rule, message) = match declarations_first.get(name.as_str()) {
+                    Some(declaration_location) if *usage == Usage::Assignment => (
+                        "assignment-before-declaration",
+                        format!(
+                            "Variable '${}' is assigned before it's declared at '{}'.",
+                            name, declaration_location
+                        ),
+                    ),
+                    Some(declaration_location) => (
+                        "undeclared-variable",
+                        format!(
+                            "Variable '${}' is used before it's declared at '{}'.",
+                            name, declaration_location
+                        ),
+                    ),
+                    None => (
+                        "undeclared-variable",
+                        format!("Variable '${}' is {} but never declared.", name, action),
+                    ),
+                };
+                diagnostics.push(Diagnostic {
+                    rule,
+                    severity: Severity::Error,
+                    location: location.clone(),
+                    message,
+                });
+            }
+        }
+
+        if *usage == Usage::Read {
+            reads.insert(name);
+        }
+    }
+
+    for (name, location) in declarations {
+        if !reads.contains(name) {
+            diagnostics.push(Diagnostic {
+                rule: "unused-variable",
+                severity: Severity::Warning,
+                location: location.clone(),
+                message: format!("Variable '${}' is declared but never read.", name),
+            });
+        }
+    }
+
+    Ok(directive::apply_levels(diagnostics, &analyzer.levels))
+}
+
+impl Analyzer<'_> {
+    /// Analyze MallardScript input path with its import arguments.
+    fn analyze_file(
+        &mut self,
+        current_directory: &Path,
+        input_path: &str,
+        arguments: &HashMap<String, String>,
+        options: &CompileOptions,
+    ) -> Result<()> {
+        let source = load(
+            current_directory,
+            input_path,
+            arguments,
+            options,
+            self.cache,
+        )?;
+        if self.imports_stack.contains(&source.path) {
+            return Err(anyhow!("Circular dependency detected."));
+        }
+
+        self.levels.extend(source.levels);
+        self.imports_stack.push(source.path.clone());
+        self.analyze_statements(
+            input_path,
+            &source.path,
+            &source.statements,
+            &mut source.line_numbers.into_iter(),
+            options,
+        )?;
+        self.imports_stack.pop();
+
+        Ok(())
+    }
+
+    /// Record variable usages of statements, following imports.
+    fn analyze_statements(
+        &mut self,
+        input_path: &str,
+        input_path_expanded: &Path,
+        statements: &[Statement],
+        line_numbers: &mut impl Iterator<Item = usize>,
+        options: &CompileOptions,
+    ) -> Result<()> {
+        for statement in statements {
+            let location = Location {
+                input_path: String::from(input_path),
+                line_number: line_numbers.next().unwrap_or_default(),
+            };
+
+            match statement {
+                Statement::VariableDeclaration(variable) => {
+                    self.read_variables(&variable.assignment, &location);
+                    self.usages
+                        .push((Usage::Declaration, variable.name.clone(), location));
+                }
+                Statement::VariableAssignment(variable) => {
+                    self.read_variables(&variable.assignment, &location);
+                    self.usages
+                        .push((Usage::Assignment, variable.name.clone(), location));
+                }
+                Statement::CommandExfil(command) => {
+                    self.usages
+                        .push((Usage::Read, command.name.clone(), location));
+                }
+                Statement::CommandDelay(command) => {
+                    self.read_variables(&command.value, &location);
+                }
+                Statement::CommandDefaultDelay(command) => {
+                    self.read_variables(&command.value, &location);
+                }
+                Statement::CommandString(command) => {
+                    self.read_string_variables(&command.value, &location);
+                }
+                Statement::CommandStringln(command) => {
+                    self.read_string_variables(&command.value, &location);
+                }
+                Statement::CommandImport(command) => {
+                    let mut import_directory = input_path_expanded.to_path_buf();
+                    import_directory.pop();
+                    let (import_path, import_arguments) =
+                        preprocessor::decode_import(&command.value);
+                    self.analyze_file(&import_directory, &import_path, &import_arguments, options)
+                        .context(format!(
+                            "Unable to import file '{}' from '{}'.",
+                            import_path, input_path
+                        ))?;
+                }
+                Statement::BlockIf(block) => {
+                    self.read_variables(&block.expression, &location);
+                    self.analyze_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_true,
+                        line_numbers,
+                        options,
+                    )?;
+                    self.analyze_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_false,
+                        line_numbers,
+                        options,
+                    )?;
+                }
+                Statement::BlockWhile(block) => {
+                    match preprocessor::loop_count(&block.expression) {
+                        Some(count) => self.read_variables(&count, &location),
+                        None => self.read_variables(&block.expression, &location),
+                    }
+                    self.analyze_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements,
+                        line_numbers,
+                        options,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record reads of the variables referenced in an expression.
+    fn read_variables(&mut self, expression: &str, location: &Location) {
+        for name in variables(expression) {
+            self.usages.push((Usage::Read, name, location.clone()));
+        }
+    }
+
+    /// Record reads of the variables typed by a string command.
+    /// Only values parsed as expressions type variables, other values are typed as is.
+    fn read_string_variables(&mut self, value: &str, location: &Location) {
+        if expression::parse(value).is_ok() {
+            self.read_variables(value, location);
+        }
+    }
+}
+
+/// Names of the variables referenced as `$name` in an expression, outside of strings.
+pub fn variables(expression: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut string = false;
+    let mut characters = expression.char_indices().peekable();
+
+    while let Some((index, character)) = characters.next() {
+        match character {
+            '"' => string = !string,
+            '$' if !string => {
+                let mut end = index + 1;
+                while let Some(&(next_index, next)) = characters.peek() {
+                    if !(next.is_ascii_alphanumeric() || next == '_') {
+                        break;
+                    }
+                    end = next_index + 1;
+                    characters.next();
+                }
+                if end > index + 1 {
+                    names.push(String::from(&expression[index + 1..end]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    names
+}
+
+/// Is the variable provided by the device, such as `$_RANDOM_INT` or `$_CAPSLOCK_ON`.
+fn is_internal_variable(name: &str) -> bool {
+    let mut characters = name.chars();
+    characters.next() == Some('_')
+        && characters
+            .next()
+            .is_some_and(|character| character.is_ascii_uppercase())
+}
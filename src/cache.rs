@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use manifest;
+use pest_duckyscript::mallardscript::ast::{
+    Statement, StatementBlockIf, StatementBlockWhile, StatementCommandDefaultDelay,
+    StatementCommandDefine, StatementCommandDelay, StatementCommandExfil, StatementCommandImport,
+    StatementCommandKey, StatementCommandKeyValue, StatementCommandRem, StatementCommandString,
+    StatementCommandStringln, StatementEnd, StatementSingleCommand, StatementVariableAssignment,
+    StatementVariableDeclaration,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Entries kept in a cache directory when it's pruned, the least recently used are removed.
+pub static ENTRIES_MAX: usize = 1024;
+
+/// Parsed statements of MallardScript sources, keyed by the SHA-256 hash of their preprocessed
+/// contents. A changed file, or a file imported with different arguments, misses the cache and is
+/// parsed again. Files importing it still hit, parsing a file never reads its imports.
+/// Entries are kept in memory for the passes of a build and, with a directory, on disk so they
+/// outlive the process.
+#[derive(Debug, Default)]
+pub struct Cache {
+    /// Directory entries are written to as `<hash>.json`, created when it doesn't exist.
+    directory: Option<PathBuf>,
+    /// Cached statements, encoded as JSON as the AST can't be cloned.
+    entries: HashMap<String, serde_json::Value>,
+    /// Lookups that found parsed statements.
+    pub hits: usize,
+    /// Lookups that didn't, their sources were parsed.
+    pub misses: usize,
+}
+
+impl Cache {
+    /// Cache kept in memory and in a directory.
+    pub fn new(directory: PathBuf) -> Cache {
+        Cache {
+            directory: Some(directory),
+            ..Cache::default()
+        }
+    }
+
+    /// Parsed statements of preprocessed contents, parsing them when they aren't cached.
+    /// Entries that can't be read or written are warned about, the contents are parsed instead.
+    pub fn parse(
+        &mut self,
+        contents: &str,
+        parse: impl FnOnce() -> Result<Vec<Statement>>,
+    ) -> Result<Vec<Statement>> {
+        let key =
+            manifest::sha256(format!("{}\0{}", env!("CARGO_PKG_VERSION"), contents).as_bytes());
+
+        if let Some(statements) = self.entries.get(&key).and_then(decode_statements) {
+            self.hits += 1;
+            return Ok(statements);
+        }
+        if let Some(statements) = self.load(&key) {
+            if let Some(decoded) = decode_statements(&statements) {
+                self.entries.insert(key, statements);
+                self.hits += 1;
+                return Ok(decoded);
+            }
+            log::warn!("Ignoring invalid cache entry '{}'.", key);
+        }
+
+        self.misses += 1;
+        let statements = parse()?;
+        let encoded = encode_statements(&statements);
+        if let Err(error) = self.store(&key, &encoded) {
+            log::warn!("Ignoring cache entry that can't be written, {:#}", error);
+        }
+        self.entries.insert(key, encoded);
+
+        Ok(statements)
+    }
+
+    /// Remove the least recently used entries of the cache directory, keeping `ENTRIES_MAX`.
+    /// Entries that can't be removed are warned about.
+    pub fn prune(&self) {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return,
+        };
+        let mut entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+                    (path.extension()? == "json").then_some((modified, path))
+                })
+                .collect::<Vec<(std::time::SystemTime, PathBuf)>>(),
+            Err(_) => return,
+        };
+        if entries.len() <= ENTRIES_MAX {
+            return;
+        }
+
+        entries.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+        for (_, path) in &entries[ENTRIES_MAX..] {
+            if let Err(error) = std::fs::remove_file(path) {
+                log::warn!(
+                    "Unable to remove cache entry '{}', {}.",
+                    path.display(),
+                    error
+                );
+            }
+        }
+        log::info!(
+            "Pruned {} least recently used cache entries.",
+            entries.len() - ENTRIES_MAX
+        );
+    }
+
+    /// Load encoded statements from the cache directory, marking the entry as recently used.
+    fn load(&self, key: &str) -> Option<serde_json::Value> {
+        let path = self.directory.as_ref()?.join(format!("{}.json", key));
+        let contents = std::fs::read_to_string(&path).ok()?;
+        if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+            file.set_modified(std::time::SystemTime::now()).ok();
+        }
+
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Store encoded statements in the cache directory.
+    fn store(&self, key: &str, statements: &serde_json::Value) -> Result<()> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return Ok(()),
+        };
+        std::fs::create_dir_all(directory).with_context(|| {
+            format!(
+                "Unable to create cache directory '{}'.",
+                directory.display()
+            )
+        })?;
+
+        let path = directory.join(format!("{}.json", key));
+        std::fs::write(&path, serde_json::to_string(statements)?)
+            .with_context(|| format!("Unable to write cache entry '{}'.", path.display()))
+    }
+}
+
+/// Encode statements as JSON, each an object tagged with its kind.
+fn encode_statements(statements: &[Statement]) -> serde_json::Value {
+    serde_json::Value::Array(statements.iter().map(encode_statement).collect())
+}
+
+fn encode_statement(statement: &Statement) -> serde_json::Value {
+    match statement {
+        Statement::BlockIf(block) => serde_json::json!({
+            "kind": "if",
+            "expression": block.expression,
+            "statements_true": encode_statements(&block.statements_true),
+            "statements_false": encode_statements(&block.statements_false),
+        }),
+        Statement::BlockWhile(block) => serde_json::json!({
+            "kind": "while",
+            "expression": block.expression,
+            "statements": encode_statements(&block.statements),
+        }),
+        Statement::CommandDefaultDelay(command) => {
+            serde_json::json!({ "kind": "default_delay", "value": command.value })
+        }
+        Statement::CommandDefine(command) => {
+            serde_json::json!({ "kind": "define", "name": command.name, "value": command.value })
+        }
+        Statement::CommandDelay(command) => {
+            serde_json::json!({ "kind": "delay", "value": command.value })
+        }
+        Statement::CommandExfil(command) => {
+            serde_json::json!({ "kind": "exfil", "name": command.name })
+        }
+        Statement::CommandImport(command) => {
+            serde_json::json!({ "kind": "import", "value": command.value })
+        }
+        Statement::CommandKey(command) => serde_json::json!({
+            "kind": "key",
+            "statements": encode_statements(&command.statements),
+            "remaining_keys": command.remaining_keys,
+        }),
+        Statement::CommandKeyValue(command) => {
+            serde_json::json!({ "kind": "key_value", "name": command.name })
+        }
+        Statement::CommandRem(command) => {
+            serde_json::json!({ "kind": "rem", "value": command.value })
+        }
+        Statement::CommandString(command) => {
+            serde_json::json!({ "kind": "string", "value": command.value })
+        }
+        Statement::CommandStringln(command) => {
+            serde_json::json!({ "kind": "stringln", "value": command.value })
+        }
+        Statement::End(_) => serde_json::json!({ "kind": "end" }),
+        Statement::SingleCommand(command) => {
+            serde_json::json!({ "kind": "single", "name": command.name })
+        }
+        Statement::VariableAssignment(variable) => serde_json::json!({
+            "kind": "assignment",
+            "name": variable.name,
+            "assignment": variable.assignment,
+        }),
+        Statement::VariableDeclaration(variable) => serde_json::json!({
+            "kind": "declaration",
+            "name": variable.name,
+            "assignment": variable.assignment,
+        }),
+    }
+}
+
+/// Decode statements encoded by `encode_statements`, `None` when they're invalid.
+fn decode_statements(statements: &serde_json::Value) -> Option<Vec<Statement>> {
+    statements
+        .as_array()?
+        .iter()
+        .map(decode_statement)
+        .collect()
+}
+
+fn decode_statement(statement: &serde_json::Value) -> Option<Statement> {
+    let field = |name: &str| statement.get(name)?.as_str().map(String::from);
+    let statements = |name: &str| decode_statements(statement.get(name)?);
+
+    Some(match statement.get("kind")?.as_str()? {
+        "if" => Statement::BlockIf(StatementBlockIf {
+            expression: field("expression")?,
+            statements_true: statements("statements_true")?,
+            statements_false: statements("statements_false")?,
+        }),
+        "while" => Statement::BlockWhile(StatementBlockWhile {
+            expression: field("expression")?,
+            statements: statements("statements")?,
+        }),
+        "default_delay" => Statement::CommandDefaultDelay(StatementCommandDefaultDelay {
+            value: field("value")?,
+        }),
+        "define" => Statement::CommandDefine(StatementCommandDefine {
+            name: field("name")?,
+            value: field("value")?,
+        }),
+        "delay" => Statement::CommandDelay(StatementCommandDelay {
+            value: field("value")?,
+        }),
+        "exfil" => Statement::CommandExfil(StatementCommandExfil {
+            name: field("name")?,
+        }),
+        "import" => Statement::CommandImport(StatementCommandImport {
+            value: field("value")?,
+        }),
+        "key" => Statement::CommandKey(StatementCommandKey {
+            statements: statements("statements")?,
+            remaining_keys: field("remaining_keys")?,
+        }),
+        "key_value" => Statement::CommandKeyValue(StatementCommandKeyValue {
+            name: field("name")?,
+        }),
+        "rem" => Statement::CommandRem(StatementCommandRem {
+            value: field("value")?,
+        }),
+        "string" => Statement::CommandString(StatementCommandString {
+            value: field("value")?,
+        }),
+        "stringln" => Statement::CommandStringln(StatementCommandStringln {
+            value: field("value")?,
+        }),
+        "end" => Statement::End(StatementEnd {}),
+        "single" => Statement::SingleCommand(StatementSingleCommand {
+            name: field("name")?,
+        }),
+        "assignment" => Statement::VariableAssignment(StatementVariableAssignment {
+            name: field("name")?,
+            assignment: field("assignment")?,
+        }),
+        "declaration" => Statement::VariableDeclaration(StatementVariableDeclaration {
+            name: field("name")?,
+            assignment: field("assignment")?,
+        }),
+        _ => return None,
+    })
+}
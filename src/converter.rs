@@ -0,0 +1,380 @@
+use anyhow::{Context, Result};
+use pest_duckyscript::duckyscript::{self, ast::Statement};
+use std::collections::HashMap;
+
+/// Repeated sequences extracted into modules have at least this many lines when none is provided.
+pub static MODULE_LINES_MIN: usize = 3;
+
+/// Marker of the `REM` lines that legacy `REPEAT` commands are parsed as.
+static REPEAT_MARKER: &str = "\u{1e}REPEAT ";
+
+/// Indentation of MallardScript blocks.
+static INDENTATION: &str = "  ";
+
+/// Repeated sequences extracted into modules have at most this many statements.
+static SEQUENCE_UNITS_MAX: usize = 64;
+
+/// Base of the rolling hash sequences of statements are compared by.
+static HASH_BASE: u64 = 1_000_003;
+
+/// MallardScript converted from DuckyScript, with the modules it imports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conversion {
+    pub contents: String,
+    pub modules: Vec<Module>,
+}
+
+/// Repeated sequence of statements extracted into an importable module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    /// File name the module is imported as, such as `module_1.ducky`.
+    pub name: String,
+    pub contents: String,
+    /// Number of times the module is imported.
+    pub imports: usize,
+}
+
+/// Converted top level statement, with the lines it's written as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Unit {
+    lines: Vec<String>,
+    /// Times the statement repeats after it runs, from legacy `REPEAT` commands.
+    repeat: u64,
+    /// Is the statement a `STRING` that can type its new line.
+    string: bool,
+}
+
+impl Unit {
+    /// Statement written as a single line.
+    fn line(line: String) -> Unit {
+        Unit {
+            string: line.starts_with("STRING "),
+            lines: vec![line],
+            repeat: 0,
+        }
+    }
+
+    /// Lines of the statement, repeated statements run in a runtime `LOOP` so large counts aren't
+    /// unrolled.
+    fn render(&self) -> Vec<String> {
+        if self.repeat == 0 {
+            return self.lines.clone();
+        }
+
+        let mut lines = vec![format!("LOOP {}", self.repeat + 1)];
+        lines.extend(indent(&self.lines));
+        lines.push(String::from("END_LOOP"));
+        lines
+    }
+
+    /// Can the statement be imported from a module more than once.
+    /// Declarations and defines would be repeated.
+    fn is_extractable(&self) -> bool {
+        !self.lines.iter().any(|line| {
+            let line = line.trim_start();
+            line.starts_with("VAR ") || line.starts_with("DEFINE ") || line.starts_with("IMPORT ")
+        })
+    }
+}
+
+/// Convert DuckyScript contents into MallardScript.
+/// Legacy DuckyScript 1.0 `DEFAULT_DELAY` and `REPEAT` commands are upgraded, `REPEAT` becomes a
+/// runtime `LOOP`, and a `STRING` followed by `ENTER` becomes a `STRINGLN` unless the
+/// contents set a non-zero `DEFAULTDELAY`, which would delay the `ENTER`. Contents must be indented
+/// with two spaces.
+/// Repeated sequences of top level statements with at least the provided number of lines are
+/// extracted into modules, when provided.
+pub fn convert(contents: &str, module_lines_min: Option<usize>) -> Result<Conversion> {
+    let statements = duckyscript::parser::parse_document(upgrade_legacy(contents))
+        .with_context(|| "Unable to parse DuckyScript input.")?;
+
+    let mut units = convert_statements(&statements, !sets_default_delay(&statements));
+
+    let modules = match module_lines_min {
+        Some(module_lines_min) => extract_modules(&mut units, module_lines_min),
+        None => vec![],
+    };
+
+    Ok(Conversion {
+        contents: render(&units),
+        modules,
+    })
+}
+
+/// Do statements, or the statements of their blocks, set a non-zero `DEFAULTDELAY`.
+fn sets_default_delay(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::CommandDefaultDelay(command) => command.value.trim() != "0",
+        Statement::BlockIf(block) => {
+            sets_default_delay(&block.statements_true)
+                || sets_default_delay(&block.statements_false)
+        }
+        Statement::BlockWhile(block) => sets_default_delay(&block.statements),
+        _ => false,
+    })
+}
+
+/// Upgrade legacy DuckyScript 1.0 commands the DuckyScript grammar doesn't parse.
+/// `REPEAT` is kept as a marked `REM` line and applied once parsed.
+fn upgrade_legacy(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| {
+            let indentation = &line[..line.len() - line.trim_start().len()];
+            let command = line.trim_start();
+            if let Some(value) = command.strip_prefix("DEFAULT_DELAY ") {
+                format!("{}DEFAULTDELAY {}", indentation, value)
+            } else if let Some(count) = command.strip_prefix("REPEAT ") {
+                format!("{}REM {}{}", indentation, REPEAT_MARKER, count.trim())
+            } else {
+                String::from(line)
+            }
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// Convert statements of a block into units.
+fn convert_statements(statements: &[Statement], merge_strings: bool) -> Vec<Unit> {
+    let mut units: Vec<Unit> = vec![];
+
+    for statement in statements {
+        let unit = match statement {
+            Statement::BlockIf(block) => {
+                let mut lines = vec![format!("IF {} THEN", block.expression)];
+                lines.extend(indent(&render_lines(&convert_statements(
+                    &block.statements_true,
+                    merge_strings,
+                ))));
+                if !block.statements_false.is_empty() {
+                    lines.push(String::from("ELSE"));
+                    lines.extend(indent(&render_lines(&convert_statements(
+                        &block.statements_false,
+                        merge_strings,
+                    ))));
+                }
+                lines.push(String::from("END_IF"));
+                Unit {
+                    lines,
+                    repeat: 0,
+                    string: false,
+                }
+            }
+            Statement::BlockWhile(block) => {
+                let mut lines = vec![format!("WHILE {}", block.expression)];
+                lines.extend(indent(&render_lines(&convert_statements(
+                    &block.statements,
+                    merge_strings,
+                ))));
+                lines.push(String::from("END_WHILE"));
+                Unit {
+                    lines,
+                    repeat: 0,
+                    string: false,
+                }
+            }
+            Statement::CommandRem(command) => {
+                if let Some(count) = command.value.trim().strip_prefix(REPEAT_MARKER) {
+                    if let (Some(previous), Ok(count)) = (units.last_mut(), count.parse::<u64>()) {
+                        previous.repeat += count;
+                        previous.string = false;
+                        continue;
+                    }
+                    log::warn!("Ignoring 'REPEAT {}' without a previous statement.", count);
+                    continue;
+                }
+                Unit::line(command_line("REM", &command.value))
+            }
+            Statement::CommandKey(command) => {
+                let keys = collect_command_key_values(&command.statements, &command.remaining_keys);
+                if merge_strings && keys == ["ENTER"] {
+                    if let Some(previous) = units.last_mut().filter(|previous| previous.string) {
+                        previous.lines[0] = previous.lines[0].replacen("STRING ", "STRINGLN ", 1);
+                        previous.string = false;
+                        continue;
+                    }
+                }
+                Unit::line(keys.join(" "))
+            }
+            Statement::CommandDefaultDelay(command) => {
+                Unit::line(command_line("DEFAULTDELAY", &command.value))
+            }
+            Statement::CommandDefine(command) => {
+                Unit::line(format!("DEFINE {} {}", command.name, command.value))
+            }
+            Statement::CommandDelay(command) => Unit::line(command_line("DELAY", &command.value)),
+            Statement::CommandString(command) => Unit::line(command_line("STRING", &command.value)),
+            Statement::CommandStringln(command) => {
+                Unit::line(command_line("STRINGLN", &command.value))
+            }
+            Statement::CommandExfil(command) => Unit::line(format!("EXFIL ${}", command.name)),
+            Statement::SingleCommand(command) => Unit::line(command.name.clone()),
+            Statement::CommandKeyValue(command) => Unit::line(command.name.clone()),
+            Statement::VariableAssignment(variable) => {
+                Unit::line(format!("${} = {}", variable.name, variable.assignment))
+            }
+            Statement::VariableDeclaration(variable) => {
+                Unit::line(format!("VAR ${} = {}", variable.name, variable.assignment))
+            }
+            Statement::End(_) => continue,
+        };
+        units.push(unit);
+    }
+
+    units
+}
+
+/// Line of a command with its value, if it has one.
+fn command_line(command: &str, value: &str) -> String {
+    if value.is_empty() {
+        String::from(command)
+    } else {
+        format!("{} {}", command, value)
+    }
+}
+
+/// Collect the keys of a DuckyScript key command and its nested key commands.
+fn collect_command_key_values(statements: &[Statement], remaining_keys: &str) -> Vec<String> {
+    let mut keys = vec![];
+    for statement in statements {
+        match statement {
+            Statement::CommandKey(command) => keys.extend(collect_command_key_values(
+                &command.statements,
+                &command.remaining_keys,
+            )),
+            Statement::CommandKeyValue(command) => keys.push(command.name.clone()),
+            _ => {}
+        }
+    }
+    if !remaining_keys.is_empty() {
+        keys.push(String::from(remaining_keys));
+    }
+
+    keys
+}
+
+/// Indent lines into a block.
+fn indent(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| {
+            if line.is_empty() {
+                line.clone()
+            } else {
+                format!("{}{}", INDENTATION, line)
+            }
+        })
+        .collect()
+}
+
+/// Lines of units.
+fn render_lines(units: &[Unit]) -> Vec<String> {
+    units.iter().flat_map(Unit::render).collect()
+}
+
+/// Write units as MallardScript contents.
+fn render(units: &[Unit]) -> String {
+    render_lines(units)
+        .into_iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// Extract repeated sequences of units into modules, replacing them with imports.
+/// The longest sequences are extracted first.
+fn extract_modules(units: &mut Vec<Unit>, module_lines_min: usize) -> Vec<Module> {
+    let mut modules = vec![];
+
+    while let Some((length, starts)) = find_repeated(units, module_lines_min) {
+        let name = format!("module_{}.ducky", modules.len() + 1);
+        log::info!(
+            "Extracting {} statements imported {} times into '{}'.",
+            length,
+            starts.len(),
+            name
+        );
+        let contents = render(&units[starts[0]..starts[0] + length]);
+
+        // Replace from the last sequence so the starts of the others don't move.
+        for start in starts.iter().rev() {
+            units.splice(
+                *start..*start + length,
+                std::iter::once(Unit::line(format!("IMPORT \"{}\"", name))),
+            );
+        }
+
+        modules.push(Module {
+            name,
+            contents,
+            imports: starts.len(),
+        });
+    }
+
+    modules
+}
+
+/// Find the longest sequence of units repeated without overlapping, with its starts.
+/// Sequences of the same length that repeat the most are found first. Sequences are compared by
+/// a rolling hash of their units, so each length is searched in a single pass, and have at most
+/// `SEQUENCE_UNITS_MAX` units.
+fn find_repeated(units: &[Unit], module_lines_min: usize) -> Option<(usize, Vec<usize>)> {
+    // Identify equal units by number, with the lines and extractable units before each unit.
+    let mut identifiers: HashMap<&Unit, u64> = HashMap::new();
+    let mut unit_identifiers = Vec::with_capacity(units.len());
+    let mut lines_before = vec![0];
+    let mut unextractable_before = vec![0];
+    for unit in units {
+        let identifier = identifiers.len() as u64 + 1;
+        unit_identifiers.push(*identifiers.entry(unit).or_insert(identifier));
+        lines_before.push(lines_before.last().unwrap() + unit.render().len());
+        unextractable_before
+            .push(unextractable_before.last().unwrap() + usize::from(!unit.is_extractable()));
+    }
+
+    for length in (1..=(units.len() / 2).min(SEQUENCE_UNITS_MAX)).rev() {
+        // Weight of the unit leaving the hash as the sequence moves forward.
+        let weight = (1..length).fold(1u64, |weight, _| weight.wrapping_mul(HASH_BASE));
+        let mut hash = unit_identifiers[..length - 1]
+            .iter()
+            .fold(0u64, |hash, identifier| {
+                hash.wrapping_mul(HASH_BASE).wrapping_add(*identifier)
+            });
+
+        let mut sequences: HashMap<u64, Vec<usize>> = HashMap::new();
+        for start in 0..=units.len() - length {
+            let end = start + length;
+            hash = hash
+                .wrapping_mul(HASH_BASE)
+                .wrapping_add(unit_identifiers[end - 1]);
+            let sequence_hash = hash;
+            hash = hash.wrapping_sub(unit_identifiers[start].wrapping_mul(weight));
+
+            if lines_before[end] - lines_before[start] < module_lines_min
+                || unextractable_before[end] != unextractable_before[start]
+            {
+                continue;
+            }
+
+            // Hashes that collide with a different sequence are skipped.
+            let starts = sequences.entry(sequence_hash).or_default();
+            if starts.first().is_some_and(|first| {
+                unit_identifiers[*first..first + length] != unit_identifiers[start..end]
+            }) {
+                continue;
+            }
+            if starts.last().is_none_or(|last| start >= last + length) {
+                starts.push(start);
+            }
+        }
+
+        let repeated = sequences
+            .into_values()
+            .filter(|starts| starts.len() > 1)
+            .max_by_key(|starts| (starts.len(), std::cmp::Reverse(starts[0])));
+        if let Some(starts) = repeated {
+            return Some((length, starts));
+        }
+    }
+
+    None
+}
@@ -0,0 +1,237 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// Hak5 device a payload is compiled for, each runs a different DuckyScript subset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Device {
+    RubberDucky,
+    Omg,
+    BashBunny,
+    KeyCroc,
+}
+
+/// Support of a command on a device, with the reason when it isn't fully supported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Support {
+    Supported,
+    /// The command may not run as expected, it's compiled with a warning.
+    Warning(&'static str),
+    /// The command doesn't run, it's a compile error.
+    Unsupported(&'static str),
+}
+
+/// Commands and constructs devices support differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Group {
+    Button,
+    Led,
+    Attackmode,
+    Storage,
+    UsbIdentity,
+    Exfil,
+    /// `VAR`, assignments and variables referenced as `$name`.
+    Variables,
+    /// `IF` blocks.
+    Conditionals,
+    /// `WHILE` and `LOOP` blocks.
+    Loops,
+}
+
+/// Commands of each group.
+static GROUPS: &[(Group, &[&str])] = &[
+    (
+        Group::Button,
+        &[
+            "WAIT_FOR_BUTTON_PRESS",
+            "BUTTON_DEF",
+            "DISABLE_BUTTON",
+            "ENABLE_BUTTON",
+        ],
+    ),
+    (Group::Led, &["LED_OFF", "LED_R", "LED_G"]),
+    (
+        Group::Attackmode,
+        &["ATTACKMODE", "SAVE_ATTACKMODE", "RESTORE_ATTACKMODE"],
+    ),
+    (Group::Storage, &["HIDE_PAYLOAD", "RESTORE_PAYLOAD"]),
+    (
+        Group::UsbIdentity,
+        &[
+            "VID_RANDOM",
+            "PID_RANDOM",
+            "MAN_RANDOM",
+            "PROD_RANDOM",
+            "SERIAL_RANDOM",
+        ],
+    ),
+    (Group::Exfil, &["EXFIL"]),
+    (Group::Variables, &["VAR"]),
+    (Group::Conditionals, &["IF"]),
+    (Group::Loops, &["WHILE", "LOOP"]),
+];
+
+/// Groups the O.MG cable doesn't fully support.
+static PROFILE_OMG: &[(Group, Support)] = &[
+    (Group::Button, Support::Unsupported("it has no button")),
+    (Group::Led, Support::Unsupported("it has no LED")),
+    (
+        Group::Storage,
+        Support::Unsupported("it has no mass storage"),
+    ),
+    (Group::Exfil, Support::Unsupported("it has no loot storage")),
+    (
+        Group::Attackmode,
+        Support::Warning("its USB mode is set from its web interface"),
+    ),
+    (
+        Group::UsbIdentity,
+        Support::Warning("its USB identity is set from its web interface"),
+    ),
+];
+
+/// Groups the Bash Bunny doesn't fully support.
+static PROFILE_BASH_BUNNY: &[(Group, Support)] = &[
+    (
+        Group::Button,
+        Support::Warning("its button is handled by bash payloads"),
+    ),
+    (
+        Group::Led,
+        Support::Warning("LED is a bash payload command"),
+    ),
+    (
+        Group::Attackmode,
+        Support::Warning("ATTACKMODE is a bash payload command"),
+    ),
+    (
+        Group::Storage,
+        Support::Unsupported("its storage is set with ATTACKMODE in bash payloads"),
+    ),
+    (
+        Group::UsbIdentity,
+        Support::Warning("its USB identity is set with ATTACKMODE in bash payloads"),
+    ),
+    (
+        Group::Exfil,
+        Support::Unsupported("loot is saved to its storage by bash payloads"),
+    ),
+    (
+        Group::Variables,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+    (
+        Group::Conditionals,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+    (
+        Group::Loops,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+];
+
+/// Groups the Key Croc doesn't fully support.
+static PROFILE_KEY_CROC: &[(Group, Support)] = &[
+    (
+        Group::Button,
+        Support::Warning("its button is handled by bash payloads"),
+    ),
+    (
+        Group::Led,
+        Support::Warning("LED is a bash payload command"),
+    ),
+    (
+        Group::Attackmode,
+        Support::Warning("ATTACKMODE is a bash payload command"),
+    ),
+    (
+        Group::Storage,
+        Support::Unsupported("its storage is set with ATTACKMODE in bash payloads"),
+    ),
+    (
+        Group::UsbIdentity,
+        Support::Unsupported("it clones the USB identity of the keyboard plugged into it"),
+    ),
+    (
+        Group::Exfil,
+        Support::Unsupported("loot is saved to its storage by bash payloads"),
+    ),
+    (
+        Group::Variables,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+    (
+        Group::Conditionals,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+    (
+        Group::Loops,
+        Support::Unsupported("it runs DuckyScript 1.0, compile with '--target duckyscript1'"),
+    ),
+];
+
+impl std::str::FromStr for Device {
+    type Err = anyhow::Error;
+
+    /// Parse a device, `rubber-ducky`, `omg`, `bash-bunny` or `key-croc`.
+    fn from_str(device: &str) -> Result<Self> {
+        match device {
+            "rubber-ducky" => Ok(Device::RubberDucky),
+            "omg" => Ok(Device::Omg),
+            "bash-bunny" => Ok(Device::BashBunny),
+            "key-croc" => Ok(Device::KeyCroc),
+            _ => Err(anyhow!(
+                "Invalid device '{}', expected 'rubber-ducky', 'omg', 'bash-bunny' or 'key-croc'.",
+                device
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Device::RubberDucky => write!(f, "the USB Rubber Ducky"),
+            Device::Omg => write!(f, "the O.MG cable"),
+            Device::BashBunny => write!(f, "the Bash Bunny"),
+            Device::KeyCroc => write!(f, "the Key Croc"),
+        }
+    }
+}
+
+impl Device {
+    /// Name of the device, as it's parsed.
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::RubberDucky => "rubber-ducky",
+            Device::Omg => "omg",
+            Device::BashBunny => "bash-bunny",
+            Device::KeyCroc => "key-croc",
+        }
+    }
+
+    /// Support of a command or construct on the device, such as `EXFIL`, `LED_R`, `WHILE` or a
+    /// variable referenced as `$name`.
+    /// The USB Rubber Ducky supports every command.
+    pub fn support(self, command: &str) -> Support {
+        let group = match GROUPS
+            .iter()
+            .find(|(_, commands)| commands.contains(&command))
+        {
+            Some((group, _)) => *group,
+            None if command.starts_with('$') => Group::Variables,
+            None => return Support::Supported,
+        };
+        let profile = match self {
+            Device::RubberDucky => return Support::Supported,
+            Device::Omg => PROFILE_OMG,
+            Device::BashBunny => PROFILE_BASH_BUNNY,
+            Device::KeyCroc => PROFILE_KEY_CROC,
+        };
+
+        profile
+            .iter()
+            .find(|(profile_group, _)| *profile_group == group)
+            .map(|(_, support)| *support)
+            .unwrap_or(Support::Supported)
+    }
+}
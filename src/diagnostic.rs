@@ -0,0 +1,137 @@
+use pest_duckyscript::mallardscript::ast::Statement;
+use std::fmt;
+
+/// Location of a statement in a MallardScript source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub input_path: String,
+    pub line_number: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.input_path, self.line_number)
+    }
+}
+
+/// Severity of a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Problem found in MallardScript source, identified by a rule such as `unused-variable`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} '{}' at '{}': {}",
+            self.severity, self.rule, self.location, self.message
+        )
+    }
+}
+
+/// Locate parsed statements in their preprocessed contents.
+/// Returns a source line number for each statement in the order they're walked: a block comes
+/// before its statements and the statements of an `IF` come before the statements of its `ELSE`.
+/// Statements nested in key commands share the line of their command and aren't included.
+pub fn locate(statements: &[Statement], contents: &str, line_numbers: &[usize]) -> Vec<usize> {
+    let lines = contents.lines().collect::<Vec<&str>>();
+    let mut line_index = 0;
+    let mut line_indexes = vec![];
+    locate_statements(statements, &lines, &mut line_index, &mut line_indexes);
+
+    line_indexes
+        .into_iter()
+        .map(|line_index| {
+            line_numbers
+                .get(line_index)
+                .copied()
+                .unwrap_or(line_index + 1)
+        })
+        .collect()
+}
+
+/// Locate statements starting at the provided line, advancing past the lines they use.
+fn locate_statements(
+    statements: &[Statement],
+    lines: &[&str],
+    line_index: &mut usize,
+    line_indexes: &mut Vec<usize>,
+) {
+    let skip_blank_lines = |line_index: &mut usize| {
+        while lines
+            .get(*line_index)
+            .is_some_and(|line| line.trim().is_empty())
+        {
+            *line_index += 1;
+        }
+    };
+
+    for statement in statements {
+        // The end of the document doesn't use a line.
+        if let Statement::End(_) = statement {
+            line_indexes.push(lines.len().saturating_sub(1));
+            continue;
+        }
+
+        skip_blank_lines(line_index);
+        line_indexes.push(*line_index);
+        *line_index += 1;
+
+        match statement {
+            Statement::BlockIf(block) => {
+                locate_statements(&block.statements_true, lines, line_index, line_indexes);
+                skip_blank_lines(line_index);
+                if lines.get(*line_index).map(|line| line.trim()) == Some("ELSE") {
+                    *line_index += 1;
+                }
+                locate_statements(&block.statements_false, lines, line_index, line_indexes);
+                skip_blank_lines(line_index);
+                *line_index += 1;
+            }
+            Statement::BlockWhile(block) => {
+                locate_statements(&block.statements, lines, line_index, line_indexes);
+                skip_blank_lines(line_index);
+                *line_index += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Count a statement and the statements of its blocks, as they're located.
+pub fn count_statements(statement: &Statement) -> usize {
+    match statement {
+        Statement::BlockIf(block) => {
+            1 + block
+                .statements_true
+                .iter()
+                .chain(&block.statements_false)
+                .map(count_statements)
+                .sum::<usize>()
+        }
+        Statement::BlockWhile(block) => {
+            1 + block.statements.iter().map(count_statements).sum::<usize>()
+        }
+        _ => 1,
+    }
+}
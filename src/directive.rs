@@ -0,0 +1,273 @@
+use analysis;
+use anyhow::{anyhow, Result};
+use diagnostic::{self, Diagnostic, Severity};
+use lint;
+use metadata;
+use pest_duckyscript::mallardscript::ast::{Statement, StatementBlockIf, StatementBlockWhile};
+
+/// Prefix of the `REM` lines read as compiler directives.
+static DIRECTIVE_PREFIX: &str = "mallard:";
+
+/// Compiler directive written as `REM mallard: ...`, applied to the statement that follows it.
+/// Directives written as `REM mallard: file ...` apply to the whole file instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// Set the severity of the listed rules' diagnostics, `None` suppresses them.
+    Level(Option<Severity>, Vec<String>),
+    /// Set the severity of the listed rules' diagnostics in the whole file.
+    FileLevel(Option<Severity>, Vec<String>),
+    /// Don't compile the statement.
+    Strip,
+}
+
+/// Severity of some rules' diagnostics on lines of a file, `None` when they're suppressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleLevel {
+    pub input_path: String,
+    /// Lines of the statement the level applies to, `None` for the whole file.
+    pub line_numbers: Option<Vec<usize>>,
+    pub rules: Vec<String>,
+    pub severity: Option<Severity>,
+}
+
+impl RuleLevel {
+    /// Does the level apply to the diagnostic.
+    pub fn applies(&self, diagnostic: &Diagnostic) -> bool {
+        diagnostic.location.input_path == self.input_path
+            && self
+                .line_numbers
+                .as_ref()
+                .is_none_or(|line_numbers| line_numbers.contains(&diagnostic.location.line_number))
+            && self.rules.iter().any(|rule| rule == diagnostic.rule)
+    }
+}
+
+/// Set the severity of diagnostics from the rule levels applying to them, removing suppressed
+/// diagnostics. The innermost level applies, as levels of a block come before its statements',
+/// and levels of a statement take precedence over levels of its file.
+pub fn apply_levels(diagnostics: Vec<Diagnostic>, levels: &[RuleLevel]) -> Vec<Diagnostic> {
+    diagnostics
+        .into_iter()
+        .filter_map(|mut diagnostic| {
+            let applying = |file: bool| {
+                levels.iter().rev().find(|level| {
+                    level.line_numbers.is_none() == file && level.applies(&diagnostic)
+                })
+            };
+            match applying(false).or_else(|| applying(true)) {
+                Some(RuleLevel { severity: None, .. }) => return None,
+                Some(RuleLevel {
+                    severity: Some(severity),
+                    ..
+                }) => diagnostic.severity = *severity,
+                None => {}
+            }
+            Some(diagnostic)
+        })
+        .collect()
+}
+
+/// Parse the directives of a `REM` value, `None` when it's an ordinary comment.
+pub fn parse(input_path: &str, line_number: usize, value: &str) -> Result<Option<Vec<Directive>>> {
+    let directives = match value.trim().strip_prefix(DIRECTIVE_PREFIX) {
+        Some(directives) => directives.trim(),
+        None => return Ok(None),
+    };
+
+    let (file, mut directives) = match directives.strip_prefix("file ") {
+        Some(directives) => (true, directives.trim()),
+        None => (false, directives),
+    };
+    let mut directives_parsed = vec![];
+    while !directives.is_empty() {
+        if let Some(remaining) = directives.strip_prefix("strip") {
+            if file {
+                return Err(anyhow!(
+                    "Directive 'strip' at '{}:{}' can't apply to a whole file.",
+                    input_path,
+                    line_number
+                ));
+            }
+            directives_parsed.push(Directive::Strip);
+            directives = remaining;
+        } else if let Some((severity, rules, remaining)) = [
+            ("allow(", None),
+            ("warning(", Some(Severity::Warning)),
+            ("error(", Some(Severity::Error)),
+        ]
+        .iter()
+        .find_map(|(prefix, severity)| {
+            directives
+                .strip_prefix(prefix)
+                .and_then(|rules| rules.split_once(')'))
+                .map(|(rules, remaining)| (*severity, rules, remaining))
+        }) {
+            let rules = rules
+                .split(',')
+                .map(str::trim)
+                .map(String::from)
+                .collect::<Vec<String>>();
+            if let Some(rule) = rules.iter().find(|rule| !is_rule(rule)) {
+                return Err(anyhow!(
+                    "Unknown rule '{}' at '{}:{}'.",
+                    rule,
+                    input_path,
+                    line_number
+                ));
+            }
+            directives_parsed.push(if file {
+                Directive::FileLevel(severity, rules)
+            } else {
+                Directive::Level(severity, rules)
+            });
+            directives = remaining;
+        } else {
+            return Err(anyhow!(
+                "Unknown directive '{}' at '{}:{}', expected 'allow(rule, ...)', 'warning(rule, ...)', 'error(rule, ...)' or 'strip'.",
+                directives,
+                input_path,
+                line_number
+            ));
+        }
+        directives = directives
+            .trim_start_matches(|character: char| character == ',' || character.is_whitespace());
+    }
+
+    Ok(Some(directives_parsed))
+}
+
+/// Apply the directives of parsed statements.
+/// Directive and metadata `REM` lines and stripped statements are removed along with their line
+/// numbers, as returned by `diagnostic::locate`, and `allow`, `warning` and `error` directives are
+/// returned as rule levels. Metadata is read from the entry file by
+/// `metadata::Metadata::parse_front_matter`.
+pub fn apply(
+    input_path: &str,
+    statements: Vec<Statement>,
+    line_numbers: Vec<usize>,
+) -> Result<(Vec<Statement>, Vec<usize>, Vec<RuleLevel>)> {
+    let mut line_numbers_applied = Vec::with_capacity(line_numbers.len());
+    let mut levels = vec![];
+    let statements = apply_statements(
+        input_path,
+        statements,
+        &mut line_numbers.into_iter(),
+        &mut line_numbers_applied,
+        &mut levels,
+    )?;
+
+    Ok((statements, line_numbers_applied, levels))
+}
+
+/// Apply the directives of a block's statements.
+fn apply_statements(
+    input_path: &str,
+    statements: Vec<Statement>,
+    line_numbers: &mut std::vec::IntoIter<usize>,
+    line_numbers_applied: &mut Vec<usize>,
+    levels: &mut Vec<RuleLevel>,
+) -> Result<Vec<Statement>> {
+    let mut statements_applied = Vec::with_capacity(statements.len());
+    let mut directives: Vec<Directive> = vec![];
+
+    for statement in statements {
+        let statement_count = diagnostic::count_statements(&statement);
+        if let Statement::End(_) = statement {
+            directives.clear();
+        }
+
+        if let Statement::CommandRem(command) = &statement {
+            // Metadata declarations are written as the header of the output instead.
+            if metadata::is_front_matter(&command.value) {
+                line_numbers.next();
+                continue;
+            }
+
+            let line_number = line_numbers.as_slice().first().copied().unwrap_or_default();
+            if let Some(rem_directives) = parse(input_path, line_number, &command.value)? {
+                line_numbers.next();
+                for directive in rem_directives {
+                    match directive {
+                        Directive::FileLevel(severity, rules) => levels.push(RuleLevel {
+                            input_path: String::from(input_path),
+                            line_numbers: None,
+                            rules,
+                            severity,
+                        }),
+                        directive => directives.push(directive),
+                    }
+                }
+                continue;
+            }
+        }
+
+        let statement_line_numbers = line_numbers
+            .as_slice()
+            .iter()
+            .take(statement_count)
+            .copied()
+            .collect::<Vec<usize>>();
+        let mut strip = false;
+        for directive in directives.drain(..) {
+            match directive {
+                Directive::Strip => strip = true,
+                Directive::Level(severity, rules) => levels.push(RuleLevel {
+                    input_path: String::from(input_path),
+                    line_numbers: Some(statement_line_numbers.clone()),
+                    rules,
+                    severity,
+                }),
+                // File levels are applied as they're parsed.
+                Directive::FileLevel(..) => {}
+            }
+        }
+        if strip {
+            log::info!(
+                "Stripping statement at '{}:{}'.",
+                input_path,
+                statement_line_numbers.first().copied().unwrap_or_default()
+            );
+            line_numbers.nth(statement_count - 1);
+            continue;
+        }
+
+        line_numbers_applied.extend(line_numbers.next());
+        statements_applied.push(match statement {
+            Statement::BlockIf(block) => Statement::BlockIf(StatementBlockIf {
+                expression: block.expression,
+                statements_true: apply_statements(
+                    input_path,
+                    block.statements_true,
+                    line_numbers,
+                    line_numbers_applied,
+                    levels,
+                )?,
+                statements_false: apply_statements(
+                    input_path,
+                    block.statements_false,
+                    line_numbers,
+                    line_numbers_applied,
+                    levels,
+                )?,
+            }),
+            Statement::BlockWhile(block) => Statement::BlockWhile(StatementBlockWhile {
+                expression: block.expression,
+                statements: apply_statements(
+                    input_path,
+                    block.statements,
+                    line_numbers,
+                    line_numbers_applied,
+                    levels,
+                )?,
+            }),
+            statement => statement,
+        });
+    }
+
+    Ok(statements_applied)
+}
+
+/// Is the rule checked by variable analysis or lint.
+fn is_rule(id: &str) -> bool {
+    analysis::RULES.contains(&id) || lint::rule(id).is_some()
+}
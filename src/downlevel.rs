@@ -0,0 +1,340 @@
+use anyhow::{anyhow, Context, Result};
+use diagnostic::{self, Location};
+use expression::{self, Value};
+use flipper;
+use pest_duckyscript::mallardscript::ast::{
+    Statement, StatementCommandDefaultDelay, StatementCommandDelay, StatementCommandImport,
+    StatementCommandKey, StatementCommandRem, StatementCommandString, StatementEnd,
+    StatementSingleCommand,
+};
+use preprocessor;
+use std::collections::HashMap;
+use Target;
+
+/// Loop iterations unrolled before lowering fails, across the whole program.
+pub static ITERATIONS_MAX: usize = 10000;
+
+/// Values known while lowering a program and its imports into a target without DuckyScript 3.0.
+#[derive(Debug, Default)]
+pub struct Lowering {
+    /// Values of the variables set so far, variables are resolved at compile time.
+    variables: HashMap<String, Value>,
+    /// Values of `DEFINE` commands, substituted where their name is referenced.
+    defines: Vec<(String, String)>,
+    /// Loop iterations unrolled so far.
+    iterations: usize,
+}
+
+/// Statement lowered into a target without DuckyScript 3.0.
+#[derive(Debug)]
+pub enum Lowered<'a> {
+    /// Statements the target supports, ready to be compiled.
+    Statements(Vec<Statement>),
+    /// Statements a block runs instead, with their line numbers, still to be lowered.
+    Expanded(Vec<(&'a Statement, &'a [usize])>),
+}
+
+/// Split statements into each statement with the line numbers of it and its nested statements.
+pub fn split<'a>(
+    statements: &'a [Statement],
+    line_numbers: &'a [usize],
+) -> Vec<(&'a Statement, &'a [usize])> {
+    let mut start = 0;
+
+    statements
+        .iter()
+        .map(|statement| {
+            let end = (start + diagnostic::count_statements(statement)).min(line_numbers.len());
+            let statement_line_numbers = &line_numbers[start.min(end)..end];
+            start = end;
+            (statement, statement_line_numbers)
+        })
+        .collect()
+}
+
+impl Lowering {
+    /// Lower a statement into DuckyScript 1.0 or Flipper Zero BadUSB, in the order statements run.
+    /// Variables are resolved at compile time, `IF` blocks are replaced by the branch their
+    /// condition selects, loops are unrolled and `DEFINE` values are substituted. `STRINGLN`
+    /// becomes `STRING` and `ENTER`. Statements depending on runtime state are errors.
+    /// Flipper Zero BadUSB key commands are renamed, see `flipper::keys`, and its commands
+    /// MallardScript can't express are written as they are, see `flipper::encode_commands`.
+    pub fn lower<'a>(
+        &mut self,
+        target: Target,
+        input_path: &str,
+        statement: &'a Statement,
+        line_numbers: &'a [usize],
+    ) -> Result<Lowered<'a>> {
+        let location = Location {
+            input_path: String::from(input_path),
+            line_number: line_numbers.first().copied().unwrap_or_default(),
+        };
+
+        Ok(Lowered::Statements(match statement {
+            Statement::BlockIf(block) => {
+                let condition = self
+                    .evaluate_condition(&block.expression, &location)
+                    .context(unsupported("IF", target, &location))?;
+                let statements = split(
+                    &block.statements_true,
+                    line_numbers.get(1..).unwrap_or_default(),
+                );
+                if condition {
+                    return Ok(Lowered::Expanded(statements));
+                }
+
+                let lines_true = statements
+                    .iter()
+                    .map(|(_, lines)| lines.len())
+                    .sum::<usize>();
+                return Ok(Lowered::Expanded(split(
+                    &block.statements_false,
+                    line_numbers.get(1 + lines_true..).unwrap_or_default(),
+                )));
+            }
+            Statement::BlockWhile(block) => {
+                let body = split(&block.statements, line_numbers.get(1..).unwrap_or_default());
+
+                // Runtime loops repeat their body a number of times known when they start.
+                if let Some(count) = preprocessor::loop_count(&block.expression) {
+                    let count = self
+                        .evaluate_integer(&count, &location)
+                        .context(unsupported("LOOP", target, &location))?;
+                    self.iterate(count as usize, target, &location)?;
+                    return Ok(Lowered::Expanded(
+                        (0..count).flat_map(|_| body.clone()).collect(),
+                    ));
+                }
+
+                // Other loops are unrolled an iteration at a time, until their condition is FALSE.
+                if !self
+                    .evaluate_condition(&block.expression, &location)
+                    .context(unsupported("WHILE", target, &location))?
+                {
+                    return Ok(Lowered::Expanded(vec![]));
+                }
+                self.iterate(1, target, &location)?;
+                let mut statements = body;
+                statements.push((statement, line_numbers));
+                return Ok(Lowered::Expanded(statements));
+            }
+            Statement::VariableDeclaration(variable) => {
+                let value = self
+                    .evaluate(&variable.assignment, &location)
+                    .context(unsupported("VAR", target, &location))?;
+                self.variables.insert(variable.name.clone(), value);
+                vec![]
+            }
+            Statement::VariableAssignment(variable) => {
+                let value = self
+                    .evaluate(&variable.assignment, &location)
+                    .context(unsupported("Assignment", target, &location))?;
+                self.variables.insert(variable.name.clone(), value);
+                vec![]
+            }
+            Statement::CommandDefine(command) => {
+                self.defines.push((
+                    command.name.clone(),
+                    self.substitute_defines(&command.value),
+                ));
+                vec![]
+            }
+            Statement::CommandDefaultDelay(command) => {
+                vec![Statement::CommandDefaultDelay(
+                    StatementCommandDefaultDelay {
+                        value: self
+                            .evaluate_integer(&command.value, &location)
+                            .context(unsupported("DEFAULTDELAY", target, &location))?
+                            .to_string(),
+                    },
+                )]
+            }
+            Statement::CommandDelay(command) => {
+                vec![Statement::CommandDelay(StatementCommandDelay {
+                    value: self
+                        .evaluate_integer(&command.value, &location)
+                        .context(unsupported("DELAY", target, &location))?
+                        .to_string(),
+                })]
+            }
+            Statement::CommandString(command) => {
+                vec![Statement::CommandString(StatementCommandString {
+                    value: self
+                        .string_text(&command.value, &location)
+                        .context(unsupported("STRING", target, &location))?,
+                })]
+            }
+            Statement::CommandStringln(command) => vec![
+                Statement::CommandString(StatementCommandString {
+                    value: self
+                        .string_text(&command.value, &location)
+                        .context(unsupported("STRINGLN", target, &location))?,
+                }),
+                Statement::CommandKey(StatementCommandKey {
+                    statements: vec![],
+                    remaining_keys: String::from("ENTER"),
+                }),
+            ],
+            Statement::CommandKey(command) => {
+                let keys = super::collect_command_key_values(command);
+                vec![Statement::CommandKey(StatementCommandKey {
+                    statements: vec![],
+                    remaining_keys: match target {
+                        Target::Flipper => flipper::keys(&keys).with_context(|| {
+                            format!(
+                                "'{}' at '{}' can't be lowered to {}.",
+                                keys.join(" "),
+                                location,
+                                target
+                            )
+                        })?,
+                        _ => keys.join(" "),
+                    },
+                })]
+            }
+            Statement::CommandRem(command) => match flipper::decode_command(&command.value) {
+                Some(command) if target == Target::Flipper => {
+                    vec![Statement::SingleCommand(StatementSingleCommand {
+                        name: String::from(command),
+                    })]
+                }
+                _ => vec![Statement::CommandRem(StatementCommandRem {
+                    value: command.value.clone(),
+                })],
+            },
+            Statement::CommandImport(command) => {
+                vec![Statement::CommandImport(StatementCommandImport {
+                    value: command.value.clone(),
+                })]
+            }
+            Statement::End(_) => vec![Statement::End(StatementEnd {})],
+            Statement::CommandExfil(_) => {
+                return Err(anyhow!(
+                    "EXFIL at '{}' can't be lowered to {}, it has no equivalent.",
+                    location,
+                    target
+                ));
+            }
+            Statement::SingleCommand(command)
+                if target == Target::Flipper
+                    && flipper::COMMANDS.contains(&command.name.as_str()) =>
+            {
+                vec![Statement::SingleCommand(StatementSingleCommand {
+                    name: command.name.clone(),
+                })]
+            }
+            Statement::SingleCommand(command) => {
+                return Err(anyhow!(
+                    "'{}' at '{}' can't be lowered to {}, it has no equivalent.",
+                    command.name,
+                    location,
+                    target
+                ));
+            }
+            Statement::CommandKeyValue(_) => {
+                return Err(anyhow!(
+                    "Key value at '{}' can't be lowered to {}, it isn't in a key command.",
+                    location,
+                    target
+                ));
+            }
+        }))
+    }
+
+    /// Count unrolled loop iterations, failing once there are too many.
+    fn iterate(&mut self, iterations: usize, target: Target, location: &Location) -> Result<()> {
+        self.iterations = self.iterations.saturating_add(iterations);
+        if self.iterations > ITERATIONS_MAX {
+            return Err(anyhow!(
+                "Loop at '{}' can't be lowered to {}, unrolling exceeds {} iterations.",
+                location,
+                target,
+                ITERATIONS_MAX
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Substitute `DEFINE` values where their name is referenced as a whole word.
+    fn substitute_defines(&self, value: &str) -> String {
+        let is_word = |character: char| character.is_ascii_alphanumeric() || character == '_';
+        let mut value = String::from(value);
+
+        for (name, define) in &self.defines {
+            let mut substituted = String::with_capacity(value.len());
+            let mut rest = value.as_str();
+            while let Some(index) = rest.find(name.as_str()) {
+                let before = rest[..index].chars().next_back();
+                let after = rest[index + name.len()..].chars().next();
+                substituted.push_str(&rest[..index]);
+                if before.is_some_and(is_word) || after.is_some_and(is_word) {
+                    substituted.push_str(name);
+                } else {
+                    substituted.push_str(define);
+                }
+                rest = &rest[index + name.len()..];
+            }
+            substituted.push_str(rest);
+            value = substituted;
+        }
+
+        value
+    }
+
+    /// Evaluate an expression with the values of the variables set so far.
+    fn evaluate(&self, expression: &str, location: &Location) -> Result<Value> {
+        let expression = self.substitute_defines(expression);
+        expression::parse(&expression)
+            .and_then(|parsed| expression::substitute(&parsed, &self.variables))
+            .and_then(|substituted| {
+                expression::evaluate(&substituted).ok_or_else(|| {
+                    anyhow!("Result is outside of DuckyScript's range or types don't match.")
+                })
+            })
+            .with_context(|| format!("Unable to evaluate '{}' at '{}'.", expression, location))
+    }
+
+    /// Evaluate an integer expression, such as a delay.
+    fn evaluate_integer(&self, expression: &str, location: &Location) -> Result<u64> {
+        self.evaluate(expression, location)?
+            .as_integer()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Expected an integer for '{}' at '{}'.",
+                    expression,
+                    location
+                )
+            })
+    }
+
+    /// Evaluate a block condition, any non-zero integer is `TRUE`.
+    fn evaluate_condition(&self, expression: &str, location: &Location) -> Result<bool> {
+        self.evaluate(expression, location)?
+            .as_boolean()
+            .ok_or_else(|| anyhow!("Expected a boolean for '{}' at '{}'.", expression, location))
+    }
+
+    /// Text typed by a string command, expressions referencing variables type their result.
+    fn string_text(&self, value: &str, location: &Location) -> Result<String> {
+        let value = self.substitute_defines(value);
+        if !value.contains('$') || expression::parse(&value).is_err() {
+            return Ok(value);
+        }
+
+        Ok(match self.evaluate(&value, location)? {
+            Value::Integer(value) => value.to_string(),
+            Value::Boolean(value) => String::from(if value { "TRUE" } else { "FALSE" }),
+            Value::String(value) => value,
+        })
+    }
+}
+
+/// Context of a statement that can't be lowered, because it depends on runtime state.
+fn unsupported(command: &str, target: Target, location: &Location) -> String {
+    format!(
+        "{} at '{}' can't be lowered to {}, it isn't known at compile time.",
+        command, location, target
+    )
+}
@@ -0,0 +1,686 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Largest integer DuckyScript can represent, integers are 16-bit unsigned.
+pub static INTEGER_MAX: u64 = 65535;
+
+/// MallardScript expression used by `IF` and `WHILE` conditions and variable assignments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Integer(u64),
+    Boolean(bool),
+    String(String),
+    Variable(String),
+    Not(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+/// MallardScript binary operator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+    LessEqual,
+    Less,
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// Type of an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Integer,
+    Boolean,
+    String,
+}
+
+/// Constant value of an evaluated expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl Operator {
+    /// Binding power of the operator, higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            Operator::Or => 1,
+            Operator::And => 2,
+            Operator::Equal | Operator::NotEqual => 3,
+            Operator::GreaterEqual | Operator::Greater | Operator::LessEqual | Operator::Less => 4,
+            Operator::Add | Operator::Subtract => 5,
+            Operator::Multiply | Operator::Divide | Operator::Modulo => 6,
+        }
+    }
+
+    /// Is the operator an arithmetic operator, taking and returning integers.
+    pub fn is_arithmetic(self) -> bool {
+        self.precedence() >= 5
+    }
+
+    /// Typing rule of the operator, shared by `check` and `evaluate`, operands of unknown type are
+    /// accepted. Logical operators take booleans or integers, equality takes operands of the same
+    /// type and other operators take integers.
+    fn accepts(self, left: Option<Type>, right: Option<Type>) -> bool {
+        match self {
+            Operator::And | Operator::Or => {
+                left != Some(Type::String) && right != Some(Type::String)
+            }
+            Operator::Equal | Operator::NotEqual => {
+                left.is_none() || right.is_none() || left == right
+            }
+            _ => [left, right]
+                .iter()
+                .all(|operand| operand.is_none_or(|operand| operand == Type::Integer)),
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self {
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::GreaterEqual => ">=",
+            Operator::Greater => ">",
+            Operator::LessEqual => "<=",
+            Operator::Less => "<",
+            Operator::And => "&&",
+            Operator::Or => "||",
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Modulo => "%",
+        };
+
+        write!(f, "{}", operator)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /// Nested binary expressions are always grouped since DuckyScript has no precedence rules.
+        fn fmt_operand(expression: &Expression, f: &mut fmt::Formatter) -> fmt::Result {
+            match expression {
+                Expression::Binary(..) => write!(f, "({})", expression),
+                _ => write!(f, "{}", expression),
+            }
+        }
+
+        match self {
+            Expression::Integer(value) => write!(f, "{}", value),
+            Expression::Boolean(true) => write!(f, "TRUE"),
+            Expression::Boolean(false) => write!(f, "FALSE"),
+            Expression::String(value) => write!(f, "\"{}\"", value),
+            Expression::Variable(name) => write!(f, "${}", name),
+            Expression::Not(expression) => {
+                write!(f, "NOT ")?;
+                fmt_operand(expression, f)
+            }
+            Expression::Binary(left, operator, right) => {
+                fmt_operand(left, f)?;
+                write!(f, " {} ", operator)?;
+                fmt_operand(right, f)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Integer => write!(f, "integer"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::String => write!(f, "string"),
+        }
+    }
+}
+
+impl Value {
+    /// Type of the value.
+    fn value_type(&self) -> Type {
+        match self {
+            Value::Integer(_) => Type::Integer,
+            Value::Boolean(_) => Type::Boolean,
+            Value::String(_) => Type::String,
+        }
+    }
+
+    /// Integer representation of the value, booleans are `1` or `0`.
+    pub fn as_integer(&self) -> Option<u64> {
+        match self {
+            Value::Integer(value) => Some(*value),
+            Value::Boolean(value) => Some(*value as u64),
+            Value::String(_) => None,
+        }
+    }
+
+    /// Truthiness of the value, any non-zero integer is `TRUE`.
+    pub fn as_boolean(&self) -> Option<bool> {
+        self.as_integer().map(|value| value != 0)
+    }
+
+    /// Convert the value back into an expression.
+    fn into_expression(self) -> Expression {
+        match self {
+            Value::Integer(value) => Expression::Integer(value),
+            Value::Boolean(value) => Expression::Boolean(value),
+            Value::String(value) => Expression::String(value),
+        }
+    }
+}
+
+/// Expression token.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Integer(u64),
+    Boolean(bool),
+    String(String),
+    Variable(String),
+    Not,
+    Operator(Operator),
+    ParenthesisOpen,
+    ParenthesisClose,
+}
+
+/// Parse a MallardScript expression.
+pub fn parse(input: &str) -> Result<Expression> {
+    let tokens = tokenize(input)?;
+    let mut position = 0;
+    let expression = parse_binary(input, &tokens, &mut position, 0)?;
+
+    if position < tokens.len() {
+        return Err(anyhow!(
+            "Unexpected '{:?}' in expression '{}'.",
+            tokens[position],
+            input
+        ));
+    }
+
+    Ok(expression)
+}
+
+/// Split an expression into tokens.
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut characters = input.char_indices().peekable();
+
+    while let Some((index, character)) = characters.next() {
+        match character {
+            ' ' | '\t' => {}
+            '(' => tokens.push(Token::ParenthesisOpen),
+            ')' => tokens.push(Token::ParenthesisClose),
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match characters.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => {
+                            value.push('\\');
+                            if let Some((_, escaped)) = characters.next() {
+                                value.push(escaped);
+                            }
+                        }
+                        Some((_, character)) => value.push(character),
+                        None => {
+                            return Err(anyhow!("Unterminated string in expression '{}'.", input))
+                        }
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            '+' => tokens.push(Token::Operator(Operator::Add)),
+            '-' => tokens.push(Token::Operator(Operator::Subtract)),
+            '*' => tokens.push(Token::Operator(Operator::Multiply)),
+            '/' => tokens.push(Token::Operator(Operator::Divide)),
+            '%' => tokens.push(Token::Operator(Operator::Modulo)),
+            '=' | '!' | '>' | '<' | '&' | '|' => {
+                let next = characters.peek().map(|&(_, next)| next);
+                let (operator, length) = match (character, next) {
+                    ('=', Some('=')) => (Operator::Equal, 2),
+                    ('!', Some('=')) => (Operator::NotEqual, 2),
+                    ('>', Some('=')) => (Operator::GreaterEqual, 2),
+                    ('<', Some('=')) => (Operator::LessEqual, 2),
+                    ('&', Some('&')) => (Operator::And, 2),
+                    ('|', Some('|')) => (Operator::Or, 2),
+                    ('>', _) => (Operator::Greater, 1),
+                    ('<', _) => (Operator::Less, 1),
+                    _ => {
+                        return Err(anyhow!(
+                            "Unknown operator '{}' in expression '{}'.",
+                            &input[index..],
+                            input
+                        ))
+                    }
+                };
+                if length == 2 {
+                    characters.next();
+                }
+                tokens.push(Token::Operator(operator));
+            }
+            _ => {
+                // Collect the word.
+                let mut end = index + character.len_utf8();
+                while let Some(&(next_index, next)) = characters.peek() {
+                    if !(next.is_ascii_alphanumeric() || next == '_') {
+                        break;
+                    }
+                    end = next_index + next.len_utf8();
+                    characters.next();
+                }
+                let word = &input[index..end];
+
+                tokens.push(match word {
+                    "TRUE" => Token::Boolean(true),
+                    "FALSE" => Token::Boolean(false),
+                    "NOT" => Token::Not,
+                    _ if word.starts_with('$') && word.len() > 1 => {
+                        Token::Variable(String::from(&word[1..]))
+                    }
+                    _ => Token::Integer(word.parse::<u64>().map_err(|_| {
+                        anyhow!("Unexpected '{}' in expression '{}'.", word, input)
+                    })?),
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parse binary operators with at least the provided precedence.
+fn parse_binary(
+    input: &str,
+    tokens: &[Token],
+    position: &mut usize,
+    precedence: u8,
+) -> Result<Expression> {
+    let mut left = parse_unary(input, tokens, position)?;
+
+    while let Some(Token::Operator(operator)) = tokens.get(*position) {
+        let operator = *operator;
+        if operator.precedence() < precedence {
+            break;
+        }
+        *position += 1;
+
+        let right = parse_binary(input, tokens, position, operator.precedence() + 1)?;
+        left = Expression::Binary(Box::new(left), operator, Box::new(right));
+    }
+
+    Ok(left)
+}
+
+/// Parse a value, variable, negation or parenthesized expression.
+fn parse_unary(input: &str, tokens: &[Token], position: &mut usize) -> Result<Expression> {
+    let token = tokens
+        .get(*position)
+        .ok_or_else(|| anyhow!("Unexpected end of expression '{}'.", input))?;
+    *position += 1;
+
+    match token {
+        Token::Integer(value) => Ok(Expression::Integer(*value)),
+        Token::Boolean(value) => Ok(Expression::Boolean(*value)),
+        Token::String(value) => Ok(Expression::String(value.clone())),
+        Token::Variable(name) => Ok(Expression::Variable(name.clone())),
+        Token::Not => Ok(Expression::Not(Box::new(parse_unary(
+            input, tokens, position,
+        )?))),
+        Token::ParenthesisOpen => {
+            let expression = parse_binary(input, tokens, position, 0)?;
+            if tokens.get(*position) != Some(&Token::ParenthesisClose) {
+                return Err(anyhow!("Missing ')' in expression '{}'.", input));
+            }
+            *position += 1;
+
+            Ok(expression)
+        }
+        Token::Operator(_) | Token::ParenthesisClose => Err(anyhow!(
+            "Unexpected '{:?}' in expression '{}'.",
+            token,
+            input
+        )),
+    }
+}
+
+/// Evaluate an expression when it's constant and well typed, following the rules of `check`.
+pub fn evaluate(expression: &Expression) -> Option<Value> {
+    match expression {
+        Expression::Integer(value) => {
+            Some(Value::Integer(*value)).filter(|_| *value <= INTEGER_MAX)
+        }
+        Expression::Boolean(value) => Some(Value::Boolean(*value)),
+        Expression::String(value) => Some(Value::String(value.clone())),
+        Expression::Variable(_) => None,
+        Expression::Not(expression) => evaluate(expression)?
+            .as_boolean()
+            .map(|value| Value::Boolean(!value)),
+        Expression::Binary(left, operator, right) => {
+            let left = evaluate(left)?;
+            let right = evaluate(right)?;
+            if !operator.accepts(Some(left.value_type()), Some(right.value_type())) {
+                return None;
+            }
+
+            match operator {
+                Operator::And => Some(Value::Boolean(left.as_boolean()? && right.as_boolean()?)),
+                Operator::Or => Some(Value::Boolean(left.as_boolean()? || right.as_boolean()?)),
+                Operator::Equal => Some(Value::Boolean(left == right)),
+                Operator::NotEqual => Some(Value::Boolean(left != right)),
+                Operator::GreaterEqual => Some(Value::Boolean(integer(&left)? >= integer(&right)?)),
+                Operator::Greater => Some(Value::Boolean(integer(&left)? > integer(&right)?)),
+                Operator::LessEqual => Some(Value::Boolean(integer(&left)? <= integer(&right)?)),
+                Operator::Less => Some(Value::Boolean(integer(&left)? < integer(&right)?)),
+                _ => arithmetic(*operator, integer(&left)?, integer(&right)?)
+                    .filter(|value| *value <= INTEGER_MAX)
+                    .map(Value::Integer),
+            }
+        }
+    }
+}
+
+/// Substitute the values of variables, as they're set when the expression runs.
+pub fn substitute(
+    expression: &Expression,
+    variables: &HashMap<String, Value>,
+) -> Result<Expression> {
+    match expression {
+        Expression::Variable(name) => variables
+            .get(name)
+            .map(|value| value.clone().into_expression())
+            .ok_or_else(|| anyhow!("Variable '${}' isn't set.", name)),
+        Expression::Not(expression) => Ok(Expression::Not(Box::new(substitute(
+            expression, variables,
+        )?))),
+        Expression::Binary(left, operator, right) => Ok(Expression::Binary(
+            Box::new(substitute(left, variables)?),
+            *operator,
+            Box::new(substitute(right, variables)?),
+        )),
+        _ => Ok(expression.clone()),
+    }
+}
+
+/// Integer of an arithmetic operand, booleans aren't converted.
+fn integer(value: &Value) -> Option<u64> {
+    match value {
+        Value::Integer(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Apply an arithmetic operator, `None` when it overflows or divides by zero.
+fn arithmetic(operator: Operator, left: u64, right: u64) -> Option<u64> {
+    match operator {
+        Operator::Add => left.checked_add(right),
+        Operator::Subtract => left.checked_sub(right),
+        Operator::Multiply => left.checked_mul(right),
+        Operator::Divide => left.checked_div(right),
+        Operator::Modulo => left.checked_rem(right),
+        _ => None,
+    }
+}
+
+/// Type check an expression, given the types of the variables declared so far.
+/// Returns the type of the expression, `None` when it depends on a variable of unknown type.
+/// Integers must fit DuckyScript's 16-bit unsigned range, including constant arithmetic.
+pub fn check(expression: &Expression, variables: &HashMap<String, Type>) -> Result<Option<Type>> {
+    match expression {
+        Expression::Integer(value) if *value > INTEGER_MAX => Err(anyhow!(
+            "Integer '{}' is outside of DuckyScript's range 0 to {}.",
+            value,
+            INTEGER_MAX
+        )),
+        Expression::Integer(_) => Ok(Some(Type::Integer)),
+        Expression::Boolean(_) => Ok(Some(Type::Boolean)),
+        Expression::String(_) => Ok(Some(Type::String)),
+        Expression::Variable(name) => Ok(variables.get(name).copied()),
+        Expression::Not(inner) => {
+            expect_condition(inner, check(inner, variables)?, "NOT")?;
+            Ok(Some(Type::Boolean))
+        }
+        Expression::Binary(left, operator, right) => {
+            let left_type = check(left, variables)?;
+            let right_type = check(right, variables)?;
+            if !operator.accepts(left_type, right_type) {
+                return Err(type_mismatch(left, left_type, *operator, right, right_type));
+            }
+
+            if !operator.is_arithmetic() {
+                return Ok(Some(Type::Boolean));
+            }
+
+            // Constant arithmetic must stay in range.
+            let constant =
+                |expression: &Expression| evaluate(expression).as_ref().and_then(integer);
+            if let (Some(left_value), Some(right_value)) = (constant(left), constant(right)) {
+                if right_value == 0
+                    && (*operator == Operator::Divide || *operator == Operator::Modulo)
+                {
+                    return Err(anyhow!("Division by zero in '{}'.", expression));
+                }
+                if arithmetic(*operator, left_value, right_value)
+                    .is_none_or(|value| value > INTEGER_MAX)
+                {
+                    return Err(anyhow!(
+                        "Integer overflow in '{}', result is outside of DuckyScript's range 0 to {}.",
+                        expression,
+                        INTEGER_MAX
+                    ));
+                }
+            }
+
+            Ok(Some(Type::Integer))
+        }
+    }
+}
+
+/// Error of operands the operator doesn't accept, see `Operator::accepts`.
+fn type_mismatch(
+    left: &Expression,
+    left_type: Option<Type>,
+    operator: Operator,
+    right: &Expression,
+    right_type: Option<Type>,
+) -> anyhow::Error {
+    match (operator, left_type, right_type) {
+        (Operator::Equal | Operator::NotEqual, Some(left_type), Some(right_type)) => anyhow!(
+            "Type mismatch, can't compare {} '{}' with {} '{}'.",
+            left_type,
+            left,
+            right_type,
+            right
+        ),
+        (Operator::And | Operator::Or, _, _) => {
+            let operand = if left_type == Some(Type::String) {
+                left
+            } else {
+                right
+            };
+            anyhow!(
+                "Type mismatch, '{}' expects booleans or integers but found string '{}'.",
+                operator,
+                operand
+            )
+        }
+        _ => {
+            let (operand, operand_type) = match left_type {
+                Some(left_type) if left_type != Type::Integer => (left, left_type),
+                _ => (right, right_type.unwrap_or(Type::Integer)),
+            };
+            anyhow!(
+                "Type mismatch, '{}' expects integers but found {} '{}'.",
+                operator,
+                operand_type,
+                operand
+            )
+        }
+    }
+}
+
+/// Require an operand to be a condition, a boolean or an integer.
+fn expect_condition<T: fmt::Display>(
+    operand: &Expression,
+    operand_type: Option<Type>,
+    operator: T,
+) -> Result<()> {
+    match operand_type {
+        Some(Type::String) => Err(anyhow!(
+            "Type mismatch, '{}' expects booleans or integers but found string '{}'.",
+            operator,
+            operand
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Fold constant sub expressions.
+/// Logical operators with a constant operand are reduced since expressions have no side effects.
+pub fn fold(expression: &Expression) -> Expression {
+    if let Some(value) = evaluate(expression) {
+        return value.into_expression();
+    }
+
+    match expression {
+        Expression::Not(inner) => Expression::Not(Box::new(fold(inner))),
+        Expression::Binary(left, operator, right) => {
+            let left = fold(left);
+            let right = fold(right);
+            let constant =
+                |expression: &Expression| evaluate(expression).and_then(|value| value.as_boolean());
+
+            match (operator, constant(&left), constant(&right)) {
+                (Operator::And, Some(true), _) => right,
+                (Operator::And, _, Some(true)) => left,
+                (Operator::And, Some(false), _) | (Operator::And, _, Some(false)) => {
+                    Expression::Boolean(false)
+                }
+                (Operator::Or, Some(false), _) => right,
+                (Operator::Or, _, Some(false)) => left,
+                (Operator::Or, Some(true), _) | (Operator::Or, _, Some(true)) => {
+                    Expression::Boolean(true)
+                }
+                _ => Expression::Binary(Box::new(left), *operator, Box::new(right)),
+            }
+        }
+        _ => expression.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate a constant expression.
+    fn evaluate_input(input: &str) -> Option<Value> {
+        evaluate(&parse(input).unwrap())
+    }
+
+    /// Type check an expression without variables.
+    fn check_input(input: &str) -> Result<Option<Type>> {
+        check(&parse(input).unwrap(), &HashMap::new())
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(evaluate_input("1 + 2 * 3"), Some(Value::Integer(7)));
+        assert_eq!(evaluate_input("10 - 4 - 3"), Some(Value::Integer(3)));
+        assert_eq!(evaluate_input("7 % 4 * 2"), Some(Value::Integer(6)));
+        assert_eq!(
+            evaluate_input("1 + 1 == 2 && 3 > 2 || FALSE"),
+            Some(Value::Boolean(true))
+        );
+        assert_eq!(
+            parse("$a || $b && $c == 1 + 2").unwrap().to_string(),
+            "$a || ($b && ($c == (1 + 2)))"
+        );
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_eq!(evaluate_input("(1 + 2) * 3"), Some(Value::Integer(9)));
+        assert_eq!(evaluate_input("10 - (4 - 3)"), Some(Value::Integer(9)));
+        assert_eq!(evaluate_input("((2))"), Some(Value::Integer(2)));
+        assert_eq!(
+            evaluate_input("NOT (TRUE && FALSE)"),
+            Some(Value::Boolean(true))
+        );
+        assert!(parse("(1 + 2").is_err());
+        assert!(parse("1 + 2)").is_err());
+    }
+
+    #[test]
+    fn test_integer_max() {
+        assert_eq!(check_input("65535").unwrap(), Some(Type::Integer));
+        assert_eq!(check_input("65534 + 1").unwrap(), Some(Type::Integer));
+        assert_eq!(
+            evaluate_input("65534 + 1"),
+            Some(Value::Integer(INTEGER_MAX))
+        );
+
+        assert_eq!(
+            check_input("65536").unwrap_err().to_string(),
+            "Integer '65536' is outside of DuckyScript's range 0 to 65535."
+        );
+        assert_eq!(
+            check_input("65535 + 1").unwrap_err().to_string(),
+            "Integer overflow in '65535 + 1', result is outside of DuckyScript's range 0 to 65535."
+        );
+        assert!(check_input("0 - 1").is_err());
+        assert!(check_input("1 / 0").is_err());
+        assert_eq!(evaluate_input("65536"), None);
+        assert_eq!(evaluate_input("65535 + 1"), None);
+        assert_eq!(evaluate_input("0 - 1"), None);
+        assert_eq!(evaluate_input("1 / 0"), None);
+    }
+
+    #[test]
+    fn test_mixed_types() {
+        for input in [
+            "TRUE == 1",
+            "1 != FALSE",
+            "\"a\" == 1",
+            "TRUE > 0",
+            "TRUE + 1",
+            "\"a\" && TRUE",
+            "\"a\" < \"b\"",
+        ] {
+            assert!(check_input(input).is_err(), "'{}' type checks", input);
+            assert_eq!(evaluate_input(input), None, "'{}' evaluates", input);
+        }
+
+        assert_eq!(
+            check_input("TRUE == 1").unwrap_err().to_string(),
+            "Type mismatch, can't compare boolean 'TRUE' with integer '1'."
+        );
+        assert_eq!(
+            check_input("1 + TRUE").unwrap_err().to_string(),
+            "Type mismatch, '+' expects integers but found boolean 'TRUE'."
+        );
+        assert_eq!(
+            check_input("1 && \"a\"").unwrap_err().to_string(),
+            "Type mismatch, '&&' expects booleans or integers but found string '\"a\"'."
+        );
+
+        // Logical operators take booleans and integers together.
+        assert_eq!(check_input("1 && TRUE").unwrap(), Some(Type::Boolean));
+        assert_eq!(evaluate_input("1 && TRUE"), Some(Value::Boolean(true)));
+        assert_eq!(evaluate_input("\"a\" == \"a\""), Some(Value::Boolean(true)));
+
+        // Operands of unknown type are accepted.
+        assert_eq!(check_input("$unknown == 1").unwrap(), Some(Type::Boolean));
+        let variables = HashMap::from([(String::from("flag"), Type::Boolean)]);
+        assert!(check(&parse("$flag + 1").unwrap(), &variables).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use anyhow::{anyhow, Context, Result};
+use cache::Cache;
+use preprocessor;
+use scenario::Scenario;
+use simulator;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use timing;
+use {compile, CompileOptions, CompileState};
+
+/// Suffix of fixture input files, such as `open_url.test.ducky`.
+static FIXTURE_SUFFIX: &str = ".test.ducky";
+
+/// Input file of fixture directories.
+static FIXTURE_DIRECTORY_INPUT: &str = "input.ducky";
+
+/// Directories that are never searched for fixtures.
+static DIRECTORIES_IGNORED: &[&str] = &["target", "node_modules"];
+
+/// Keys of a fixture options file.
+static OPTIONS_KEYS: &[&str] = &["defines", "target", "device"];
+
+/// MallardScript input with its expected compiled output and simulated keystrokes.
+/// A `name.test.ducky` file expects `name.expected.ducky` and `name.expected.keys`, optionally
+/// simulated with `name.scenario.json` and compiled with `name.options.json`. A directory with an
+/// `input.ducky` file expects `expected.ducky` and `expected.keys`, optionally simulated with
+/// `scenario.json` and compiled with `options.json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub input: PathBuf,
+    pub expected_output: PathBuf,
+    pub expected_keys: PathBuf,
+    pub scenario: PathBuf,
+    pub options: PathBuf,
+}
+
+/// Outcome of running a fixture.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// Expected files written, as named.
+    Updated(Vec<PathBuf>),
+    /// Expected files that don't match, with a diff of their lines.
+    Failed(Vec<(PathBuf, String)>),
+}
+
+impl Fixture {
+    /// Fixture of a `name.test.ducky` file or a directory with an `input.ducky` file.
+    fn new(input: PathBuf) -> Fixture {
+        let file_name = input
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or_default();
+        let prefix = match file_name.strip_suffix(FIXTURE_SUFFIX) {
+            Some(name) => format!("{}.", name),
+            None => String::new(),
+        };
+
+        Fixture {
+            expected_output: input.with_file_name(format!("{}expected.ducky", prefix)),
+            expected_keys: input.with_file_name(format!("{}expected.keys", prefix)),
+            scenario: input.with_file_name(format!("{}scenario.json", prefix)),
+            options: input.with_file_name(format!("{}options.json", prefix)),
+            input,
+        }
+    }
+
+    /// Run the fixture, comparing its compiled output and simulated keystrokes with the expected
+    /// files that exist. Updating writes the expected files instead, the compiled output is always
+    /// written and keystrokes when they're already expected or a scenario exists.
+    pub fn run(&self, update: bool, cache: &mut Cache) -> Result<Outcome> {
+        let directory = self.input.parent().unwrap_or_else(|| Path::new("."));
+        let input = self
+            .input
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| anyhow!("Invalid fixture input '{}'.", self.input.display()))?;
+        let options = &self.load_options()?;
+
+        let mut actuals = vec![(
+            self.expected_output.clone(),
+            compile_to_string(directory, input, options, cache)?,
+        )];
+        if self.expected_keys.exists() || self.scenario.exists() {
+            actuals.push((
+                self.expected_keys.clone(),
+                simulate_to_string(directory, input, options, &self.scenario, cache)?,
+            ));
+        }
+
+        if update {
+            let mut updated = vec![];
+            for (path, actual) in actuals {
+                if std::fs::read_to_string(&path).ok().as_ref() != Some(&actual) {
+                    std::fs::write(&path, actual).with_context(|| {
+                        format!("Unable to write expected file '{}'.", path.display())
+                    })?;
+                    updated.push(path);
+                }
+            }
+
+            return Ok(if updated.is_empty() {
+                Outcome::Passed
+            } else {
+                Outcome::Updated(updated)
+            });
+        }
+
+        if !self.expected_output.exists() && !self.expected_keys.exists() {
+            return Err(anyhow!(
+                "Fixture '{}' has no expected files, run with '--update' to write them.",
+                self.input.display()
+            ));
+        }
+
+        let mut failures = vec![];
+        for (path, actual) in actuals {
+            if !path.exists() {
+                continue;
+            }
+            let expected = std::fs::read_to_string(&path)
+                .with_context(|| format!("Unable to load expected file '{}'.", path.display()))?;
+            if expected != actual {
+                failures.push((path, diff(&expected, &actual)));
+            }
+        }
+
+        Ok(if failures.is_empty() {
+            Outcome::Passed
+        } else {
+            Outcome::Failed(failures)
+        })
+    }
+
+    /// Compile options of the fixture, the defaults unless an options file exists.
+    pub fn load_options(&self) -> Result<CompileOptions> {
+        if !self.options.exists() {
+            return Ok(CompileOptions::default());
+        }
+
+        let contents = std::fs::read_to_string(&self.options).with_context(|| {
+            format!("Unable to load options file '{}'.", self.options.display())
+        })?;
+        parse_options(&contents)
+            .with_context(|| format!("Invalid options file '{}'.", self.options.display()))
+    }
+}
+
+/// Parse JSON fixture options, `defines` of names and values, a `target` and a `device`.
+fn parse_options(contents: &str) -> Result<CompileOptions> {
+    let options: serde_json::Value =
+        serde_json::from_str(contents).context("Unable to parse JSON.")?;
+    let options = options
+        .as_object()
+        .ok_or_else(|| anyhow!("Fixture options must be an object."))?;
+    if let Some(key) = options
+        .keys()
+        .find(|key| !OPTIONS_KEYS.contains(&key.as_str()))
+    {
+        return Err(anyhow!(
+            "Unknown options key '{}', expected one of '{}'.",
+            key,
+            OPTIONS_KEYS.join("', '")
+        ));
+    }
+
+    let mut defines = HashMap::new();
+    if let Some(defines_value) = options.get("defines") {
+        let defines_value = defines_value
+            .as_object()
+            .ok_or_else(|| anyhow!("Fixture options 'defines' must be an object."))?;
+        for (name, value) in defines_value {
+            if !preprocessor::is_define_name(name) {
+                return Err(anyhow!("Invalid define name '{}'.", name));
+            }
+            let value = value
+                .as_str()
+                .ok_or_else(|| anyhow!("Define '{}' must be a string.", name))?;
+            defines.insert(name.clone(), String::from(value));
+        }
+    }
+
+    let string = |key: &str| -> Result<Option<&str>> {
+        options
+            .get(key)
+            .map(|value| {
+                value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Fixture options '{}' must be a string.", key))
+            })
+            .transpose()
+    };
+
+    Ok(CompileOptions {
+        defines,
+        target: string("target")?
+            .map(|target| target.parse())
+            .transpose()?
+            .unwrap_or_default(),
+        device: string("device")?.map(|device| device.parse()).transpose()?,
+        ..CompileOptions::default()
+    })
+}
+
+/// Discover the fixtures of a path, sorted by input.
+/// A path to a fixture file or directory is a single fixture.
+pub fn discover(path: &Path) -> Result<Vec<Fixture>> {
+    let mut fixtures = vec![];
+    discover_directory(path, &mut fixtures)
+        .with_context(|| format!("Unable to discover fixtures in '{}'.", path.display()))?;
+    fixtures.sort_by(|left, right| left.input.cmp(&right.input));
+
+    Ok(fixtures)
+}
+
+/// Discover the fixtures of a path, searching directories recursively.
+fn discover_directory(path: &Path, fixtures: &mut Vec<Fixture>) -> Result<()> {
+    if path.is_file() {
+        if is_fixture_file(path) {
+            fixtures.push(Fixture::new(path.to_path_buf()));
+        }
+        return Ok(());
+    }
+
+    let input = path.join(FIXTURE_DIRECTORY_INPUT);
+    if input.is_file() {
+        fixtures.push(Fixture::new(input));
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let entry_name = entry_path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or_default();
+        if entry_path.is_dir()
+            && (entry_name.starts_with('.') || DIRECTORIES_IGNORED.contains(&entry_name))
+        {
+            continue;
+        }
+
+        discover_directory(&entry_path, fixtures)?;
+    }
+
+    Ok(())
+}
+
+/// Is the path a `name.test.ducky` fixture file.
+fn is_fixture_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .is_some_and(|file_name| file_name.ends_with(FIXTURE_SUFFIX))
+}
+
+/// Compile MallardScript input path to a string, through a temporary output file.
+fn compile_to_string(
+    directory: &Path,
+    input: &str,
+    options: &CompileOptions,
+    cache: &mut Cache,
+) -> Result<String> {
+    let output_path = std::env::temp_dir().join(format!(
+        "mallardscript-test-{}-{}",
+        std::process::id(),
+        input
+    ));
+    let output_file = std::fs::File::create(&output_path).with_context(|| {
+        format!(
+            "Unable to create temporary output file '{}'.",
+            output_path.display()
+        )
+    })?;
+
+    let mut state = CompileState {
+        cache: std::mem::take(cache),
+        ..CompileState::default()
+    };
+    let result = compile(
+        directory.to_path_buf(),
+        input,
+        &output_file,
+        0,
+        options,
+        &mut state,
+    )
+    .and_then(|_| Ok(std::fs::read_to_string(&output_path)?));
+    std::fs::remove_file(&output_path).ok();
+    *cache = state.cache;
+
+    result
+}
+
+/// Simulate MallardScript input path to its keystroke timeline, a line for each event.
+fn simulate_to_string(
+    directory: &Path,
+    input: &str,
+    options: &CompileOptions,
+    scenario: &Path,
+    cache: &mut Cache,
+) -> Result<String> {
+    let scenario = if scenario.exists() {
+        Scenario::load(scenario)?
+    } else {
+        Scenario::default()
+    };
+    let simulation = simulator::simulate(
+        directory.to_path_buf(),
+        input,
+        options,
+        timing::CHARACTERS_PER_SECOND,
+        &scenario,
+        cache,
+    )?;
+
+    Ok(simulation
+        .events
+        .iter()
+        .map(|event| format!("{}\n", event))
+        .collect())
+}
+
+/// Diff the lines of expected and actual contents, removed lines start with `-` and added lines
+/// with `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected = expected.lines().collect::<Vec<&str>>();
+    let actual = actual.lines().collect::<Vec<&str>>();
+
+    // Longest common subsequence lengths of the remaining lines.
+    let mut lengths = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lengths[i][j] = if expected[i] == actual[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!(" {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j < actual.len()
+            && (i == expected.len() || lengths[i][j + 1] >= lengths[i + 1][j])
+        {
+            lines.push(format!("+{}", actual[j]));
+            j += 1;
+        } else {
+            lines.push(format!("-{}", expected[i]));
+            i += 1;
+        }
+    }
+    if lines.iter().all(|line| line.starts_with(' ')) {
+        // Contents only differ by their trailing new lines.
+        lines.push(String::from("\\ trailing new lines differ"));
+    }
+
+    lines.join("\n")
+}
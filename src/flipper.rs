@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Result};
+use preprocessor::{self, Preprocessed};
+
+/// Commands Flipper Zero BadUSB runs as they're written in MallardScript.
+pub static COMMANDS: &[&str] = &["WAIT_FOR_BUTTON_PRESS"];
+
+/// Commands of the lowered output, other lines are key commands.
+static COMMANDS_OUTPUT: &[&str] = &["REM", "STRING", "DELAY", "DEFAULTDELAY"];
+
+/// Flipper Zero BadUSB commands MallardScript can't express, written as they are in the output.
+/// The parser reads some of them as other commands, such as `ALTCHAR 65` as the `ALT` modifier.
+static COMMANDS_NATIVE: &[&str] = &[
+    "HOLD",
+    "RELEASE",
+    "STRING_DELAY",
+    "STRINGDELAY",
+    "DEFAULT_STRING_DELAY",
+    "DEFAULTSTRINGDELAY",
+    "ALTCHAR",
+    "ALTSTRING",
+    "ALTCODE",
+];
+
+/// Modifier keys with their Flipper Zero BadUSB name.
+static MODIFIERS: &[(&str, &str)] = &[
+    ("CTRL", "CTRL"),
+    ("CONTROL", "CTRL"),
+    ("SHIFT", "SHIFT"),
+    ("ALT", "ALT"),
+    ("OPTION", "ALT"),
+    ("GUI", "GUI"),
+    ("WINDOWS", "GUI"),
+    ("COMMAND", "GUI"),
+];
+
+/// Pairs of modifier keys Flipper Zero BadUSB presses together, written joined by `-`.
+static MODIFIER_PAIRS: &[&str] = &[
+    "CTRL-ALT",
+    "CTRL-SHIFT",
+    "ALT-SHIFT",
+    "ALT-GUI",
+    "GUI-SHIFT",
+    "GUI-CTRL",
+];
+
+/// Special keys with their Flipper Zero BadUSB name.
+static KEYS: &[(&str, &str)] = &[
+    ("BACKSPACE", "BACKSPACE"),
+    ("DELETE", "DELETE"),
+    ("DEL", "DELETE"),
+    ("DOWNARROW", "DOWN"),
+    ("DOWN", "DOWN"),
+    ("END", "END"),
+    ("HOME", "HOME"),
+    ("INSERT", "INSERT"),
+    ("LEFTARROW", "LEFT"),
+    ("LEFT", "LEFT"),
+    ("PAGEDOWN", "PAGEDOWN"),
+    ("PAGEUP", "PAGEUP"),
+    ("RIGHTARROW", "RIGHT"),
+    ("RIGHT", "RIGHT"),
+    ("SPACE", "SPACE"),
+    ("TAB", "TAB"),
+    ("UPARROW", "UP"),
+    ("UP", "UP"),
+    ("APP", "APP"),
+    ("MENU", "MENU"),
+    ("BREAK", "BREAK"),
+    ("PAUSE", "PAUSE"),
+    ("ENTER", "ENTER"),
+    ("ESCAPE", "ESC"),
+    ("PRINTSCREEN", "PRINTSCREEN"),
+    ("CAPSLOCK", "CAPSLOCK"),
+    ("NUMLOCK", "NUMLOCK"),
+    ("SCROLLOCK", "SCROLLLOCK"),
+    ("F1", "F1"),
+    ("F2", "F2"),
+    ("F3", "F3"),
+    ("F4", "F4"),
+    ("F5", "F5"),
+    ("F6", "F6"),
+    ("F7", "F7"),
+    ("F8", "F8"),
+    ("F9", "F9"),
+    ("F10", "F10"),
+    ("F11", "F11"),
+    ("F12", "F12"),
+];
+
+/// Write the keys of a key command as Flipper Zero BadUSB presses them, such as `CTRL-ALT DELETE`.
+/// Flipper Zero BadUSB holds at most two modifier keys, from a fixed set of pairs.
+pub fn keys(keys: &[String]) -> Result<String> {
+    let (modifiers, keys): (Vec<&String>, Vec<&String>) = keys
+        .iter()
+        .partition(|key| lookup(MODIFIERS, key).is_some());
+    let modifiers = modifiers
+        .iter()
+        .filter_map(|modifier| lookup(MODIFIERS, modifier))
+        .collect::<Vec<&str>>();
+
+    let modifier = match modifiers.as_slice() {
+        [] => None,
+        [modifier] => Some(String::from(*modifier)),
+        [first, second] => Some(
+            MODIFIER_PAIRS
+                .iter()
+                .find(|pair| {
+                    **pair == format!("{}-{}", first, second)
+                        || **pair == format!("{}-{}", second, first)
+                })
+                .map(|pair| String::from(*pair))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Modifiers '{} {}' can't be pressed together, expected one of '{}'.",
+                        first,
+                        second,
+                        MODIFIER_PAIRS.join("', '")
+                    )
+                })?,
+        ),
+        _ => {
+            return Err(anyhow!(
+                "Modifiers '{}' can't be pressed together, at most two are supported.",
+                modifiers.join(" ")
+            ))
+        }
+    };
+
+    let key = match keys.as_slice() {
+        [] => None,
+        [key] if key.chars().count() == 1 => Some(key.to_string()),
+        [key] => Some(String::from(
+            lookup(KEYS, key).ok_or_else(|| anyhow!("Key '{}' isn't supported.", key))?,
+        )),
+        _ => {
+            return Err(anyhow!(
+                "Keys '{}' can't be pressed together, only one key follows the modifiers.",
+                keys.iter()
+                    .map(|key| key.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            ))
+        }
+    };
+
+    Ok(modifier
+        .into_iter()
+        .chain(key)
+        .collect::<Vec<String>>()
+        .join(" "))
+}
+
+/// Encode the Flipper Zero BadUSB commands MallardScript can't express in preprocessed source as
+/// marked `REM` lines before it's parsed, so they're lowered as they're written.
+pub fn encode_commands(preprocessed: Preprocessed) -> Preprocessed {
+    let contents = preprocessed
+        .contents
+        .split_inclusive('\n')
+        .map(|line| {
+            let line_trimmed = line.trim_start();
+            let command = line_trimmed.split_whitespace().next().unwrap_or_default();
+            if !COMMANDS_NATIVE.contains(&command) {
+                return String::from(line);
+            }
+
+            format!(
+                "{}REM {}{}{}",
+                &line[..line.len() - line_trimmed.len()],
+                preprocessor::TARGET_COMMAND_MARKER,
+                line_trimmed.trim_end(),
+                &line[line.trim_end_matches(['\r', '\n']).len()..]
+            )
+        })
+        .collect::<String>();
+
+    Preprocessed {
+        contents,
+        ..preprocessed
+    }
+}
+
+/// Flipper Zero BadUSB command encoded as a `REM` value by `encode_commands`.
+pub fn decode_command(value: &str) -> Option<&str> {
+    value.strip_prefix(preprocessor::TARGET_COMMAND_MARKER)
+}
+
+/// Validate lowered output, each line is a command or key command Flipper Zero BadUSB runs.
+pub fn validate(contents: &str) -> Result<()> {
+    for (line_index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let command = line.split(' ').next().unwrap_or_default();
+        if line.is_empty()
+            || COMMANDS_OUTPUT.contains(&command)
+            || COMMANDS_NATIVE.contains(&command)
+            || COMMANDS.contains(&line)
+            || is_key_command(line)
+        {
+            continue;
+        }
+
+        return Err(anyhow!(
+            "Line {} '{}' isn't a Flipper Zero BadUSB command.",
+            line_index + 1,
+            line
+        ));
+    }
+
+    Ok(())
+}
+
+/// Is a line keys written as Flipper Zero BadUSB presses them, a modifier or pair of modifiers
+/// followed by at most one key.
+fn is_key_command(line: &str) -> bool {
+    let keys = line.split(' ').collect::<Vec<&str>>();
+    let keys = match keys.split_first() {
+        Some((modifier, keys)) if is_modifier(modifier) => keys,
+        _ => &keys,
+    };
+
+    match keys {
+        [] => true,
+        [key] => {
+            key.chars().count() == 1 || KEYS.iter().any(|(_, flipper_name)| flipper_name == key)
+        }
+        _ => false,
+    }
+}
+
+/// Is a key a Flipper Zero BadUSB modifier or pair of modifiers.
+fn is_modifier(key: &str) -> bool {
+    MODIFIER_PAIRS.contains(&key)
+        || MODIFIERS
+            .iter()
+            .any(|(_, flipper_name)| *flipper_name == key)
+}
+
+/// Flipper Zero BadUSB name of a key.
+fn lookup(names: &[(&str, &'static str)], key: &str) -> Option<&'static str> {
+    names
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, flipper_name)| *flipper_name)
+}
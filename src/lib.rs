@@ -1,43 +1,237 @@
 extern crate anyhow;
 extern crate pest_duckyscript;
+extern crate serde_json;
+extern crate sha2;
 
 use anyhow::{anyhow, Context, Result};
 use pest_duckyscript::mallardscript;
 use std::{
     collections::HashMap,
     io::{Seek, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-static INDENTATION_SIZE: usize = 2;
+pub mod analysis;
+pub mod cache;
+pub mod converter;
+pub mod device;
+pub mod diagnostic;
+pub mod directive;
+pub mod downlevel;
+pub mod expression;
+pub mod fixture;
+pub mod flipper;
+pub mod lint;
+pub mod manifest;
+pub mod metadata;
+pub mod minifier;
+pub mod optimizer;
+pub mod preprocessor;
+pub mod scenario;
+pub mod simulator;
+pub mod timing;
+
+/// Options used to compile MallardScript.
+#[derive(Debug, Default)]
+pub struct CompileOptions {
+    /// Compile time defines referenced in MallardScript as `#NAME`.
+    pub defines: HashMap<String, String>,
+    /// Fold constant conditions and remove unreachable blocks.
+    pub optimize: bool,
+    /// Indentation of `IF` and `WHILE` bodies.
+    pub indentation: Indentation,
+    /// DuckyScript dialect of the output.
+    pub target: Target,
+    /// Device the output runs on, commands it doesn't support are rejected or warned about.
+    pub device: Option<device::Device>,
+    /// Path of the define file the defines were loaded from, with the SHA-256 hash of its contents.
+    pub define_file: Option<(PathBuf, String)>,
+}
+
+/// DuckyScript dialect compiled to.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Target {
+    /// DuckyScript 3.0, with variables, blocks and defines.
+    #[default]
+    DuckyScript3,
+    /// DuckyScript 1.0, with 3.0 statements lowered at compile time.
+    DuckyScript1,
+    /// Flipper Zero BadUSB, lowered like DuckyScript 1.0 with its own key names.
+    Flipper,
+}
+
+impl std::str::FromStr for Target {
+    type Err = anyhow::Error;
+
+    /// Parse a target, `duckyscript3`, `duckyscript1` or `flipper`.
+    fn from_str(target: &str) -> Result<Self> {
+        match target {
+            "duckyscript3" => Ok(Target::DuckyScript3),
+            "duckyscript1" => Ok(Target::DuckyScript1),
+            "flipper" => Ok(Target::Flipper),
+            _ => Err(anyhow!(
+                "Invalid target '{}', expected 'duckyscript3', 'duckyscript1' or 'flipper'.",
+                target
+            )),
+        }
+    }
+}
+
+impl Target {
+    /// Name of the target, as it's parsed.
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::DuckyScript3 => "duckyscript3",
+            Target::DuckyScript1 => "duckyscript1",
+            Target::Flipper => "flipper",
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::DuckyScript3 => write!(f, "DuckyScript 3.0"),
+            Target::DuckyScript1 => write!(f, "DuckyScript 1.0"),
+            Target::Flipper => write!(f, "Flipper Zero BadUSB"),
+        }
+    }
+}
+
+/// Indentation style of emitted blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indentation {
+    Spaces(usize),
+    Tabs,
+    None,
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Indentation::Spaces(2)
+    }
+}
+
+impl std::str::FromStr for Indentation {
+    type Err = anyhow::Error;
+
+    /// Parse an indentation style, a number of spaces, `tab` or `none`.
+    fn from_str(style: &str) -> Result<Self> {
+        match style {
+            "tab" => Ok(Indentation::Tabs),
+            "none" => Ok(Indentation::None),
+            spaces => spaces
+                .parse::<usize>()
+                .map(Indentation::Spaces)
+                .map_err(|_| {
+                    anyhow!(
+                        "Invalid indentation '{}', expected a number of spaces, 'tab' or 'none'.",
+                        style
+                    )
+                }),
+        }
+    }
+}
+
+impl Indentation {
+    /// Render the indentation of a nesting level.
+    pub fn render(self, level: usize) -> String {
+        match self {
+            Indentation::Spaces(spaces) => " ".repeat(spaces * level),
+            Indentation::Tabs => "\t".repeat(level),
+            Indentation::None => String::new(),
+        }
+    }
+}
+
+/// State shared while compiling MallardScript and its imports.
+#[derive(Debug, Default)]
+pub struct CompileState {
+    /// Imports compiled so far, used to detect circular dependencies.
+    pub imports_visited: HashMap<String, bool>,
+    /// Number of runtime loops lowered so far, used to name loop counters.
+    pub loops: usize,
+    /// Types of the variables declared so far, used to type check expressions.
+    pub variables: HashMap<String, expression::Type>,
+    /// Values known so far when lowering to a target without DuckyScript 3.0.
+    pub lowering: downlevel::Lowering,
+    /// Canonical paths of the files compiled with the SHA-256 hash of the contents they were
+    /// compiled from, in the order they're first compiled.
+    pub sources: Vec<(PathBuf, String)>,
+    /// Parsed statements of the files compiled, shared with the other passes of a build.
+    pub cache: cache::Cache,
+}
 
 /// Compile MallardScript input path to DuckyScript output file.
+/// Output is indented by the provided nesting level, using the indentation style of the options.
 pub fn compile(
     current_directory: PathBuf,
     input_path: &str,
     output_file: &std::fs::File,
     indentation: usize,
-    imports_visited: &mut HashMap<String, bool>,
+    options: &CompileOptions,
+    state: &mut CompileState,
+) -> Result<()> {
+    compile_file(
+        current_directory,
+        input_path,
+        &HashMap::new(),
+        output_file,
+        indentation,
+        options,
+        state,
+    )
+}
+
+/// Compile MallardScript input path with its import arguments to DuckyScript output file.
+fn compile_file(
+    current_directory: PathBuf,
+    input_path: &str,
+    arguments: &HashMap<String, String>,
+    output_file: &std::fs::File,
+    indentation: usize,
+    options: &CompileOptions,
+    state: &mut CompileState,
 ) -> Result<()> {
     log::info!("Compiling '{}'.", input_path);
 
-    // Expand our input path.
-    let input_path_expanded = std::fs::canonicalize(current_directory.join(input_path))
-        .with_context(|| {
-            format!(
-                "Unable to find file input '{}' from '{}'.",
+    let entry = state.sources.is_empty();
+    let source = load(
+        &current_directory,
+        input_path,
+        arguments,
+        options,
+        &mut state.cache,
+    )?;
+
+    // Metadata is only read from the entry file, imports can't declare it.
+    if !entry {
+        if let Some(line_number) = metadata::find_front_matter(&source.preprocessed) {
+            return Err(anyhow!(
+                "Metadata at '{}:{}' must be declared in the entry file.",
                 input_path,
-                current_directory.display()
-            )
-        })?;
+                line_number
+            ));
+        }
+    }
+    let input_path_expanded = source.path;
+    if !state
+        .sources
+        .iter()
+        .any(|(path, _)| *path == input_path_expanded)
+    {
+        state
+            .sources
+            .push((input_path_expanded.clone(), source.sha256));
+    }
 
     // Handle Circular Dependencies.
     // Do not compile input, if we've already compiled it before.
-    if imports_visited.contains_key(input_path) {
+    if state.imports_visited.contains_key(input_path) {
         return Err(anyhow!("Circular dependency detected."));
     } else {
         // Mark import as visited.
-        imports_visited.insert(
+        state.imports_visited.insert(
             input_path_expanded
                 .clone()
                 .into_os_string()
@@ -46,19 +240,61 @@ pub fn compile(
             true,
         );
     }
+    let mut program_ast = source.statements;
 
-    // Load input contents.
-    let input_contents = std::fs::read_to_string(&input_path_expanded).with_context(|| {
-        format!(
-            "Unable to load file input '{}' from '{}'.",
-            input_path_expanded.display(),
-            current_directory.display()
-        )
-    })?;
+    // Type check expressions.
+    // Files imported inside a block are nested in it.
+    check_statements(
+        input_path,
+        &program_ast,
+        &mut source.line_numbers.iter().copied(),
+        indentation > 0,
+        options.device,
+        options.target,
+        &mut state.variables,
+    )?;
 
-    // Parse input contents into AST.
-    let program_ast = mallardscript::parser::parse_document(input_contents)
-        .with_context(|| ("Unable to parse input."))?;
+    // Lower statements as they're compiled, so imports see the variables set before them.
+    // Lowering resolves every block, so the AST isn't optimized first.
+    if options.target != Target::DuckyScript3 {
+        let mut pending: std::collections::VecDeque<_> =
+            downlevel::split(&program_ast, &source.line_numbers).into();
+        while let Some((statement, line_numbers)) = pending.pop_front() {
+            match state
+                .lowering
+                .lower(options.target, input_path, statement, line_numbers)?
+            {
+                downlevel::Lowered::Statements(statements) => {
+                    for statement in statements {
+                        compile_statement(
+                            input_path,
+                            input_path_expanded.clone(),
+                            statement,
+                            output_file,
+                            indentation,
+                            options,
+                            state,
+                        )?;
+                    }
+                }
+                downlevel::Lowered::Expanded(statements) => {
+                    for statement in statements.into_iter().rev() {
+                        pending.push_front(statement);
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Lower runtime loops before optimizing, so folding their condition can't lose the counter.
+    program_ast = lower_loops(program_ast, state);
+
+    // Optimize AST.
+    if options.optimize {
+        program_ast = optimizer::optimize(input_path, program_ast)?;
+    }
 
     // Process AST.
     for statement in program_ast {
@@ -68,17 +304,406 @@ pub fn compile(
             statement,
             output_file,
             indentation,
-            imports_visited,
+            options,
+            state,
         )?;
     }
 
     Ok(())
 }
 
+/// MallardScript source file, preprocessed and parsed.
+#[derive(Debug)]
+pub struct Source {
+    /// Canonical path of the source file.
+    pub path: PathBuf,
+    /// SHA-256 hash of the contents read from the source file.
+    pub sha256: String,
+    /// Preprocessed contents with the source line number of each line.
+    pub preprocessed: preprocessor::Preprocessed,
+    pub statements: Vec<mallardscript::ast::Statement>,
+    /// Source line number of each statement, as returned by `diagnostic::locate`.
+    pub line_numbers: Vec<usize>,
+    /// Rule levels set with `REM mallard: allow(rule)`, `warning(rule)` or `error(rule)` directives.
+    pub levels: Vec<directive::RuleLevel>,
+}
+
+/// Load MallardScript input path with its import arguments, without optimizing it.
+/// Compiler directives written as `REM mallard: ...` are applied and removed.
+/// Parsed statements are looked up in the cache, shared by the passes over the same sources.
+pub fn load(
+    current_directory: &Path,
+    input_path: &str,
+    arguments: &HashMap<String, String>,
+    options: &CompileOptions,
+    cache: &mut cache::Cache,
+) -> Result<Source> {
+    // Expand our input path.
+    let input_path_expanded = std::fs::canonicalize(current_directory.join(input_path))
+        .with_context(|| {
+            format!(
+                "Unable to find file input '{}' from '{}'.",
+                input_path,
+                current_directory.display()
+            )
+        })?;
+
+    // Load input contents.
+    let input_contents = std::fs::read(&input_path_expanded)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(String::from_utf8(contents)?))
+        .with_context(|| {
+            format!(
+                "Unable to load file input '{}' from '{}'.",
+                input_path_expanded.display(),
+                current_directory.display()
+            )
+        })?;
+    let sha256 = manifest::sha256(input_contents.as_bytes());
+
+    // Preprocess compile time conditionals, macros, defines and import arguments.
+    let mut preprocessed =
+        preprocessor::preprocess(input_path, &input_contents, &options.defines, arguments)?;
+    // Some Flipper Zero BadUSB commands would be parsed as other commands.
+    if options.target == Target::Flipper {
+        preprocessed = flipper::encode_commands(preprocessed);
+    }
+
+    // Parse input contents into AST.
+    let parse = || {
+        let mut statements = mallardscript::parser::parse_document(preprocessed.contents.clone())
+            .with_context(|| "Unable to parse input.")?;
+        preprocessor::decode_expressions(&mut statements);
+        Ok(statements)
+    };
+    let statements = cache.parse(&preprocessed.contents, parse)?;
+
+    // Apply compiler directives.
+    let line_numbers = diagnostic::locate(
+        &statements,
+        &preprocessed.contents,
+        &preprocessed.line_numbers,
+    );
+    let (statements, line_numbers, levels) =
+        directive::apply(input_path, statements, line_numbers)?;
+
+    Ok(Source {
+        path: input_path_expanded,
+        sha256,
+        preprocessed,
+        statements,
+        line_numbers,
+        levels,
+    })
+}
+
+/// Lower runtime `LOOP` blocks into a counter declaration and a counted `WHILE` block.
+/// Counters are named by the number of loops lowered so far, so nested loops don't collide.
+fn lower_loops(
+    statements: Vec<mallardscript::ast::Statement>,
+    state: &mut CompileState,
+) -> Vec<mallardscript::ast::Statement> {
+    let mut statements_lowered = Vec::with_capacity(statements.len());
+
+    for statement in statements {
+        match statement {
+            mallardscript::ast::Statement::BlockIf(block) => {
+                statements_lowered.push(mallardscript::ast::Statement::BlockIf(
+                    mallardscript::ast::StatementBlockIf {
+                        expression: block.expression,
+                        statements_true: lower_loops(block.statements_true, state),
+                        statements_false: lower_loops(block.statements_false, state),
+                    },
+                ));
+            }
+            mallardscript::ast::Statement::BlockWhile(block) => {
+                let count = match preprocessor::loop_count(&block.expression) {
+                    Some(count) => count,
+                    None => {
+                        statements_lowered.push(mallardscript::ast::Statement::BlockWhile(
+                            mallardscript::ast::StatementBlockWhile {
+                                expression: block.expression,
+                                statements: lower_loops(block.statements, state),
+                            },
+                        ));
+                        continue;
+                    }
+                };
+
+                state.loops += 1;
+                let counter = format!("{}{}", preprocessor::LOOP_COUNTER_PREFIX, state.loops);
+                log::info!("Lowering 'LOOP {}' with counter '${}'.", count, counter);
+
+                let mut statements = lower_loops(block.statements, state);
+                statements.push(mallardscript::ast::Statement::VariableAssignment(
+                    mallardscript::ast::StatementVariableAssignment {
+                        name: counter.clone(),
+                        assignment: format!("(${} + 1)", counter),
+                    },
+                ));
+                statements_lowered.push(mallardscript::ast::Statement::VariableDeclaration(
+                    mallardscript::ast::StatementVariableDeclaration {
+                        name: counter.clone(),
+                        assignment: String::from("0"),
+                    },
+                ));
+                statements_lowered.push(mallardscript::ast::Statement::BlockWhile(
+                    mallardscript::ast::StatementBlockWhile {
+                        expression: format!("(${} < {})", counter, count),
+                        statements,
+                    },
+                ));
+            }
+            statement => statements_lowered.push(statement),
+        }
+    }
+
+    statements_lowered
+}
+
+/// Type check the expressions of statements, in the order they're compiled.
+/// Nested statements are in a block, where global commands such as `DEFINE` aren't allowed.
+/// Commands the device doesn't support are errors, or warnings when they may not run as expected.
+/// Variables and blocks are checked too, unless the target lowers them at compile time.
+fn check_statements(
+    input_path: &str,
+    statements: &[mallardscript::ast::Statement],
+    line_numbers: &mut impl Iterator<Item = usize>,
+    nested: bool,
+    device: Option<device::Device>,
+    target: Target,
+    variables: &mut HashMap<String, expression::Type>,
+) -> Result<()> {
+    let check_construct = |line_number: usize, construct: &str| {
+        if target != Target::DuckyScript3 {
+            return Ok(());
+        }
+        check_device(input_path, line_number, construct, device)
+    };
+    let check_references =
+        |line_number: usize, expression: &str| match analysis::variables(expression).first() {
+            Some(name) => check_construct(line_number, &format!("${}", name)),
+            None => Ok(()),
+        };
+
+    for statement in statements {
+        let line_number = line_numbers.next().unwrap_or_default();
+        let check = |expression: &str, variables: &HashMap<String, expression::Type>| {
+            expression::parse(expression)
+                .and_then(|expression| expression::check(&expression, variables))
+                .with_context(|| {
+                    format!(
+                        "Invalid expression '{}' at '{}:{}'.",
+                        expression, input_path, line_number
+                    )
+                })
+        };
+
+        match statement {
+            mallardscript::ast::Statement::CommandDefine(command) if nested => {
+                return Err(anyhow!(
+                    "DEFINE '{}' at '{}:{}' can't be nested in a block, defines are global.",
+                    command.name,
+                    input_path,
+                    line_number
+                ));
+            }
+            mallardscript::ast::Statement::SingleCommand(command) => {
+                check_device(input_path, line_number, &command.name, device)?;
+            }
+            mallardscript::ast::Statement::CommandExfil(_) => {
+                check_device(input_path, line_number, "EXFIL", device)?;
+            }
+            mallardscript::ast::Statement::CommandDelay(command) => {
+                check_references(line_number, &command.value)?;
+            }
+            mallardscript::ast::Statement::CommandDefaultDelay(command) => {
+                check_references(line_number, &command.value)?;
+            }
+            // Only values parsed as expressions type variables, other values are typed as is.
+            mallardscript::ast::Statement::CommandString(command)
+                if expression::parse(&command.value).is_ok() =>
+            {
+                check_references(line_number, &command.value)?;
+            }
+            mallardscript::ast::Statement::CommandStringln(command)
+                if expression::parse(&command.value).is_ok() =>
+            {
+                check_references(line_number, &command.value)?;
+            }
+            mallardscript::ast::Statement::VariableDeclaration(variable) => {
+                check_construct(line_number, "VAR")?;
+                check_variable_name(input_path, line_number, &variable.name)?;
+                match check(&variable.assignment, variables)? {
+                    Some(variable_type) => variables.insert(variable.name.clone(), variable_type),
+                    None => variables.remove(&variable.name),
+                };
+            }
+            mallardscript::ast::Statement::VariableAssignment(variable) => {
+                check_construct(line_number, "VAR")?;
+                check_variable_name(input_path, line_number, &variable.name)?;
+                let assignment_type = check(&variable.assignment, variables)?;
+                if let (Some(variable_type), Some(assignment_type)) =
+                    (variables.get(&variable.name), assignment_type)
+                {
+                    if *variable_type != assignment_type {
+                        return Err(anyhow!(
+                            "Type mismatch at '{}:{}', variable '${}' is {} but is assigned {} '{}'.",
+                            input_path,
+                            line_number,
+                            variable.name,
+                            variable_type,
+                            assignment_type,
+                            variable.assignment
+                        ));
+                    }
+                }
+            }
+            mallardscript::ast::Statement::BlockIf(block) => {
+                check_construct(line_number, "IF")?;
+                check_condition(
+                    input_path,
+                    line_number,
+                    &block.expression,
+                    check(&block.expression, variables)?,
+                )?;
+                check_statements(
+                    input_path,
+                    &block.statements_true,
+                    line_numbers,
+                    true,
+                    device,
+                    target,
+                    variables,
+                )?;
+                check_statements(
+                    input_path,
+                    &block.statements_false,
+                    line_numbers,
+                    true,
+                    device,
+                    target,
+                    variables,
+                )?;
+            }
+            mallardscript::ast::Statement::BlockWhile(block) => {
+                match preprocessor::loop_count(&block.expression) {
+                    Some(count) => {
+                        check_construct(line_number, "LOOP")?;
+                        if let Some(count_type) = check(&count, variables)?
+                            .filter(|count_type| *count_type != expression::Type::Integer)
+                        {
+                            return Err(anyhow!(
+                                "Type mismatch at '{}:{}', LOOP count '{}' is {}.",
+                                input_path,
+                                line_number,
+                                count,
+                                count_type
+                            ));
+                        }
+                    }
+                    None => {
+                        check_construct(line_number, "WHILE")?;
+                        check_condition(
+                            input_path,
+                            line_number,
+                            &block.expression,
+                            check(&block.expression, variables)?,
+                        )?;
+                    }
+                }
+                check_statements(
+                    input_path,
+                    &block.statements,
+                    line_numbers,
+                    true,
+                    device,
+                    target,
+                    variables,
+                )?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Require a variable not to be named like the counters of runtime loops, which are generated.
+fn check_variable_name(input_path: &str, line_number: usize, name: &str) -> Result<()> {
+    if name.starts_with(preprocessor::LOOP_COUNTER_PREFIX) {
+        return Err(anyhow!(
+            "Variable '${}' at '{}:{}' is reserved for the counters of runtime loops.",
+            name,
+            input_path,
+            line_number
+        ));
+    }
+
+    Ok(())
+}
+
+/// Require the device, if any, to support a command, warning when it may not run as expected.
+fn check_device(
+    input_path: &str,
+    line_number: usize,
+    command: &str,
+    device: Option<device::Device>,
+) -> Result<()> {
+    let device = match device {
+        Some(device) => device,
+        None => return Ok(()),
+    };
+
+    match device.support(command) {
+        device::Support::Supported => {}
+        device::Support::Warning(reason) => log::warn!(
+            "'{}' at '{}:{}' may not run on {}, {}.",
+            command,
+            input_path,
+            line_number,
+            device,
+            reason
+        ),
+        device::Support::Unsupported(reason) => {
+            return Err(anyhow!(
+                "'{}' at '{}:{}' isn't supported by {}, {}.",
+                command,
+                input_path,
+                line_number,
+                device,
+                reason
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// Require a block condition to be a boolean or an integer.
+fn check_condition(
+    input_path: &str,
+    line_number: usize,
+    condition: &str,
+    condition_type: Option<expression::Type>,
+) -> Result<()> {
+    if condition_type == Some(expression::Type::String) {
+        return Err(anyhow!(
+            "Type mismatch at '{}:{}', condition '{}' is a string.",
+            input_path,
+            line_number,
+            condition
+        ));
+    }
+
+    Ok(())
+}
+
 /// Compile MallardScript simple command to DuckyScript output file.
 fn compile_simple_statement(
     output_file: &std::fs::File,
     indentation: usize,
+    options: &CompileOptions,
     command_name: String,
     command_value: Option<String>,
 ) -> Result<()> {
@@ -88,11 +713,17 @@ fn compile_simple_statement(
         write_statement(
             output_file,
             indentation,
+            options,
             format!("{} {}\n", command_name, value),
         )?;
     } else {
         // Process all other statement commands.
-        write_statement(output_file, indentation, format!("{}\n", command_name))?;
+        write_statement(
+            output_file,
+            indentation,
+            options,
+            format!("{}\n", command_name),
+        )?;
     }
 
     Ok(())
@@ -105,13 +736,15 @@ fn compile_statement(
     statement: mallardscript::ast::Statement,
     mut output_file: &std::fs::File,
     indentation: usize,
-    imports_visited: &mut HashMap<String, bool>,
+    options: &CompileOptions,
+    state: &mut CompileState,
 ) -> Result<()> {
     match statement {
         mallardscript::ast::Statement::CommandDefaultDelay(command) => {
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("DEFAULTDELAY"),
                 command.value.into(),
             )?;
@@ -120,14 +753,16 @@ fn compile_statement(
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("DEFINE"),
-                command.value.into(),
+                Some(format!("{} {}", command.name, command.value)),
             )?;
         }
         mallardscript::ast::Statement::CommandDelay(command) => {
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("DELAY"),
                 command.value.into(),
             )?;
@@ -136,47 +771,20 @@ fn compile_statement(
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("EXFIL"),
-                command.name.into(),
+                Some(format!("${}", command.name)),
             )?;
         }
         mallardscript::ast::Statement::CommandKey(command) => {
-            fn collect_command_key_values(
-                command_key: mallardscript::ast::StatementCommandKey,
-            ) -> Vec<String> {
-                // Collect all command key statement command key values.
-                let mut command_key_statements_reduced = command_key.statements.into_iter().fold(
-                    vec![] as Vec<String>,
-                    |mut accumulation, statement| {
-                        if let mallardscript::ast::Statement::CommandKey(statement_command_key) =
-                            statement
-                        {
-                            accumulation.extend(collect_command_key_values(statement_command_key));
-                        } else if let mallardscript::ast::Statement::CommandKeyValue(
-                            statement_command_key_value,
-                        ) = statement
-                        {
-                            accumulation.push(statement_command_key_value.name);
-                        }
-
-                        accumulation
-                    },
-                );
-
-                if !command_key.remaining_keys.is_empty() {
-                    command_key_statements_reduced.push(command_key.remaining_keys);
-                }
-
-                command_key_statements_reduced
-            }
-
-            let command_reduced = collect_command_key_values(command).join(" ");
-            compile_simple_statement(output_file, indentation, command_reduced, None)?;
+            let command_reduced = collect_command_key_values(&command).join(" ");
+            compile_simple_statement(output_file, indentation, options, command_reduced, None)?;
         }
         mallardscript::ast::Statement::CommandRem(command) => {
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("REM"),
                 command.value.into(),
             )?;
@@ -185,6 +793,7 @@ fn compile_statement(
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("STRING"),
                 command.value.into(),
             )?;
@@ -193,12 +802,13 @@ fn compile_statement(
             compile_simple_statement(
                 output_file,
                 indentation,
+                options,
                 String::from("STRINGLN"),
                 command.value.into(),
             )?;
         }
         mallardscript::ast::Statement::SingleCommand(command) => {
-            compile_simple_statement(output_file, indentation, command.name, None)?;
+            compile_simple_statement(output_file, indentation, options, command.name, None)?;
         }
         mallardscript::ast::Statement::VariableDeclaration(variable) => {
             log::info!("Processing '${} = {}'.", variable.name, variable.assignment);
@@ -207,6 +817,7 @@ fn compile_statement(
             write_statement(
                 output_file,
                 indentation,
+                options,
                 format!("VAR ${} = {}\n", variable.name, variable.assignment),
             )?;
         }
@@ -217,6 +828,7 @@ fn compile_statement(
             write_statement(
                 output_file,
                 indentation,
+                options,
                 format!("${} = {}\n", variable.name, variable.assignment),
             )?;
         }
@@ -225,26 +837,33 @@ fn compile_statement(
             // Make sure to get the current working directory so imports can resolve locally.
             let mut new_current_directory = input_path_expanded;
             new_current_directory.pop();
-            compile(
+            let (import_path, import_arguments) = preprocessor::decode_import(&command.value);
+            compile_file(
                 new_current_directory,
-                &command.value,
+                &import_path,
+                &import_arguments,
                 output_file,
                 indentation,
-                imports_visited,
+                options,
+                state,
             )
             .context(format!(
                 "Unable to import file '{}' from '{}'.",
-                command.value, input_path
+                import_path, input_path
             ))?;
 
-            // Add a new line after import file compilation.
-            write_statement(output_file, indentation, String::from("\n"))?;
+            // Restore the new line removed by the end of the import file.
+            // It ends the last imported line, so it isn't indented.
+            output_file
+                .write_all(b"\n")
+                .context("Unable to write to output file.")?;
         }
         mallardscript::ast::Statement::BlockIf(block) => {
             // Process block if statement.
             write_statement(
                 output_file,
                 indentation,
+                options,
                 format!("IF {} THEN\n", block.expression),
             )?;
 
@@ -255,14 +874,15 @@ fn compile_statement(
                     input_path_expanded.clone(),
                     statement,
                     output_file,
-                    indentation + INDENTATION_SIZE,
-                    imports_visited,
+                    indentation + 1,
+                    options,
+                    state,
                 )?;
             }
 
             // Add ELSE statement.
             if !block.statements_false.is_empty() {
-                write_statement(output_file, indentation, String::from("ELSE\n"))?;
+                write_statement(output_file, indentation, options, String::from("ELSE\n"))?;
 
                 // Process block if statement, false case statements.
                 for statement in block.statements_false {
@@ -271,20 +891,22 @@ fn compile_statement(
                         input_path_expanded.clone(),
                         statement,
                         output_file,
-                        indentation + INDENTATION_SIZE,
-                        imports_visited,
+                        indentation + 1,
+                        options,
+                        state,
                     )?;
                 }
             }
 
             // Add the END_IF statement.
-            write_statement(output_file, indentation, String::from("END_IF\n"))?;
+            write_statement(output_file, indentation, options, String::from("END_IF\n"))?;
         }
         mallardscript::ast::Statement::BlockWhile(block) => {
             // Process block while statement.
             write_statement(
                 output_file,
                 indentation,
+                options,
                 format!("WHILE {}\n", block.expression),
             )?;
 
@@ -295,26 +917,31 @@ fn compile_statement(
                     input_path_expanded.clone(),
                     statement,
                     output_file,
-                    indentation + INDENTATION_SIZE,
-                    imports_visited,
+                    indentation + 1,
+                    options,
+                    state,
                 )?;
             }
 
             // Add the END_WHILE statement.
-            write_statement(output_file, indentation, String::from("END_WHILE\n"))?;
+            write_statement(
+                output_file,
+                indentation,
+                options,
+                String::from("END_WHILE\n"),
+            )?;
         }
         mallardscript::ast::Statement::End { .. } => {
             log::info!("Processing End.");
 
-            // Remove statement end line from end of file.
+            // Remove statement end line from end of file, unless nothing was written.
             output_file
                 .set_len(
                     output_file
                         .metadata()
                         .unwrap()
                         .len()
-                        .checked_sub("\n".len() as u64)
-                        .unwrap(),
+                        .saturating_sub("\n".len() as u64),
                 )
                 .unwrap();
             output_file.seek(std::io::SeekFrom::End(0))?;
@@ -327,15 +954,46 @@ fn compile_statement(
     Ok(())
 }
 
+/// Collect the keys of a key command and its nested key commands, such as `GUI r`.
+fn collect_command_key_values(
+    command_key: &mallardscript::ast::StatementCommandKey,
+) -> Vec<String> {
+    // Collect all command key statement command key values.
+    let mut command_key_statements_reduced =
+        command_key
+            .statements
+            .iter()
+            .fold(vec![] as Vec<String>, |mut accumulation, statement| {
+                if let mallardscript::ast::Statement::CommandKey(statement_command_key) = statement
+                {
+                    accumulation.extend(collect_command_key_values(statement_command_key));
+                } else if let mallardscript::ast::Statement::CommandKeyValue(
+                    statement_command_key_value,
+                ) = statement
+                {
+                    accumulation.push(statement_command_key_value.name.clone());
+                }
+
+                accumulation
+            });
+
+    if !command_key.remaining_keys.is_empty() {
+        command_key_statements_reduced.push(command_key.remaining_keys.clone());
+    }
+
+    command_key_statements_reduced
+}
+
 /// Write a statement line to the output file.
 /// This also adds indentation for the statement line.
 fn write_statement(
     mut output_file: &std::fs::File,
     indentation: usize,
+    options: &CompileOptions,
     line: String,
 ) -> Result<()> {
     output_file
-        .write_all(format!("{}{}", " ".repeat(indentation), line).as_bytes())
+        .write_all(format!("{}{}", options.indentation.render(indentation), line).as_bytes())
         .context("Unable to write to output file.")?;
 
     Ok(())
@@ -0,0 +1,411 @@
+use anyhow::{anyhow, Context, Result};
+use cache::Cache;
+use diagnostic::{Diagnostic, Location, Severity};
+use directive::{self, RuleLevel};
+use expression;
+use pest_duckyscript::mallardscript::ast::Statement;
+use preprocessor;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use {load, CompileOptions};
+
+/// Lint rule with its default severity.
+pub struct Rule {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+}
+
+/// Lint rules checked by `lint`.
+pub static RULES: &[Rule] = &[
+    Rule {
+        id: "missing-defaultdelay",
+        severity: Severity::Warning,
+        description: "the payload never sets a DEFAULTDELAY",
+    },
+    Rule {
+        id: "suspicious-delay",
+        severity: Severity::Warning,
+        description: "a DELAY is shorter than 10 ms or longer than 60000 ms",
+    },
+    Rule {
+        id: "trailing-whitespace",
+        severity: Severity::Warning,
+        description: "a STRING or STRINGLN ends with whitespace that's easy to miss",
+    },
+    Rule {
+        id: "while-true-without-delay",
+        severity: Severity::Error,
+        description: "a WHILE TRUE loop has no DELAY and floods the host",
+    },
+    Rule {
+        id: "unreachable-code",
+        severity: Severity::Warning,
+        description: "a statement follows STOP_PAYLOAD or RESTART_PAYLOAD",
+    },
+    Rule {
+        id: "empty-branch",
+        severity: Severity::Warning,
+        description: "an IF has no statements when its condition is TRUE",
+    },
+    Rule {
+        id: "duplicate-import",
+        severity: Severity::Warning,
+        description: "a file is imported more than once with the same arguments",
+    },
+];
+
+/// Shortest `DELAY` in milliseconds that isn't suspicious.
+static DELAY_MIN: u64 = 10;
+
+/// Longest `DELAY` in milliseconds that isn't suspicious.
+static DELAY_MAX: u64 = 60000;
+
+/// Commands after which no statement of the same block runs, checked by `unreachable-code`.
+/// DuckyScript has no `END` command ending a payload, `END` presses the End key, so code is
+/// unreachable after the commands that end it instead.
+static COMMANDS_TERMINATING: &[&str] = &["STOP_PAYLOAD", "RESTART_PAYLOAD"];
+
+/// Import found while linting, identified by its resolved path and sorted arguments.
+struct Import {
+    path: PathBuf,
+    arguments: Vec<(String, String)>,
+    location: Location,
+}
+
+/// Lint state of a program and its imports.
+struct Linter<'a> {
+    /// Files being linted, used to detect circular dependencies.
+    imports_stack: Vec<PathBuf>,
+    cache: &'a mut Cache,
+    /// Imports found so far with their arguments and location.
+    imports: Vec<Import>,
+    default_delay: bool,
+    /// Location of the first statement of the program.
+    location_first: Option<Location>,
+    diagnostics: Vec<Diagnostic>,
+    levels: Vec<RuleLevel>,
+}
+
+/// Lint MallardScript input path and its imports.
+/// Rules are configured for each statement with `REM mallard: allow(rule)`,
+/// `REM mallard: warning(rule)` or `REM mallard: error(rule)` directives, and for each file with
+/// `REM mallard: file ...` directives.
+pub fn lint(
+    current_directory: PathBuf,
+    input_path: &str,
+    options: &CompileOptions,
+    cache: &mut Cache,
+) -> Result<Vec<Diagnostic>> {
+    let mut linter = Linter {
+        imports_stack: vec![],
+        cache,
+        imports: vec![],
+        default_delay: false,
+        location_first: None,
+        diagnostics: vec![],
+        levels: vec![],
+    };
+    linter.lint_file(&current_directory, input_path, &HashMap::new(), options)?;
+
+    // Reported at the first statement, so it's configured by the directives before it.
+    if !linter.default_delay {
+        let location = linter.location_first.take().unwrap_or_else(|| Location {
+            input_path: String::from(input_path),
+            line_number: 1,
+        });
+        linter.report(
+            "missing-defaultdelay",
+            &location,
+            String::from(
+                "Payload never sets 'DEFAULTDELAY', keystrokes may be dropped by slow hosts.",
+            ),
+        );
+    }
+
+    Ok(directive::apply_levels(linter.diagnostics, &linter.levels))
+}
+
+/// Find a lint rule by its ID.
+pub fn rule(id: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|rule| rule.id == id)
+}
+
+impl Linter<'_> {
+    /// Lint MallardScript input path with its import arguments.
+    fn lint_file(
+        &mut self,
+        current_directory: &Path,
+        input_path: &str,
+        arguments: &HashMap<String, String>,
+        options: &CompileOptions,
+    ) -> Result<()> {
+        let source = load(
+            current_directory,
+            input_path,
+            arguments,
+            options,
+            self.cache,
+        )?;
+        if self.imports_stack.contains(&source.path) {
+            return Err(anyhow!("Circular dependency detected."));
+        }
+
+        self.levels.extend(source.levels);
+
+        self.imports_stack.push(source.path.clone());
+        self.lint_statements(
+            input_path,
+            &source.path,
+            &source.statements,
+            &mut source.line_numbers.into_iter(),
+            options,
+        )?;
+        self.imports_stack.pop();
+
+        Ok(())
+    }
+
+    /// Lint statements of a block, following imports.
+    fn lint_statements(
+        &mut self,
+        input_path: &str,
+        input_path_expanded: &Path,
+        statements: &[Statement],
+        line_numbers: &mut impl Iterator<Item = usize>,
+        options: &CompileOptions,
+    ) -> Result<()> {
+        let mut terminated_by: Option<(&str, Location)> = None;
+
+        for statement in statements {
+            let location = Location {
+                input_path: String::from(input_path),
+                line_number: line_numbers.next().unwrap_or_default(),
+            };
+
+            match statement {
+                Statement::End(_) | Statement::CommandRem(_) => {}
+                _ => {
+                    if self.location_first.is_none() {
+                        self.location_first = Some(location.clone());
+                    }
+                    if let Some((command, command_location)) = terminated_by.take() {
+                        self.report(
+                            "unreachable-code",
+                            &location,
+                            format!(
+                                "Statement is unreachable after '{}' at '{}'.",
+                                command, command_location
+                            ),
+                        );
+                    }
+                }
+            }
+
+            match statement {
+                Statement::SingleCommand(command)
+                    if COMMANDS_TERMINATING.contains(&command.name.as_str()) =>
+                {
+                    terminated_by = Some((&command.name, location));
+                }
+                Statement::CommandDefaultDelay(_) => self.default_delay = true,
+                Statement::CommandDelay(command) => {
+                    let delay = expression::parse(&command.value)
+                        .ok()
+                        .and_then(|expression| expression::evaluate(&expression))
+                        .and_then(|value| value.as_integer());
+                    if let Some(delay) =
+                        delay.filter(|delay| *delay < DELAY_MIN || *delay > DELAY_MAX)
+                    {
+                        self.report(
+                            "suspicious-delay",
+                            &location,
+                            format!(
+                                "'DELAY {}' is outside of {} to {} ms.",
+                                delay, DELAY_MIN, DELAY_MAX
+                            ),
+                        );
+                    }
+                }
+                Statement::CommandString(command) => {
+                    self.lint_string("STRING", &command.value, &location)
+                }
+                Statement::CommandStringln(command) => {
+                    self.lint_string("STRINGLN", &command.value, &location)
+                }
+                Statement::CommandImport(command) => {
+                    let mut import_directory = input_path_expanded.to_path_buf();
+                    import_directory.pop();
+                    let (import_path, import_arguments) =
+                        preprocessor::decode_import(&command.value);
+
+                    // Compare imports by their resolved path and arguments.
+                    if let Ok(import_path_expanded) =
+                        std::fs::canonicalize(import_directory.join(&import_path))
+                    {
+                        let mut arguments =
+                            import_arguments.clone().into_iter().collect::<Vec<_>>();
+                        arguments.sort();
+                        let import_first = self.imports.iter().find(|import| {
+                            import.path == import_path_expanded && import.arguments == arguments
+                        });
+                        match import_first {
+                            Some(import) => {
+                                let message = format!(
+                                    "File '{}' is already imported at '{}'.",
+                                    import_path, import.location
+                                );
+                                self.report("duplicate-import", &location, message);
+                            }
+                            None => self.imports.push(Import {
+                                path: import_path_expanded,
+                                arguments,
+                                location: location.clone(),
+                            }),
+                        }
+                    }
+
+                    self.lint_file(&import_directory, &import_path, &import_arguments, options)
+                        .context(format!(
+                            "Unable to import file '{}' from '{}'.",
+                            import_path, input_path
+                        ))?;
+                }
+                Statement::BlockIf(block) => {
+                    if block.statements_true.is_empty() {
+                        self.report(
+                            "empty-branch",
+                            &location,
+                            format!("'IF {}' has no statements.", block.expression),
+                        );
+                    }
+                    self.lint_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_true,
+                        line_numbers,
+                        options,
+                    )?;
+                    self.lint_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_false,
+                        line_numbers,
+                        options,
+                    )?;
+                }
+                Statement::BlockWhile(block) => {
+                    let always = expression::parse(&block.expression)
+                        .ok()
+                        .and_then(|expression| expression::evaluate(&expression))
+                        .and_then(|value| value.as_boolean());
+                    if always == Some(true)
+                        && !self.contains_delay(
+                            input_path_expanded,
+                            &block.statements,
+                            options,
+                            &mut vec![],
+                        )
+                    {
+                        self.report(
+                            "while-true-without-delay",
+                            &location,
+                            format!(
+                                "'WHILE {}' loops forever without a 'DELAY'.",
+                                block.expression
+                            ),
+                        );
+                    }
+                    self.lint_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements,
+                        line_numbers,
+                        options,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Does a block contain a `DELAY`, including nested blocks and the files it imports.
+    /// Imports that can't be loaded are reported when they're linted.
+    fn contains_delay(
+        &mut self,
+        input_path_expanded: &Path,
+        statements: &[Statement],
+        options: &CompileOptions,
+        imports_stack: &mut Vec<PathBuf>,
+    ) -> bool {
+        statements.iter().any(|statement| match statement {
+            Statement::CommandDelay(_) => true,
+            Statement::BlockIf(block) => {
+                self.contains_delay(
+                    input_path_expanded,
+                    &block.statements_true,
+                    options,
+                    imports_stack,
+                ) || self.contains_delay(
+                    input_path_expanded,
+                    &block.statements_false,
+                    options,
+                    imports_stack,
+                )
+            }
+            Statement::BlockWhile(block) => self.contains_delay(
+                input_path_expanded,
+                &block.statements,
+                options,
+                imports_stack,
+            ),
+            Statement::CommandImport(command) => {
+                let mut import_directory = input_path_expanded.to_path_buf();
+                import_directory.pop();
+                let (import_path, import_arguments) = preprocessor::decode_import(&command.value);
+                let source = match load(
+                    &import_directory,
+                    &import_path,
+                    &import_arguments,
+                    options,
+                    self.cache,
+                ) {
+                    Ok(source) if !imports_stack.contains(&source.path) => source,
+                    _ => return false,
+                };
+
+                imports_stack.push(source.path.clone());
+                let contains_delay =
+                    self.contains_delay(&source.path, &source.statements, options, imports_stack);
+                imports_stack.pop();
+                contains_delay
+            }
+            _ => false,
+        })
+    }
+
+    /// Lint the value of a string command.
+    fn lint_string(&mut self, command: &str, value: &str, location: &Location) {
+        if value.ends_with(char::is_whitespace) {
+            self.report(
+                "trailing-whitespace",
+                location,
+                format!("'{}' ends with whitespace.", command),
+            );
+        }
+    }
+
+    /// Report a rule violation with the default severity of the rule.
+    fn report(&mut self, rule_id: &'static str, location: &Location, message: String) {
+        if let Some(rule) = rule(rule_id) {
+            self.diagnostics.push(Diagnostic {
+                rule: rule.id,
+                severity: rule.severity,
+                location: location.clone(),
+                message,
+            });
+        }
+    }
+}
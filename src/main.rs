@@ -1,12 +1,19 @@
 extern crate anyhow;
 extern crate mallardscript;
 extern crate pest_duckyscript;
+extern crate serde_json;
 
 use anyhow::{anyhow, Context, Result};
-use mallardscript::compile;
+use mallardscript::diagnostic::Severity;
+use mallardscript::fixture::{self, Outcome};
+use mallardscript::metadata::Metadata;
+use mallardscript::scenario::Scenario;
+use mallardscript::{
+    analysis, cache, compile, converter, flipper, lint, manifest, minifier, preprocessor,
+    simulator, timing, CompileOptions, CompileState, Target,
+};
 use pest_duckyscript::duckyscript;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Entry point for mallardscript.
 fn main() -> Result<()> {
@@ -14,7 +21,7 @@ fn main() -> Result<()> {
     let args = create_application()?.get_matches();
 
     // Initialize logger.
-    initialize_logger();
+    initialize_logger(args.occurrences_of("verbose"));
 
     // Run the application.
     if let Err(e) = run(args) {
@@ -26,12 +33,20 @@ fn main() -> Result<()> {
 
 /// Create the application command line interface.
 fn create_application() -> Result<clap::App<'static, 'static>> {
-    return Ok(clap::App::new(clap::crate_name!())
+    Ok(clap::App::new(clap::crate_name!())
         .bin_name(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
         .about(clap::crate_description!())
         .setting(clap::AppSettings::ArgRequiredElseHelp)
+        .arg(
+            clap::Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .global(true)
+                .help("increase logging verbosity"),
+        )
         .subcommand(
             clap::SubCommand::with_name("completions")
                 .about("completions")
@@ -45,6 +60,173 @@ fn create_application() -> Result<clap::App<'static, 'static>> {
                         .case_insensitive(true),
                 ),
         )
+        .subcommand(
+            clap::SubCommand::with_name("lint")
+                .about("lint mallardscript input")
+                .arg(
+                    clap::Arg::with_name("input")
+                        .short("in")
+                        .long("input")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("index.ducky")
+                        .help("entry file to lint"),
+                )
+                .arg(
+                    clap::Arg::with_name("define")
+                        .short("D")
+                        .long("define")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("NAME=value")
+                        .help("compile time define referenced as '#NAME'"),
+                )
+                .arg(
+                    clap::Arg::with_name("define-file")
+                        .long("define-file")
+                        .required(false)
+                        .takes_value(true)
+                        .help("file of 'NAME=value' compile time defines"),
+                )
+                .arg(
+                    clap::Arg::with_name("cache-dir")
+                        .long("cache-dir")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("directory caching parsed files between runs, by content hash"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("test")
+                .about("test mallardscript fixtures against their expected output")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value(".")
+                        .help("fixture file or directory to search for fixtures"),
+                )
+                .arg(
+                    clap::Arg::with_name("update")
+                        .long("update")
+                        .required(false)
+                        .takes_value(false)
+                        .help("write the expected files of fixtures instead of comparing them"),
+                )
+                .arg(
+                    clap::Arg::with_name("cache-dir")
+                        .long("cache-dir")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("directory caching parsed files between runs, by content hash"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("simulate")
+                .about("simulate mallardscript input keystrokes")
+                .arg(
+                    clap::Arg::with_name("input")
+                        .short("in")
+                        .long("input")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("index.ducky")
+                        .help("entry file to simulate"),
+                )
+                .arg(
+                    clap::Arg::with_name("define")
+                        .short("D")
+                        .long("define")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("NAME=value")
+                        .help("compile time define referenced as '#NAME'"),
+                )
+                .arg(
+                    clap::Arg::with_name("define-file")
+                        .long("define-file")
+                        .required(false)
+                        .takes_value(true)
+                        .help("file of 'NAME=value' compile time defines"),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("text")
+                        .possible_values(&["text", "json"])
+                        .help("format of the keystroke timeline"),
+                )
+                .arg(
+                    clap::Arg::with_name("chars-per-second")
+                        .long("chars-per-second")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("SPEED")
+                        .help("typing speed of string commands, defaults to 50"),
+                )
+                .arg(
+                    clap::Arg::with_name("scenario")
+                        .long("scenario")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("JSON scenario of device inputs and expected actions"),
+                )
+                .arg(
+                    clap::Arg::with_name("cache-dir")
+                        .long("cache-dir")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("directory caching parsed files between runs, by content hash"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("convert")
+                .about("convert duckyscript input to mallardscript")
+                .arg(
+                    clap::Arg::with_name("input")
+                        .short("in")
+                        .long("input")
+                        .required(true)
+                        .takes_value(true)
+                        .help("duckyscript file to convert"),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .short("out")
+                        .long("output")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("converted")
+                        .help("out directory to write mallardscript to"),
+                )
+                .arg(
+                    clap::Arg::with_name("extract")
+                        .long("extract")
+                        .required(false)
+                        .takes_value(false)
+                        .help("extract repeated statements into importable modules"),
+                )
+                .arg(
+                    clap::Arg::with_name("min-lines")
+                        .long("min-lines")
+                        .required(false)
+                        .takes_value(true)
+                        .requires("extract")
+                        .value_name("LINES")
+                        .help("minimum lines of an extracted module, defaults to 3"),
+                ),
+        )
         .subcommand(
             clap::SubCommand::with_name("build")
                 .about("build mallardscript input")
@@ -65,18 +247,242 @@ fn create_application() -> Result<clap::App<'static, 'static>> {
                         .takes_value(true)
                         .default_value("output")
                         .help("out directory to build to"),
+                )
+                .arg(
+                    clap::Arg::with_name("define")
+                        .short("D")
+                        .long("define")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("NAME=value")
+                        .help("compile time define referenced as '#NAME'"),
+                )
+                .arg(
+                    clap::Arg::with_name("define-file")
+                        .long("define-file")
+                        .required(false)
+                        .takes_value(true)
+                        .help("file of 'NAME=value' compile time defines"),
+                )
+                .arg(
+                    clap::Arg::with_name("optimize")
+                        .short("O")
+                        .long("optimize")
+                        .required(false)
+                        .takes_value(false)
+                        .help("fold constant conditions and remove unreachable blocks"),
+                )
+                .arg(
+                    clap::Arg::with_name("indent")
+                        .long("indent")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("2")
+                        .value_name("STYLE")
+                        .help("indentation of blocks, a number of spaces, 'tab' or 'none'"),
+                )
+                .arg(
+                    clap::Arg::with_name("target")
+                        .long("target")
+                        .required(false)
+                        .takes_value(true)
+                        .default_value("duckyscript3")
+                        .possible_values(&["duckyscript3", "duckyscript1", "flipper"])
+                        .help("duckyscript dialect to compile to"),
+                )
+                .arg(
+                    clap::Arg::with_name("device")
+                        .long("device")
+                        .required(false)
+                        .takes_value(true)
+                        .possible_values(&["rubber-ducky", "omg", "bash-bunny", "key-croc"])
+                        .help("device the payload runs on, rejecting commands it doesn't support"),
+                )
+                .arg(
+                    clap::Arg::with_name("metadata-file")
+                        .long("metadata-file")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("file of 'key=value' payload metadata written as the output header"),
+                )
+                .arg(
+                    clap::Arg::with_name("cache-dir")
+                        .long("cache-dir")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("directory caching parsed files between builds, by content hash"),
+                )
+                .arg(
+                    clap::Arg::with_name("manifest")
+                        .long("manifest")
+                        .required(false)
+                        .takes_value(false)
+                        .help("write a manifest hashing the sources and output of the build"),
+                )
+                .arg(
+                    clap::Arg::with_name("minify")
+                        .long("minify")
+                        .required(false)
+                        .takes_value(false)
+                        .help("remove comments, indentation and blank lines and merge strings"),
+                )
+                .arg(
+                    clap::Arg::with_name("analyze")
+                        .long("analyze")
+                        .required(false)
+                        .takes_value(false)
+                        .hidden(true)
+                        .help("report undeclared and unused variables, the default"),
+                )
+                .arg(
+                    clap::Arg::with_name("no-analyze")
+                        .long("no-analyze")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with("analyze")
+                        .help("don't report undeclared and unused variables"),
+                )
+                .arg(
+                    clap::Arg::with_name("report")
+                        .long("report")
+                        .required(false)
+                        .takes_value(true)
+                        .possible_values(&["timing"])
+                        .value_name("REPORT")
+                        .help(
+                            "report the estimated execution time of the payload, from its source before compilation",
+                        ),
+                )
+                .arg(
+                    clap::Arg::with_name("chars-per-second")
+                        .long("chars-per-second")
+                        .required(false)
+                        .takes_value(true)
+                        .value_name("SPEED")
+                        .help("typing speed of the timing report, defaults to 50"),
                 ),
-        ));
+        ))
+}
+
+/// Re-indent blocks with two spaces, the only indentation DuckyScript validation accepts.
+fn normalize_indentation(contents: &str) -> String {
+    let mut level: usize = 0;
+
+    contents
+        .lines()
+        .map(|line| {
+            let line = line.trim_start();
+            if line.starts_with("END_IF") || line.starts_with("END_WHILE") {
+                level = level.saturating_sub(1);
+            }
+            let line_level = if line == "ELSE" {
+                level.saturating_sub(1)
+            } else {
+                level
+            };
+            let line_normalized = format!("{}{}", "  ".repeat(line_level), line);
+            if (line.starts_with("IF ") && line.ends_with("THEN")) || line.starts_with("WHILE ") {
+                level += 1;
+            }
+
+            line_normalized
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Load payload metadata, the front matter of the entry file takes precedence over the metadata
+/// file. Declared metadata must have every required field.
+fn load_metadata(
+    args: &clap::ArgMatches,
+    current_directory: &Path,
+    input: &str,
+) -> Result<Metadata> {
+    let mut metadata = match args.value_of("metadata-file") {
+        Some(metadata_file) => Metadata::load(&PathBuf::from(
+            shellexpand::tilde(metadata_file).into_owned(),
+        ))?,
+        None => Metadata::default(),
+    };
+
+    // An entry file that can't be read is reported when it's compiled.
+    if let Ok(input_contents) = std::fs::read_to_string(current_directory.join(input)) {
+        metadata.extend(Metadata::parse_front_matter(input, &input_contents)?);
+    }
+    if !metadata.is_empty() {
+        metadata.validate()?;
+    }
+
+    Ok(metadata)
+}
+
+/// Load compile options shared by subcommands.
+/// Command line defines take precedence over the define file.
+fn load_compile_options(args: &clap::ArgMatches) -> Result<CompileOptions> {
+    let mut options = CompileOptions::default();
+    if let Some(define_file) = args.value_of("define-file") {
+        let define_file = PathBuf::from(shellexpand::tilde(define_file).into_owned());
+        let (defines, sha256) = preprocessor::load_define_file(&define_file)?;
+        options.defines = defines;
+        options.define_file = Some((define_file, sha256));
+    }
+    if let Some(defines) = args.values_of("define") {
+        for define in defines {
+            let (name, value) = preprocessor::parse_define(define)?;
+            options.defines.insert(name, value);
+        }
+    }
+
+    Ok(options)
+}
+
+/// Load the cache of parsed files, kept on disk when a cache directory is provided.
+fn load_cache(args: &clap::ArgMatches) -> cache::Cache {
+    match args.value_of("cache-dir") {
+        Some(cache_directory) => cache::Cache::new(PathBuf::from(
+            shellexpand::tilde(cache_directory).into_owned(),
+        )),
+        None => cache::Cache::default(),
+    }
+}
+
+/// Load the typing speed of string and key commands.
+fn load_characters_per_second(args: &clap::ArgMatches) -> Result<u64> {
+    match args.value_of("chars-per-second") {
+        Some(speed) => speed.parse::<u64>().map_err(|_| {
+            anyhow!(
+                "Invalid typing speed '{}', expected a number of characters per second.",
+                speed
+            )
+        }),
+        None => Ok(timing::CHARACTERS_PER_SECOND),
+    }
 }
 
 /// Initializes the application logger.
-fn initialize_logger() {
-    // TODO: Support `--verbosity`.
-    let env = env_logger::Env::default();
+/// Each `--verbose` occurrence increases the log level, otherwise `RUST_LOG` is used and
+/// defaults to warnings.
+fn initialize_logger(verbosity: u64) {
+    let env = env_logger::Env::default().default_filter_or("warn");
 
-    return env_logger::Builder::from_env(env)
-        .target(env_logger::Target::Stdout)
-        .init();
+    let mut builder = env_logger::Builder::from_env(env);
+    match verbosity {
+        0 => {}
+        1 => {
+            builder.filter_level(log::LevelFilter::Info);
+        }
+        2 => {
+            builder.filter_level(log::LevelFilter::Debug);
+        }
+        _ => {
+            builder.filter_level(log::LevelFilter::Trace);
+        }
+    }
+    builder.target(env_logger::Target::Stdout).init();
 }
 
 /// Run application according to command line interface arguments.
@@ -85,9 +491,17 @@ fn run(args: clap::ArgMatches) -> Result<()> {
         return command_completions(args);
     } else if args.subcommand_matches("build").is_some() {
         return command_build(args);
+    } else if args.subcommand_matches("convert").is_some() {
+        return command_convert(args);
+    } else if args.subcommand_matches("lint").is_some() {
+        return command_lint(args);
+    } else if args.subcommand_matches("simulate").is_some() {
+        return command_simulate(args);
+    } else if args.subcommand_matches("test").is_some() {
+        return command_test(args);
     }
 
-    return Err(anyhow!("No supported command provided."));
+    Err(anyhow!("No supported command provided."))
 }
 
 /// Command to output completions of a specific type to STDOUT.
@@ -149,12 +563,48 @@ fn command_build(args: clap::ArgMatches) -> Result<()> {
 
     let current_directory = &std::env::current_dir().unwrap();
 
+    let options = CompileOptions {
+        optimize: args_build.is_present("optimize"),
+        indentation: args_build.value_of("indent").unwrap().parse()?,
+        target: args_build.value_of("target").unwrap().parse()?,
+        device: args_build
+            .value_of("device")
+            .map(|device| device.parse())
+            .transpose()?,
+        ..load_compile_options(args_build)?
+    };
+
+    let metadata = load_metadata(args_build, current_directory, input)?;
+
     // Build.
     println!("Build MallardScript.");
     println!("  Current Directory: '{}'", current_directory.display());
     println!("  Input: '{}'", input);
     println!("  Output: '{}'", output);
 
+    // Analyze variables before anything is written, so a failed analysis leaves no output.
+    // Sources that can't be loaded are reported when they're compiled.
+    let mut cache = load_cache(args_build);
+    if !args_build.is_present("no-analyze") {
+        let diagnostics =
+            analysis::analyze_variables(current_directory.clone(), input, &options, &mut cache)
+                .unwrap_or_default();
+        for diagnostic in &diagnostics {
+            match diagnostic.severity {
+                Severity::Warning => log::warn!("{}", diagnostic),
+                Severity::Error => log::error!("{}", diagnostic),
+            }
+        }
+
+        let errors = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count();
+        if errors > 0 {
+            return Err(anyhow!("Variable analysis found {} error(s).", errors));
+        }
+    }
+
     // Setup.
     let output_file = std::fs::File::create(output_file_path).context(format!(
         "Failed to create output file '{}'.",
@@ -162,17 +612,28 @@ fn command_build(args: clap::ArgMatches) -> Result<()> {
     ))?;
 
     // Compile.
+    let mut state = CompileState {
+        cache,
+        ..CompileState::default()
+    };
     compile(
         current_directory.clone(),
         input,
         &output_file,
         0,
-        &mut HashMap::new(),
+        &options,
+        &mut state,
     )
     .context(format!(
         "Failed to compile to output file '{}'.",
         output_file_path.display()
     ))?;
+    if args_build.is_present("cache-dir") {
+        println!(
+            "  Cache: {} hit(s), {} miss(es).",
+            state.cache.hits, state.cache.misses
+        );
+    }
 
     // Validate DuckyScript.
     let output_contents = std::fs::read_to_string(output_file_path).with_context(|| {
@@ -181,13 +642,395 @@ fn command_build(args: clap::ArgMatches) -> Result<()> {
             output_file_path.display()
         )
     })?;
-    duckyscript::parser::parse_document(output_contents).with_context(|| {
+    // Flipper Zero BadUSB key commands such as `CTRL-ALT DELETE` aren't DuckyScript, its output is
+    // validated line by line instead.
+    let validated = if options.target == Target::Flipper {
+        flipper::validate(&output_contents)
+    } else {
+        duckyscript::parser::parse_document(replace_define_references(
+            &replace_arithmetic_operators(&normalize_indentation(&output_contents)),
+        ))
+        .map(|_| ())
+    };
+    validated.with_context(|| {
         format!(
             "Unable to validate compiled output '{}'.",
             output_file_path.display(),
         )
     })?;
 
+    // Minify once validated, blocks without indentation can't be validated.
+    if args_build.is_present("minify") {
+        let output_minified = minifier::minify(&output_contents);
+        std::fs::write(output_file_path, &output_minified).with_context(|| {
+            format!(
+                "Unable to write minified output '{}'.",
+                output_file_path.display()
+            )
+        })?;
+        println!(
+            "  Minified: {} bytes to {} bytes, saved {} bytes.",
+            output_contents.len(),
+            output_minified.len(),
+            output_contents.len() - output_minified.len()
+        );
+    }
+
+    // Write the metadata header once minified, minifying removes comments.
+    if !metadata.is_empty() {
+        let output_contents = std::fs::read_to_string(output_file_path).with_context(|| {
+            format!(
+                "Unable load compiled output '{}'.",
+                output_file_path.display()
+            )
+        })?;
+        std::fs::write(
+            output_file_path,
+            format!("{}{}", metadata.header(), output_contents),
+        )
+        .with_context(|| {
+            format!(
+                "Unable to write metadata header to output '{}'.",
+                output_file_path.display()
+            )
+        })?;
+        println!(
+            "  Metadata: '{}' version '{}'.",
+            metadata.get("title").unwrap_or_default(),
+            metadata.get("version").unwrap_or_default()
+        );
+    }
+
+    // Write the manifest once the output is final, build details are hashed when requested.
+    if !metadata.is_empty() || args_build.is_present("manifest") {
+        let mut manifest = serde_json::json!({ "artifact": "index.ducky" });
+        if !metadata.is_empty() {
+            manifest["metadata"] = metadata.to_json();
+        }
+        if args_build.is_present("manifest") {
+            let artifact = std::fs::read(output_file_path).with_context(|| {
+                format!(
+                    "Unable load compiled output '{}'.",
+                    output_file_path.display()
+                )
+            })?;
+            let build =
+                manifest::Build::new(current_directory, &state.sources, &options, &artifact);
+            if let (Some(manifest), serde_json::Value::Object(build)) =
+                (manifest.as_object_mut(), build.to_json())
+            {
+                manifest.extend(build);
+            }
+            println!("  Sources: {} file(s) hashed.", state.sources.len());
+        }
+
+        let manifest_path = output_file_path.with_file_name("manifest.json");
+        std::fs::write(
+            &manifest_path,
+            format!("{}\n", serde_json::to_string_pretty(&manifest)?),
+        )
+        .with_context(|| format!("Unable to write manifest '{}'.", manifest_path.display()))?;
+        println!("  Manifest: '{}'", manifest_path.display());
+    }
+
+    // Report estimated execution time.
+    if args_build.value_of("report") == Some("timing") {
+        let characters_per_second = load_characters_per_second(args_build)?;
+        let report = timing::estimate(
+            current_directory.clone(),
+            input,
+            &options,
+            characters_per_second,
+            &mut state.cache,
+        )
+        .context("Failed to estimate execution time.")?;
+        print_timing_report(&report, characters_per_second);
+    }
+    state.cache.prune();
+
+    println!("Done.");
+
+    Ok(())
+}
+
+/// Print the estimated execution time of each file and the statements running for an unknown time.
+fn print_timing_report(report: &timing::Report, characters_per_second: u64) {
+    let bound = |unknown: bool| if unknown { "at least " } else { "" };
+
+    println!(
+        "  Timing at {} characters per second:",
+        characters_per_second
+    );
+    for file in &report.files {
+        println!(
+            "    '{}': {}{} ms",
+            file.input_path,
+            bound(file.unknown),
+            file.milliseconds
+        );
+    }
+    for (location, reason) in &report.unknowns {
+        println!("    Unknown at '{}': {}", location, reason);
+    }
+    println!(
+        "    Total: {}{} ms",
+        bound(report.is_unknown()),
+        report.milliseconds()
+    );
+}
+
+/// Replace arithmetic operators with comparisons for validation.
+/// The DuckyScript grammar doesn't support arithmetic operators, they share the same binary
+/// operator structure as comparisons so the rest of the expression can still be validated.
+fn replace_arithmetic_operators(contents: &str) -> String {
+    [" + ", " - ", " * ", " / ", " % "]
+        .iter()
+        .fold(String::from(contents), |contents, operator| {
+            contents.replace(operator, " == ")
+        })
+}
+
+/// Replace references to defines written as `DEFINE #NAME value` with their value for validation.
+/// The DuckyScript grammar doesn't support `#` in define names, the device substitutes them.
+fn replace_define_references(contents: &str) -> String {
+    // Longer names first, so that a name isn't replaced within a longer one.
+    let mut defines = contents
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix("DEFINE #"))
+        .filter_map(|define| define.split_once(' '))
+        .collect::<Vec<(&str, &str)>>();
+    defines.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    contents
+        .lines()
+        .map(|line| match line.trim_start().strip_prefix("DEFINE #") {
+            Some(_) => line.replacen("DEFINE #", "DEFINE ", 1),
+            None => defines
+                .iter()
+                .fold(String::from(line), |line, (name, value)| {
+                    line.replace(&format!("#{}", name), value)
+                }),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Lint MallardScript input and its imports.
+fn command_lint(args: clap::ArgMatches) -> Result<()> {
+    // Parse arguments.
+    let args_lint = args.subcommand_matches("lint").unwrap();
+    let input = args_lint.value_of("input").unwrap();
+    let current_directory = &std::env::current_dir().unwrap();
+    let options = load_compile_options(args_lint)?;
+
+    // Lint.
+    println!("Lint MallardScript.");
+    println!("  Current Directory: '{}'", current_directory.display());
+    println!("  Input: '{}'", input);
+
+    let mut cache = load_cache(args_lint);
+    let diagnostics = lint::lint(current_directory.clone(), input, &options, &mut cache)
+        .context(format!("Failed to lint input '{}'.", input))?;
+    cache.prune();
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow!("Lint found {} error(s).", errors));
+    }
+
+    println!("Done.");
+
+    Ok(())
+}
+
+/// Simulate MallardScript input and print its keystroke timeline.
+/// The JSON format prints the timeline alone so it can be parsed.
+fn command_simulate(args: clap::ArgMatches) -> Result<()> {
+    // Parse arguments.
+    let args_simulate = args.subcommand_matches("simulate").unwrap();
+    let input = args_simulate.value_of("input").unwrap();
+    let current_directory = &std::env::current_dir().unwrap();
+    let options = load_compile_options(args_simulate)?;
+    let characters_per_second = load_characters_per_second(args_simulate)?;
+    let json = args_simulate.value_of("format") == Some("json");
+    let scenario = match args_simulate.value_of("scenario") {
+        Some(scenario) => {
+            Scenario::load(&PathBuf::from(shellexpand::tilde(scenario).into_owned()))?
+        }
+        None => Scenario::default(),
+    };
+
+    // Simulate.
+    if !json {
+        println!("Simulate MallardScript.");
+        println!("  Current Directory: '{}'", current_directory.display());
+        println!("  Input: '{}'", input);
+        if let Some(scenario) = args_simulate.value_of("scenario") {
+            println!("  Scenario: '{}'", scenario);
+        }
+    }
+
+    let mut cache = load_cache(args_simulate);
+    let simulation = simulator::simulate(
+        current_directory.clone(),
+        input,
+        &options,
+        characters_per_second,
+        &scenario,
+        &mut cache,
+    )
+    .context(format!("Failed to simulate input '{}'.", input))?;
+    cache.prune();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&simulation.to_json())?);
+    } else {
+        for event in &simulation.events {
+            println!("  {}", event);
+        }
+        println!("  Duration: {} ms", simulation.duration);
+    }
+
+    // Check expected actions once the timeline is printed.
+    let expectations = scenario
+        .check(&simulation)
+        .context("Simulation doesn't match the scenario.")?;
+    if !json {
+        if scenario.expect.is_some() {
+            println!("  Expectations: {} action(s) matched.", expectations);
+        }
+        println!("Done.");
+    }
+
+    Ok(())
+}
+
+/// Convert DuckyScript input into MallardScript, writing `index.ducky` and extracted modules.
+fn command_convert(args: clap::ArgMatches) -> Result<()> {
+    // Parse arguments.
+    let args_convert = args.subcommand_matches("convert").unwrap();
+    let input = args_convert.value_of("input").unwrap();
+    let output = args_convert.value_of("output").unwrap();
+    let output_directory = PathBuf::from(shellexpand::tilde(output).into_owned());
+    let current_directory = &std::env::current_dir().unwrap();
+    let module_lines_min = if args_convert.is_present("extract") {
+        Some(match args_convert.value_of("min-lines") {
+            Some(lines) => lines
+                .parse::<usize>()
+                .ok()
+                .filter(|lines| *lines > 0)
+                .ok_or_else(|| anyhow!("Invalid minimum lines '{}', expected a number.", lines))?,
+            None => converter::MODULE_LINES_MIN,
+        })
+    } else {
+        None
+    };
+
+    // Convert.
+    println!("Convert DuckyScript.");
+    println!("  Current Directory: '{}'", current_directory.display());
+    println!("  Input: '{}'", input);
+    println!("  Output: '{}'", output);
+
+    let input_contents = std::fs::read_to_string(shellexpand::tilde(input).as_ref())
+        .with_context(|| format!("Unable to load input file '{}'.", input))?;
+    let conversion = converter::convert(&normalize_indentation(&input_contents), module_lines_min)
+        .context(format!("Failed to convert input '{}'.", input))?;
+
+    // Write.
+    std::fs::create_dir_all(&output_directory).with_context(|| {
+        format!(
+            "Failed to create output directory '{}'.",
+            output_directory.display()
+        )
+    })?;
+    let mut files = vec![(String::from("index.ducky"), &conversion.contents)];
+    files.extend(
+        conversion
+            .modules
+            .iter()
+            .map(|module| (module.name.clone(), &module.contents)),
+    );
+    for (name, contents) in files {
+        let output_file_path = output_directory.join(name);
+        std::fs::write(&output_file_path, contents).with_context(|| {
+            format!(
+                "Failed to write output file '{}'.",
+                output_file_path.display()
+            )
+        })?;
+    }
+
+    for module in &conversion.modules {
+        println!(
+            "  Extracted: '{}' imported {} times.",
+            module.name, module.imports
+        );
+    }
+    println!("Done.");
+
+    Ok(())
+}
+
+/// Test MallardScript fixtures, printing a diff of each expected file that doesn't match.
+fn command_test(args: clap::ArgMatches) -> Result<()> {
+    // Parse arguments.
+    let args_test = args.subcommand_matches("test").unwrap();
+    let path = args_test.value_of("path").unwrap();
+    let update = args_test.is_present("update");
+    let current_directory = &std::env::current_dir().unwrap();
+
+    // Test.
+    println!("Test MallardScript.");
+    println!("  Current Directory: '{}'", current_directory.display());
+    println!("  Path: '{}'", path);
+
+    let fixtures = fixture::discover(&PathBuf::from(shellexpand::tilde(path).into_owned()))?;
+    let mut cache = load_cache(args_test);
+    let mut failed = 0;
+    for fixture in &fixtures {
+        match fixture.run(update, &mut cache) {
+            Ok(Outcome::Passed) => println!("  ok '{}'", fixture.input.display()),
+            Ok(Outcome::Updated(paths)) => {
+                for path in paths {
+                    println!("  updated '{}'", path.display());
+                }
+            }
+            Ok(Outcome::Failed(failures)) => {
+                failed += 1;
+                println!("  FAILED '{}'", fixture.input.display());
+                for (path, diff) in failures {
+                    println!("    '{}' doesn't match:", path.display());
+                    for line in diff.lines() {
+                        println!("      {}", line);
+                    }
+                }
+            }
+            Err(error) => {
+                failed += 1;
+                println!("  FAILED '{}'", fixture.input.display());
+                for line in format!("{:?}", error).lines() {
+                    println!("    {}", line);
+                }
+            }
+        }
+    }
+
+    println!(
+        "  Fixtures: {} passed, {} failed.",
+        fixtures.len() - failed,
+        failed
+    );
+    cache.prune();
+    if failed > 0 {
+        return Err(anyhow!("{} fixture(s) failed.", failed));
+    }
     println!("Done.");
 
     Ok(())
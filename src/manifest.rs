@@ -0,0 +1,101 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use CompileOptions;
+
+/// Source file of a build with the hash of its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    /// Path relative to the current directory, or absolute when it's outside of it.
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Inputs and output of a build, proving which sources produced an artifact.
+/// Nothing depends on when or where the build ran, identical inputs produce an identical manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Build {
+    pub compiler_version: String,
+    pub target: String,
+    pub device: Option<String>,
+    pub defines: HashMap<String, String>,
+    /// File the defines were loaded from.
+    pub define_file: Option<Source>,
+    /// Every file of the import graph, in the order they're first compiled.
+    pub sources: Vec<Source>,
+    pub artifact_sha256: String,
+}
+
+impl Build {
+    /// Describe the sources compiled into an artifact, with the hash of the contents they were
+    /// compiled from, and the options they were compiled with.
+    pub fn new(
+        current_directory: &Path,
+        sources: &[(PathBuf, String)],
+        options: &CompileOptions,
+        artifact: &[u8],
+    ) -> Build {
+        // Sources are canonical, so the current directory is too before they're made relative.
+        let current_directory = std::fs::canonicalize(current_directory)
+            .unwrap_or_else(|_| current_directory.to_path_buf());
+        let source = |(path, sha256): &(PathBuf, String)| Source {
+            path: relative_path(&current_directory, path),
+            sha256: sha256.clone(),
+        };
+
+        Build {
+            compiler_version: String::from(env!("CARGO_PKG_VERSION")),
+            target: String::from(options.target.name()),
+            device: options.device.map(|device| String::from(device.name())),
+            defines: options.defines.clone(),
+            define_file: options.define_file.as_ref().map(|(path, sha256)| {
+                let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                source(&(path, sha256.clone()))
+            }),
+            sources: sources.iter().map(source).collect(),
+            artifact_sha256: sha256(artifact),
+        }
+    }
+
+    /// JSON object of the build, its keys are sorted.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "compiler": {
+                "name": "mallardscript",
+                "version": self.compiler_version,
+            },
+            "target": self.target,
+            "device": self.device,
+            "defines": self.defines,
+            "define_file": self.define_file.as_ref().map(|source| serde_json::json!({
+                "path": source.path,
+                "sha256": source.sha256,
+            })),
+            "sources": self
+                .sources
+                .iter()
+                .map(|source| serde_json::json!({
+                    "path": source.path,
+                    "sha256": source.sha256,
+                }))
+                .collect::<Vec<serde_json::Value>>(),
+            "artifact_sha256": self.artifact_sha256,
+        })
+    }
+}
+
+/// Path relative to a directory, or absolute when it's outside of it.
+fn relative_path(directory: &Path, path: &Path) -> String {
+    path.strip_prefix(directory)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// SHA-256 hash of contents, as lowercase hexadecimal.
+pub fn sha256(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
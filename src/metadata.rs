@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Context, Result};
+use preprocessor::Preprocessed;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Prefix of the `REM` lines declaring metadata at the top of the entry file.
+static FRONT_MATTER_PREFIX: &str = "meta:";
+
+/// Metadata fields in the order of the header, with their header name and whether they're required.
+static FIELDS: &[(&str, &str, bool)] = &[
+    ("title", "Title", true),
+    ("author", "Author", true),
+    ("description", "Description", true),
+    ("target", "Target", true),
+    ("version", "Version", true),
+    ("category", "Category", false),
+];
+
+/// Payload metadata written as the standard `REM Title:` header of Hak5 payload libraries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    values: HashMap<String, String>,
+}
+
+impl Metadata {
+    /// Load metadata from a key/value file.
+    /// Each line is a `key=value` pair, blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<Metadata> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to load metadata file '{}'.", path.display()))?;
+
+        let mut metadata = Metadata::default();
+        for (line_index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            metadata.insert(line, &format!("{}:{}", path.display(), line_index + 1))?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Parse the metadata front matter of an entry file, `REM meta: key=value` lines before its
+    /// first statement other than a comment.
+    pub fn parse_front_matter(input_path: &str, contents: &str) -> Result<Metadata> {
+        let mut metadata = Metadata::default();
+        let mut front_matter = true;
+
+        for (line_index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            let location = format!("{}:{}", input_path, line_index + 1);
+            let comment = line
+                .strip_prefix("REM ")
+                .or_else(|| (line == "REM").then_some(""));
+            match comment.and_then(front_matter_value) {
+                Some(_) if !front_matter => {
+                    return Err(anyhow!(
+                        "Metadata at '{}' must be declared before the first statement.",
+                        location
+                    ));
+                }
+                Some(value) => metadata.insert(value, &location)?,
+                None if line.is_empty() || comment.is_some() => {}
+                None => front_matter = false,
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Insert a `key=value` field, declared at a location.
+    fn insert(&mut self, field: &str, location: &str) -> Result<()> {
+        let (key, value) = field
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid metadata '{}' at '{}', expected 'key=value'.",
+                    field,
+                    location
+                )
+            })?;
+        if !FIELDS.iter().any(|(name, _, _)| *name == key) {
+            return Err(anyhow!(
+                "Unknown metadata '{}' at '{}', expected one of '{}'.",
+                key,
+                location,
+                FIELDS
+                    .iter()
+                    .map(|(name, _, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join("', '")
+            ));
+        }
+        if value.is_empty() {
+            return Err(anyhow!("Metadata '{}' at '{}' is empty.", key, location));
+        }
+
+        self.values.insert(String::from(key), String::from(value));
+        Ok(())
+    }
+
+    /// Override fields with the fields of other metadata.
+    pub fn extend(&mut self, metadata: Metadata) {
+        self.values.extend(metadata.values);
+    }
+
+    /// Is no field declared.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Value of a field, such as `title`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Require every required field to be declared.
+    pub fn validate(&self) -> Result<()> {
+        let missing = FIELDS
+            .iter()
+            .filter(|(name, _, required)| *required && !self.values.contains_key(*name))
+            .map(|(name, _, _)| *name)
+            .collect::<Vec<&str>>();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Missing required metadata '{}', declare it with 'REM meta: key=value'.",
+                missing.join("', '")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Header of the metadata, a `REM Name: value` line for each declared field.
+    pub fn header(&self) -> String {
+        FIELDS
+            .iter()
+            .filter_map(|(name, header_name, _)| {
+                self.values
+                    .get(*name)
+                    .map(|value| format!("REM {}: {}\n", header_name, value))
+            })
+            .collect()
+    }
+
+    /// JSON object of the declared fields.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.values
+                .iter()
+                .map(|(key, value)| (key.clone(), serde_json::Value::String(value.clone())))
+                .collect(),
+        )
+    }
+}
+
+/// Is a `REM` value a metadata declaration, such as `meta: title=Open URL`.
+pub fn is_front_matter(value: &str) -> bool {
+    front_matter_value(value).is_some()
+}
+
+/// Source line number of the first metadata declaration of preprocessed contents.
+pub fn find_front_matter(preprocessed: &Preprocessed) -> Option<usize> {
+    preprocessed
+        .contents
+        .lines()
+        .zip(&preprocessed.line_numbers)
+        .find_map(|(line, line_number)| {
+            line.trim()
+                .strip_prefix("REM ")
+                .filter(|value| is_front_matter(value))
+                .map(|_| *line_number)
+        })
+}
+
+/// Field of a metadata declaration `REM` value.
+fn front_matter_value(value: &str) -> Option<&str> {
+    value
+        .trim()
+        .strip_prefix(FRONT_MATTER_PREFIX)
+        .map(str::trim)
+}
@@ -0,0 +1,60 @@
+use expression;
+
+/// Minify compiled DuckyScript for devices with tight payload size limits.
+/// `REM` lines, indentation and blank lines are removed and consecutive `STRING` lines are merged,
+/// followed by a `STRINGLN` when there's one.
+/// Strings aren't merged when a `DEFAULTDELAY` is set since the delay runs after each command, or
+/// when they type an expression.
+pub fn minify(contents: &str) -> String {
+    let lines = contents
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.trim_end().is_empty())
+        .filter(|line| !is_command(line, "REM"))
+        .collect::<Vec<&str>>();
+    let merge = !lines.iter().any(|line| {
+        ["DEFAULTDELAY", "DEFAULT_DELAY"]
+            .iter()
+            .any(|command| is_command(line, command))
+            && line
+                .split_whitespace()
+                .nth(1)
+                .is_some_and(|delay| delay != "0")
+    });
+
+    let mut lines_minified: Vec<String> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let string = string_value(line, "STRING");
+        let stringln = string_value(line, "STRINGLN");
+        let previous = lines_minified
+            .last()
+            .and_then(|previous| string_value(previous, "STRING"))
+            .map(String::from);
+
+        match (merge, previous, string.or(stringln)) {
+            (true, Some(previous), Some(value)) => {
+                let command = if stringln.is_some() {
+                    "STRINGLN"
+                } else {
+                    "STRING"
+                };
+                *lines_minified.last_mut().unwrap() = format!("{} {}{}", command, previous, value);
+            }
+            _ => lines_minified.push(String::from(line)),
+        }
+    }
+
+    lines_minified.join("\n")
+}
+
+/// Is the line the provided command.
+fn is_command(line: &str, command: &str) -> bool {
+    line.split_whitespace().next() == Some(command)
+}
+
+/// Typed value of a string command that can be merged, without expressions.
+fn string_value<'a>(line: &'a str, command: &str) -> Option<&'a str> {
+    line.strip_prefix(command)
+        .and_then(|value| value.strip_prefix(' '))
+        .filter(|value| expression::parse(value).is_err())
+}
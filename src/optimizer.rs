@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use expression;
+use pest_duckyscript::mallardscript::ast::{Statement, StatementBlockIf, StatementBlockWhile};
+
+/// Optimize MallardScript statements.
+/// Constant `IF` and `WHILE` conditions are folded, unreachable branches are removed and blocks
+/// that have no statements are dropped.
+pub fn optimize(input_path: &str, statements: Vec<Statement>) -> Result<Vec<Statement>> {
+    let mut statements_optimized = Vec::with_capacity(statements.len());
+
+    for statement in statements {
+        match statement {
+            Statement::BlockIf(block) => {
+                let condition = fold_condition(input_path, &block.expression)?;
+                let statements_true = optimize(input_path, block.statements_true)?;
+                let statements_false = optimize(input_path, block.statements_false)?;
+
+                match expression::evaluate(&condition).and_then(|value| value.as_boolean()) {
+                    Some(true) => {
+                        log::info!(
+                            "Removed 'IF {}' in '{}', condition is always TRUE.",
+                            block.expression,
+                            input_path
+                        );
+                        statements_optimized.extend(statements_true);
+                    }
+                    Some(false) => {
+                        log::info!(
+                            "Removed 'IF {}' in '{}', condition is always FALSE.",
+                            block.expression,
+                            input_path
+                        );
+                        statements_optimized.extend(statements_false);
+                    }
+                    None if statements_true.is_empty() && statements_false.is_empty() => {
+                        log::info!(
+                            "Removed 'IF {}' in '{}', block is empty.",
+                            block.expression,
+                            input_path
+                        );
+                    }
+                    None => {
+                        statements_optimized.push(Statement::BlockIf(StatementBlockIf {
+                            expression: render_condition(&block.expression, &condition),
+                            statements_true,
+                            statements_false,
+                        }));
+                    }
+                }
+            }
+            Statement::BlockWhile(block) => {
+                let condition = fold_condition(input_path, &block.expression)?;
+                let statements = optimize(input_path, block.statements)?;
+
+                // Empty loops with runtime conditions are kept since they wait on the condition.
+                if expression::evaluate(&condition).and_then(|value| value.as_boolean())
+                    == Some(false)
+                {
+                    log::info!(
+                        "Removed 'WHILE {}' in '{}', condition is always FALSE.",
+                        block.expression,
+                        input_path
+                    );
+                } else {
+                    statements_optimized.push(Statement::BlockWhile(StatementBlockWhile {
+                        expression: render_condition(&block.expression, &condition),
+                        statements,
+                    }));
+                }
+            }
+            statement => statements_optimized.push(statement),
+        }
+    }
+
+    Ok(statements_optimized)
+}
+
+/// Parse and fold a block condition.
+fn fold_condition(input_path: &str, condition: &str) -> Result<expression::Expression> {
+    let expression = expression::parse(condition)
+        .with_context(|| format!("Unable to optimize condition in '{}'.", input_path))?;
+
+    Ok(expression::fold(&expression))
+}
+
+/// Render a folded condition, keeping the original condition when nothing was folded.
+fn render_condition(condition: &str, condition_folded: &expression::Expression) -> String {
+    if expression::parse(condition).ok().as_ref() == Some(condition_folded) {
+        return String::from(condition);
+    }
+
+    let condition_rendered = condition_folded.to_string();
+    log::info!(
+        "Folded condition '{}' into '{}'.",
+        condition,
+        condition_rendered
+    );
+
+    condition_rendered
+}
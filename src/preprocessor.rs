@@ -0,0 +1,1378 @@
+use anyhow::{anyhow, Context, Result};
+use manifest;
+use pest_duckyscript::mallardscript::ast::Statement;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Parse a compile time define provided as `NAME=value`.
+pub fn parse_define(define: &str) -> Result<(String, String)> {
+    let (name, value) = define
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid define '{}', expected 'NAME=value'.", define))?;
+    let name = name.trim();
+
+    if !is_define_name(name) {
+        return Err(anyhow!("Invalid define name '{}'.", name));
+    }
+
+    Ok((String::from(name), String::from(value)))
+}
+
+/// Load compile time defines from a key/value file.
+/// Each line is a `NAME=value` pair, blank lines and lines starting with `#` are ignored.
+/// Returns the defines with the SHA-256 hash of the file's contents.
+pub fn load_define_file(define_file_path: &Path) -> Result<(HashMap<String, String>, String)> {
+    let define_file_contents = std::fs::read_to_string(define_file_path).with_context(|| {
+        format!(
+            "Unable to load define file '{}'.",
+            define_file_path.display()
+        )
+    })?;
+
+    let mut defines = HashMap::new();
+    for (line_index, line) in define_file_contents.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = parse_define(line).with_context(|| {
+            format!(
+                "Unable to parse define file '{}' at line {}.",
+                define_file_path.display(),
+                line_index + 1
+            )
+        })?;
+        defines.insert(name, value);
+    }
+
+    Ok((defines, manifest::sha256(define_file_contents.as_bytes())))
+}
+
+/// State of a compile time conditional `#IF` block.
+struct Conditional {
+    /// Is the enclosing source being emitted.
+    parent_active: bool,
+    /// Has any branch of the conditional been taken.
+    taken: bool,
+    /// Is the current branch of the conditional being emitted.
+    active: bool,
+    /// Has the `#ELSE` branch been reached.
+    otherwise: bool,
+    /// Line number of the `#IF` directive.
+    line_number: usize,
+}
+
+/// User defined macro expanded inline at each call site.
+#[derive(Clone)]
+struct Macro {
+    /// Parameter names without the leading `$`.
+    parameters: Vec<String>,
+    /// Variables declared in the macro body, renamed at each expansion.
+    variables: Vec<String>,
+    /// Body lines with their line numbers, without the body indentation.
+    body: Vec<(usize, String)>,
+    /// Line number of the `MACRO` definition.
+    line_number: usize,
+}
+
+/// Preprocessor state for a single MallardScript source file.
+struct Preprocessor<'a> {
+    input_path: &'a str,
+    /// Compile time defines, including the import arguments.
+    defines: HashMap<String, String>,
+    /// Parameters declared with `#PARAMETER`.
+    parameters: Vec<String>,
+    /// Names the source defines with `DEFINE`, substituted by the device instead.
+    defines_source: Vec<String>,
+    /// Names the source declares with `MACRO`, only calls to these are expanded.
+    macros_source: Vec<String>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// Compile time loop iterations unrolled so far, including nested loops.
+    iterations: u64,
+    /// Line number of the outermost macro call being expanded.
+    call_line_number: Option<usize>,
+    output_contents: String,
+    line_numbers: Vec<usize>,
+}
+
+/// MallardScript source after it's preprocessed.
+#[derive(Debug, Default)]
+pub struct Preprocessed {
+    pub contents: String,
+    /// Source line number of each preprocessed line, expanded macros point at their call site.
+    pub line_numbers: Vec<usize>,
+}
+
+/// Preprocess MallardScript source before it's parsed.
+/// Compile time conditionals (`#IF`, `#ELSE_IF`, `#ELSE`, `#END_IF`) select which lines are kept,
+/// macros (`MACRO`, `END_MACRO`) are expanded at the calls to the names they declare, compile time
+/// loops (`FOR`, `END_FOR`) are unrolled, runtime loops (`LOOP`, `END_LOOP`) are rewritten as
+/// `WHILE` loops and compile time defines referenced as `#NAME` are substituted.
+/// Defines that must be provided are declared with `#REQUIRE NAME`, other references to defines
+/// that weren't provided are left as they are.
+/// Import arguments are substituted like defines and must be declared with `#PARAMETER NAME`.
+/// Removed lines are kept as blank lines so parse errors still point at the original lines.
+/// The control characters and `DEFINE` prefix the preprocessor encodes lines with are reserved and
+/// can't appear in the source or in define values.
+pub fn preprocess(
+    input_path: &str,
+    input_contents: &str,
+    defines: &HashMap<String, String>,
+    arguments: &HashMap<String, String>,
+) -> Result<Preprocessed> {
+    let lines = input_contents
+        .lines()
+        .enumerate()
+        .map(|(line_index, line)| (line_index + 1, String::from(line)))
+        .collect::<Vec<(usize, String)>>();
+    if let Some((line_number, line)) = lines
+        .iter()
+        .find(|(_, line)| line.contains(is_reserved_character))
+    {
+        return Err(anyhow!(
+            "Reserved character 'U+{:04X}' at '{}:{}'.",
+            line.chars()
+                .find(|character| is_reserved_character(*character))
+                .unwrap() as u32,
+            input_path,
+            line_number
+        ));
+    }
+
+    let mut defines = defines.clone();
+    defines.extend(arguments.clone());
+    let mut defines_reserved = defines
+        .iter()
+        .filter(|(_, value)| value.contains(is_reserved_character))
+        .map(|(name, _)| name)
+        .collect::<Vec<&String>>();
+    defines_reserved.sort();
+    if let Some(name) = defines_reserved.first() {
+        return Err(anyhow!(
+            "Define '{}' used by '{}' contains a reserved character.",
+            name,
+            input_path
+        ));
+    }
+
+    // Names defined with `DEFINE` are DuckyScript defines, which the device substitutes.
+    let defines_source = lines
+        .iter()
+        .filter_map(|(line_number, line)| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("DEFINE"), Some(name)) => Some((line_number, name.trim_start_matches('#'))),
+                _ => None,
+            }
+        })
+        .map(|(line_number, name)| {
+            if name.starts_with(DEFINE_REFERENCE_PREFIX) {
+                return Err(anyhow!(
+                    "Define '{}' at '{}:{}' uses the reserved prefix '{}'.",
+                    name,
+                    input_path,
+                    line_number,
+                    DEFINE_REFERENCE_PREFIX
+                ));
+            }
+            Ok(String::from(name))
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let macros_source = lines
+        .iter()
+        .filter_map(|(_, line)| line.trim_start().strip_prefix("MACRO "))
+        .filter_map(|declaration| declaration.split('(').next())
+        .map(|name| String::from(name.trim()))
+        .collect::<Vec<String>>();
+
+    let mut preprocessor = Preprocessor {
+        input_path,
+        defines,
+        parameters: vec![],
+        defines_source,
+        macros_source,
+        macros: HashMap::new(),
+        expansions: 0,
+        iterations: 0,
+        call_line_number: None,
+        output_contents: String::with_capacity(input_contents.len()),
+        line_numbers: vec![],
+    };
+    preprocessor.process(&lines, "", &mut vec![])?;
+
+    // Validate import arguments were declared as parameters.
+    let mut arguments_undeclared = arguments
+        .keys()
+        .filter(|name| !preprocessor.parameters.contains(name))
+        .collect::<Vec<&String>>();
+    arguments_undeclared.sort();
+    if let Some(name) = arguments_undeclared.first() {
+        return Err(anyhow!(
+            "Unknown import argument '{}', '{}' doesn't declare it with '#PARAMETER'.",
+            name,
+            input_path
+        ));
+    }
+
+    Ok(Preprocessed {
+        contents: preprocessor.output_contents,
+        line_numbers: preprocessor.line_numbers,
+    })
+}
+
+impl<'a> Preprocessor<'a> {
+    /// Process source lines, writing them to the output with the provided indentation.
+    fn process(
+        &mut self,
+        lines: &[(usize, String)],
+        indentation: &str,
+        call_stack: &mut Vec<String>,
+    ) -> Result<()> {
+        let input_path = self.input_path;
+        let mut conditionals: Vec<Conditional> = vec![];
+        let mut lines = lines.iter();
+
+        while let Some((line_number, line)) = lines.next() {
+            let line_number = *line_number;
+            let line_trimmed = line.trim();
+            let line_indentation = &line[..line.len() - line.trim_start().len()];
+            let active = conditionals
+                .last()
+                .is_none_or(|conditional| conditional.active);
+
+            let (directive, argument) = line_trimmed
+                .split_once(char::is_whitespace)
+                .map_or((line_trimmed, ""), |(directive, argument)| {
+                    (directive, argument.trim())
+                });
+            match directive {
+                "#IF" | "#IF_DEFINED" | "#IF_NOT_DEFINED" => {
+                    let condition = active
+                        && evaluate_condition(
+                            input_path,
+                            line_number,
+                            directive,
+                            argument,
+                            &self.defines,
+                        )?;
+                    log::info!(
+                        "Processing '{}' at '{}:{}' as {}.",
+                        line_trimmed,
+                        input_path,
+                        line_number,
+                        condition
+                    );
+
+                    conditionals.push(Conditional {
+                        parent_active: active,
+                        taken: condition,
+                        active: condition,
+                        otherwise: false,
+                        line_number,
+                    });
+                }
+                "#ELSE_IF" => {
+                    let conditional = conditionals
+                        .last_mut()
+                        .filter(|conditional| !conditional.otherwise)
+                        .ok_or_else(|| {
+                            anyhow!("Unexpected '#ELSE_IF' at '{}:{}'.", input_path, line_number)
+                        })?;
+                    let condition = conditional.parent_active
+                        && !conditional.taken
+                        && evaluate_condition(
+                            input_path,
+                            line_number,
+                            "#IF",
+                            argument,
+                            &self.defines,
+                        )?;
+
+                    conditional.taken |= condition;
+                    conditional.active = condition;
+                }
+                "#ELSE" => {
+                    let conditional = conditionals
+                        .last_mut()
+                        .filter(|conditional| !conditional.otherwise)
+                        .ok_or_else(|| {
+                            anyhow!("Unexpected '#ELSE' at '{}:{}'.", input_path, line_number)
+                        })?;
+
+                    conditional.active = conditional.parent_active && !conditional.taken;
+                    conditional.taken = true;
+                    conditional.otherwise = true;
+                }
+                "#END_IF" => {
+                    conditionals.pop().ok_or_else(|| {
+                        anyhow!("Unexpected '#END_IF' at '{}:{}'.", input_path, line_number)
+                    })?;
+                }
+                _ if !active => {
+                    log::info!(
+                        "Removing '{}' at '{}:{}'.",
+                        line_trimmed,
+                        input_path,
+                        line_number
+                    );
+                }
+                "#PARAMETER" => {
+                    let name = argument.trim_start_matches('#');
+                    if !is_define_name(name) {
+                        return Err(anyhow!(
+                            "Invalid parameter name '{}' at '{}:{}'.",
+                            argument,
+                            input_path,
+                            line_number
+                        ));
+                    }
+                    if !self.defines.contains_key(name) {
+                        return Err(anyhow!(
+                            "Missing parameter '{}' declared at '{}:{}'.",
+                            name,
+                            input_path,
+                            line_number
+                        ));
+                    }
+
+                    self.parameters.push(String::from(name));
+                }
+                "#REQUIRE" => {
+                    let name = argument.trim_start_matches('#');
+                    if !is_define_name(name) {
+                        return Err(anyhow!(
+                            "Invalid define name '{}' at '{}:{}'.",
+                            argument,
+                            input_path,
+                            line_number
+                        ));
+                    }
+                    if !self.defines.contains_key(name) {
+                        return Err(anyhow!(
+                            "Missing required define '#{}' declared at '{}:{}'.",
+                            name,
+                            input_path,
+                            line_number
+                        ));
+                    }
+                }
+                "MACRO" => {
+                    let body =
+                        collect_body(&mut lines, input_path, line_number, "MACRO", "END_MACRO")?;
+                    if let Some((body_line_number, _)) = body
+                        .iter()
+                        .find(|(_, body_line)| body_line.trim_start().starts_with("MACRO "))
+                    {
+                        return Err(anyhow!(
+                            "Unexpected 'MACRO' at '{}:{}', macros can't be defined inside macro '{}' at '{}:{}'.",
+                            input_path,
+                            body_line_number,
+                            argument,
+                            input_path,
+                            line_number
+                        ));
+                    }
+
+                    let line_count = body.len() + 2;
+                    self.define_macro(line_number, argument, body)?;
+
+                    // Keep removed lines so that line numbers are preserved.
+                    for line_offset in 0..line_count {
+                        self.output_contents.push_str(indentation);
+                        self.output_contents.push_str(line_indentation);
+                        self.end_line(line_number + line_offset);
+                    }
+                    continue;
+                }
+                "FOR" => {
+                    let body = remove_indentation(collect_body(
+                        &mut lines,
+                        input_path,
+                        line_number,
+                        "FOR",
+                        "END_FOR",
+                    )?);
+                    let argument =
+                        substitute_defines(argument, &self.defines, &self.defines_source);
+                    let (variable, start, end) = parse_for(input_path, line_number, &argument)?;
+
+                    // Check the iterations before unrolling, so large ranges fail fast.
+                    self.iterations = self
+                        .iterations
+                        .saturating_add(start.abs_diff(end).saturating_add(1));
+                    if self.iterations > FOR_ITERATIONS_MAX {
+                        return Err(anyhow!(
+                            "Unable to unroll 'FOR {}' at '{}:{}', unrolling exceeds {} iterations.",
+                            argument,
+                            input_path,
+                            line_number,
+                            FOR_ITERATIONS_MAX
+                        ));
+                    }
+
+                    // Unroll the loop body for each value of the loop variable.
+                    let loop_indentation = format!("{}{}", indentation, line_indentation);
+                    let output_length = self.output_contents.len();
+                    let values: Vec<u64> = if start <= end {
+                        (start..=end).collect()
+                    } else {
+                        (end..=start).rev().collect()
+                    };
+                    for value in &values {
+                        let mut substitutions: HashMap<&str, String> = HashMap::new();
+                        substitutions.insert(&variable, value.to_string());
+                        let body_unrolled = body
+                            .iter()
+                            .map(|(body_line_number, body_line)| {
+                                (
+                                    *body_line_number,
+                                    substitute_variables(body_line, &substitutions),
+                                )
+                            })
+                            .collect::<Vec<(usize, String)>>();
+
+                        self.process(&body_unrolled, &loop_indentation, call_stack)?;
+                    }
+
+                    let output_unrolled = &self.output_contents[output_length..];
+                    if values.len() > FOR_UNROLL_WARNING_ITERATIONS {
+                        log::warn!(
+                            "Unrolled 'FOR {}' at '{}:{}' into {} iterations, adding {} lines ({} bytes).",
+                            argument,
+                            input_path,
+                            line_number,
+                            values.len(),
+                            output_unrolled.lines().count(),
+                            output_unrolled.len()
+                        );
+                    } else {
+                        log::info!(
+                            "Unrolled 'FOR {}' at '{}:{}' into {} iterations.",
+                            argument,
+                            input_path,
+                            line_number,
+                            values.len()
+                        );
+                    }
+                    continue;
+                }
+                "END_MACRO" | "END_FOR" => {
+                    return Err(anyhow!(
+                        "Unexpected '{}' at '{}:{}'.",
+                        directive,
+                        input_path,
+                        line_number
+                    ));
+                }
+                _ => {
+                    // Other lines shaped like calls are left to the parser.
+                    if let Some((name, arguments)) =
+                        parse_macro_call(line_trimmed).filter(|(name, _)| {
+                            self.macros_source
+                                .iter()
+                                .any(|macro_name| macro_name == name)
+                        })
+                    {
+                        let call_indentation = format!("{}{}", indentation, line_indentation);
+                        self.expand_macro(
+                            line_number,
+                            name,
+                            arguments,
+                            &call_indentation,
+                            call_stack,
+                        )?;
+                        continue;
+                    }
+
+                    let line = substitute_defines(line, &self.defines, &self.defines_source);
+                    self.output_contents.push_str(indentation);
+                    match directive {
+                        "IMPORT" => {
+                            self.output_contents.push_str(&encode_import(
+                                input_path,
+                                line_number,
+                                &line,
+                            )?);
+                        }
+                        "LOOP" => {
+                            // Runtime loops are lowered by the compiler once their counter is named.
+                            let count = line.trim_start()["LOOP".len()..].trim();
+                            self.output_contents.push_str(&format!(
+                                "{}WHILE \"{}{}\"",
+                                line_indentation,
+                                LOOP_MARKER,
+                                encode_value(count)
+                            ));
+                        }
+                        "DEFINE" => {
+                            self.output_contents.push_str(&encode_define(&line));
+                        }
+                        "END_LOOP" => {
+                            self.output_contents
+                                .push_str(&format!("{}END_WHILE", line_indentation));
+                        }
+                        _ => {
+                            self.output_contents.push_str(&encode_expression(&line));
+                        }
+                    }
+                    self.end_line(line_number);
+                    continue;
+                }
+            }
+
+            // Keep removed lines so that line numbers are preserved.
+            self.output_contents.push_str(indentation);
+            self.output_contents.push_str(line_indentation);
+            self.end_line(line_number);
+        }
+
+        if let Some(conditional) = conditionals.last() {
+            return Err(anyhow!(
+                "Missing '#END_IF' for '#IF' at '{}:{}'.",
+                input_path,
+                conditional.line_number
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// End the current output line, recording the source line it came from.
+    fn end_line(&mut self, line_number: usize) {
+        self.output_contents.push('\n');
+        self.line_numbers
+            .push(self.call_line_number.unwrap_or(line_number));
+    }
+
+    /// Define a macro from its `MACRO name($parameter, ...)` signature and body.
+    fn define_macro(
+        &mut self,
+        line_number: usize,
+        signature: &str,
+        body: Vec<(usize, String)>,
+    ) -> Result<()> {
+        let input_path = self.input_path;
+        let (name, parameters) = match signature.split_once('(') {
+            Some((name, parameters)) => (
+                name.trim(),
+                parameters
+                    .trim()
+                    .strip_suffix(')')
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Missing ')' for macro '{}' at '{}:{}'.",
+                            signature,
+                            input_path,
+                            line_number
+                        )
+                    })?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|parameter| !parameter.is_empty())
+                    .collect::<Vec<&str>>(),
+            ),
+            None => (signature, vec![]),
+        };
+
+        if !is_define_name(name) {
+            return Err(anyhow!(
+                "Invalid macro name '{}' at '{}:{}'.",
+                name,
+                input_path,
+                line_number
+            ));
+        }
+        if let Some(existing) = self.macros.get(name) {
+            return Err(anyhow!(
+                "Macro '{}' at '{}:{}' is already defined at '{}:{}'.",
+                name,
+                input_path,
+                line_number,
+                input_path,
+                existing.line_number
+            ));
+        }
+        let parameters = parameters
+            .into_iter()
+            .map(|parameter| match parameter.strip_prefix('$') {
+                Some(parameter) if is_define_name(parameter) => Ok(String::from(parameter)),
+                _ => Err(anyhow!(
+                    "Invalid macro parameter '{}' at '{}:{}', expected '$name'.",
+                    parameter,
+                    input_path,
+                    line_number
+                )),
+            })
+            .collect::<Result<Vec<String>>>()?;
+
+        // Remove the body indentation so it can be indented at each call site.
+        let body = remove_indentation(body);
+
+        // Collect the variables declared by the macro body.
+        let variables = body
+            .iter()
+            .filter_map(|(_, line)| {
+                let declaration = line.trim_start().strip_prefix("VAR")?.trim_start();
+                let declaration = declaration.strip_prefix('$')?;
+                let name_end = declaration
+                    .find(|character: char| {
+                        !(character.is_ascii_alphanumeric() || character == '_')
+                    })
+                    .unwrap_or(declaration.len());
+
+                Some(String::from(&declaration[..name_end]))
+            })
+            .collect::<Vec<String>>();
+
+        log::info!(
+            "Defining macro '{}' at '{}:{}'.",
+            name,
+            input_path,
+            line_number
+        );
+        self.macros.insert(
+            String::from(name),
+            Macro {
+                parameters,
+                variables,
+                body,
+                line_number,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Expand a macro call inline, substituting its arguments and renaming its variables.
+    fn expand_macro(
+        &mut self,
+        line_number: usize,
+        name: &str,
+        arguments: Vec<String>,
+        indentation: &str,
+        call_stack: &mut Vec<String>,
+    ) -> Result<()> {
+        let input_path = self.input_path;
+        let definition = self.macros.get(name).cloned().ok_or_else(|| {
+            anyhow!(
+                "Macro '{}' called at '{}:{}' isn't defined before it.",
+                name,
+                input_path,
+                line_number
+            )
+        })?;
+
+        if call_stack.iter().any(|called| called == name) {
+            return Err(anyhow!(
+                "Recursive macro '{}' called at '{}:{}', defined at '{}:{}'.",
+                name,
+                input_path,
+                line_number,
+                input_path,
+                definition.line_number
+            ));
+        }
+        if arguments.len() != definition.parameters.len() {
+            return Err(anyhow!(
+                "Macro '{}' called at '{}:{}' with {} arguments, expected {} as defined at '{}:{}'.",
+                name,
+                input_path,
+                line_number,
+                arguments.len(),
+                definition.parameters.len(),
+                input_path,
+                definition.line_number
+            ));
+        }
+
+        log::info!(
+            "Expanding macro '{}' at '{}:{}'.",
+            name,
+            input_path,
+            line_number
+        );
+        self.expansions += 1;
+
+        // Substitute parameters and keep variables local to this expansion.
+        let mut substitutions: HashMap<&str, String> = HashMap::new();
+        for variable in &definition.variables {
+            substitutions.insert(
+                variable,
+                format!("${}_{}_{}", variable, name, self.expansions),
+            );
+        }
+        for (parameter, argument) in definition.parameters.iter().zip(arguments) {
+            substitutions.insert(parameter, argument);
+        }
+        let body = definition
+            .body
+            .iter()
+            .map(|(body_line_number, line)| {
+                (
+                    *body_line_number,
+                    substitute_variables(line, &substitutions),
+                )
+            })
+            .collect::<Vec<(usize, String)>>();
+
+        call_stack.push(String::from(name));
+        let call_line_number = self.call_line_number;
+        self.call_line_number = call_line_number.or(Some(line_number));
+        self.process(&body, indentation, call_stack)
+            .with_context(|| {
+                format!(
+                    "Unable to expand macro '{}' called at '{}:{}', defined at '{}:{}'.",
+                    name, input_path, line_number, input_path, definition.line_number
+                )
+            })?;
+        self.call_line_number = call_line_number;
+        call_stack.pop();
+
+        Ok(())
+    }
+}
+
+/// Collect the body of a block directive up to its matching end directive.
+fn collect_body(
+    lines: &mut std::slice::Iter<(usize, String)>,
+    input_path: &str,
+    line_number: usize,
+    directive: &str,
+    directive_end: &str,
+) -> Result<Vec<(usize, String)>> {
+    let mut body: Vec<(usize, String)> = vec![];
+    let mut depth = 0;
+
+    loop {
+        let (body_line_number, body_line) = lines.next().ok_or_else(|| {
+            anyhow!(
+                "Missing '{}' for '{}' at '{}:{}'.",
+                directive_end,
+                directive,
+                input_path,
+                line_number
+            )
+        })?;
+        let body_directive = body_line.split_whitespace().next().unwrap_or("");
+
+        if body_directive == directive {
+            depth += 1;
+        } else if body_directive == directive_end {
+            if depth == 0 {
+                return Ok(body);
+            }
+            depth -= 1;
+        }
+        body.push((*body_line_number, body_line.clone()));
+    }
+}
+
+/// Remove the common indentation of body lines, so they can be indented where they're used.
+fn remove_indentation(body: Vec<(usize, String)>) -> Vec<(usize, String)> {
+    let body_indentation = body
+        .iter()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(_, line)| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    body.into_iter()
+        .map(|(body_line_number, line)| {
+            (
+                body_line_number,
+                String::from(line.get(body_indentation..).unwrap_or("")),
+            )
+        })
+        .collect()
+}
+
+/// Parse a compile time loop of the form `$name = start TO end`.
+fn parse_for(input_path: &str, line_number: usize, argument: &str) -> Result<(String, u64, u64)> {
+    let invalid = || {
+        anyhow!(
+            "Invalid 'FOR {}' at '{}:{}', expected 'FOR $name = start TO end'.",
+            argument,
+            input_path,
+            line_number
+        )
+    };
+
+    let (variable, range) = argument.split_once('=').ok_or_else(invalid)?;
+    let variable = variable
+        .trim()
+        .strip_prefix('$')
+        .filter(|variable| is_define_name(variable))
+        .ok_or_else(invalid)?;
+    let (start, end) = range.split_once(" TO ").ok_or_else(invalid)?;
+    let start = start.trim().parse::<u64>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u64>().map_err(|_| invalid())?;
+
+    Ok((String::from(variable), start, end))
+}
+
+/// Parse a macro call of the form `name(argument, ...)`.
+/// Quoted arguments have their quotes removed.
+fn parse_macro_call(line: &str) -> Option<(&str, Vec<String>)> {
+    let (name, arguments) = line.split_once('(')?;
+    let arguments = arguments.strip_suffix(')')?;
+    if !is_define_name(name) {
+        return None;
+    }
+
+    // Split arguments on commas that aren't quoted.
+    let mut arguments_parsed = vec![];
+    let mut argument = String::new();
+    let mut quoted = false;
+    for character in arguments.chars() {
+        match character {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                arguments_parsed.push(argument.trim().replace('"', ""));
+                argument.clear();
+                continue;
+            }
+            _ => {}
+        }
+        argument.push(character);
+    }
+    if !arguments_parsed.is_empty() || !argument.trim().is_empty() {
+        arguments_parsed.push(argument.trim().replace('"', ""));
+    }
+
+    Some((name, arguments_parsed))
+}
+
+/// Substitute variables referenced as `$name` in a line of MallardScript source.
+fn substitute_variables(line: &str, substitutions: &HashMap<&str, String>) -> String {
+    let mut output_line = String::with_capacity(line.len());
+    let mut characters = line.char_indices().peekable();
+
+    while let Some((index, character)) = characters.next() {
+        if character != '$' {
+            output_line.push(character);
+            continue;
+        }
+
+        // Collect the variable name following the reference.
+        let mut name_end = index + 1;
+        while let Some(&(next_index, next_character)) = characters.peek() {
+            if !(next_character.is_ascii_alphanumeric() || next_character == '_') {
+                break;
+            }
+            name_end = next_index + next_character.len_utf8();
+            characters.next();
+        }
+
+        match substitutions.get(&line[index + 1..name_end]) {
+            Some(value) => output_line.push_str(value),
+            None => output_line.push_str(&line[index..name_end]),
+        }
+    }
+
+    output_line
+}
+
+/// Number of unrolled loop iterations after which a warning is reported.
+static FOR_UNROLL_WARNING_ITERATIONS: usize = 100;
+
+/// Compile time loop iterations unrolled before preprocessing fails, across a file.
+static FOR_ITERATIONS_MAX: u64 = 10000;
+
+/// Prefix of the counters of runtime `LOOP` blocks, reserved for the compiler which names them
+/// when they're lowered.
+pub static LOOP_COUNTER_PREFIX: &str = "__loop_";
+
+/// Marker of the count of a runtime `LOOP` block, encoded as the string condition of a `WHILE`.
+static LOOP_MARKER: char = '\u{1d}';
+
+/// Separator between an import path and its arguments once encoded.
+static IMPORT_ARGUMENT_SEPARATOR: char = '\u{1f}';
+
+/// Marker of an expression encoded as a string.
+static EXPRESSION_MARKER: char = '\u{1e}';
+
+/// Prefix of a `DEFINE` name written with a `#`, which the MallardScript grammar doesn't accept.
+static DEFINE_REFERENCE_PREFIX: &str = "__define_reference_";
+
+/// Marker of a `REM` line carrying a command of the target as it's written, such as Flipper Zero
+/// BadUSB's `ALTSTRING`, which the MallardScript grammar doesn't accept.
+pub static TARGET_COMMAND_MARKER: char = '\u{1c}';
+
+/// Is a character one of the control characters the preprocessor encodes lines with.
+fn is_reserved_character(character: char) -> bool {
+    character == LOOP_MARKER
+        || character == IMPORT_ARGUMENT_SEPARATOR
+        || character == EXPRESSION_MARKER
+        || character == TARGET_COMMAND_MARKER
+}
+
+/// Encode the arguments of an `IMPORT "path" NAME=value ...` line into its path, so that the line
+/// can be parsed as a plain `IMPORT` and the arguments travel with the statement.
+fn encode_import(input_path: &str, line_number: usize, line: &str) -> Result<String> {
+    let line_indentation = &line[..line.len() - line.trim_start().len()];
+    let (import_path, arguments) = line
+        .trim()
+        .strip_prefix("IMPORT")
+        .map(str::trim_start)
+        .and_then(|import| import.strip_prefix('"'))
+        .and_then(|import| import.split_once('"'))
+        .ok_or_else(|| anyhow!("Invalid import at '{}:{}'.", input_path, line_number))?;
+    let arguments = arguments.trim();
+    if arguments.is_empty() {
+        return Ok(String::from(line));
+    }
+
+    let mut import_encoded = format!("{}IMPORT \"{}", line_indentation, import_path);
+    let mut arguments = arguments.chars().peekable();
+    while arguments.peek().is_some() {
+        // Collect the argument name.
+        let mut name = String::new();
+        let mut assigned = false;
+        for character in arguments.by_ref() {
+            if character == '=' {
+                assigned = true;
+                break;
+            }
+            name.push(character);
+        }
+        if !assigned || !is_define_name(name.trim()) {
+            return Err(anyhow!(
+                "Invalid import argument '{}' at '{}:{}', expected 'NAME=value'.",
+                name.trim(),
+                input_path,
+                line_number
+            ));
+        }
+
+        // Collect the argument value, which may be quoted.
+        let value = if arguments.peek() == Some(&'"') {
+            arguments.next();
+            arguments
+                .by_ref()
+                .take_while(|character| *character != '"')
+                .collect::<String>()
+        } else {
+            arguments
+                .by_ref()
+                .take_while(|character| !character.is_whitespace())
+                .collect::<String>()
+        };
+
+        import_encoded.push(IMPORT_ARGUMENT_SEPARATOR);
+        import_encoded.push_str(name.trim());
+        import_encoded.push('=');
+        import_encoded.push_str(&encode_value(&value));
+        while arguments
+            .peek()
+            .is_some_and(|character| character.is_whitespace())
+        {
+            arguments.next();
+        }
+    }
+    import_encoded.push('"');
+
+    Ok(import_encoded)
+}
+
+/// Decode an `IMPORT` value into its path and arguments.
+pub fn decode_import(import: &str) -> (String, HashMap<String, String>) {
+    let mut parts = import.split(IMPORT_ARGUMENT_SEPARATOR);
+    let import_path = String::from(parts.next().unwrap_or(""));
+
+    let arguments = parts
+        .filter_map(|argument| argument.split_once('='))
+        .map(|(name, value)| (String::from(name), decode_value(value)))
+        .collect::<HashMap<String, String>>();
+
+    (import_path, arguments)
+}
+
+/// Encode the `#NAME` of a `DEFINE #NAME value` line, so that the line can be parsed and the name
+/// is decoded with its `#` once it's parsed.
+fn encode_define(line: &str) -> String {
+    let line_indentation = &line[..line.len() - line.trim_start().len()];
+    match line.trim().strip_prefix("DEFINE #") {
+        Some(define) => format!(
+            "{}DEFINE {}{}",
+            line_indentation, DEFINE_REFERENCE_PREFIX, define
+        ),
+        None => String::from(line),
+    }
+}
+
+/// Encode the expression of a `VAR`, assignment, `IF`, `WHILE`, `DELAY` or `DEFAULTDELAY` line
+/// when it uses arithmetic or references a DuckyScript define as `#NAME`, which the MallardScript
+/// grammar doesn't accept, as a string that's decoded once it's parsed.
+fn encode_expression(line: &str) -> String {
+    let line_indentation = &line[..line.len() - line.trim_start().len()];
+    let line_trimmed = line.trim();
+    let (prefix, expression, suffix) = if let Some(condition) = line_trimmed
+        .strip_prefix("IF ")
+        .and_then(|condition| condition.strip_suffix(" THEN"))
+    {
+        ("IF ", condition, " THEN")
+    } else if let Some(condition) = line_trimmed.strip_prefix("WHILE ") {
+        ("WHILE ", condition, "")
+    } else if let Some(delay) = line_trimmed.strip_prefix("DELAY ") {
+        ("DELAY ", delay, "")
+    } else if let Some(delay) = line_trimmed.strip_prefix("DEFAULTDELAY ") {
+        ("DEFAULTDELAY ", delay, "")
+    } else if let Some((variable, assignment)) = line_trimmed
+        .split_once('=')
+        .filter(|(variable, _)| variable.starts_with("VAR ") || variable.starts_with('$'))
+    {
+        (&line_trimmed[..variable.len() + 1], assignment, "")
+    } else {
+        return String::from(line);
+    };
+
+    let expression = expression.trim();
+    let mut string = false;
+    let encoded = expression.chars().any(|character| {
+        string ^= character == '"';
+        !string && matches!(character, '+' | '-' | '*' | '/' | '%' | '#')
+    });
+    if !encoded {
+        return String::from(line);
+    }
+
+    format!(
+        "{}{} \"{}{}\"{}",
+        line_indentation,
+        prefix.trim_end(),
+        EXPRESSION_MARKER,
+        encode_value(expression),
+        suffix
+    )
+}
+
+/// Decode the expressions encoded by the preprocessor in parsed statements.
+pub fn decode_expressions(statements: &mut [Statement]) {
+    let decode = |expression: &mut String| {
+        if let Some(expression_encoded) = expression
+            .strip_prefix('"')
+            .and_then(|expression| expression.strip_prefix(EXPRESSION_MARKER))
+            .and_then(|expression| expression.strip_suffix('"'))
+        {
+            *expression = decode_value(expression_encoded);
+        }
+    };
+
+    for statement in statements {
+        match statement {
+            Statement::CommandDefine(command) => {
+                if let Some(name) = command.name.strip_prefix(DEFINE_REFERENCE_PREFIX) {
+                    command.name = format!("#{}", name);
+                }
+            }
+            Statement::CommandDelay(command) => decode(&mut command.value),
+            Statement::CommandDefaultDelay(command) => decode(&mut command.value),
+            Statement::VariableDeclaration(variable) => decode(&mut variable.assignment),
+            Statement::VariableAssignment(variable) => decode(&mut variable.assignment),
+            Statement::BlockIf(block) => {
+                decode(&mut block.expression);
+                decode_expressions(&mut block.statements_true);
+                decode_expressions(&mut block.statements_false);
+            }
+            Statement::BlockWhile(block) => {
+                decode(&mut block.expression);
+                decode_expressions(&mut block.statements);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Percent encode the characters that can't appear in an encoded string.
+fn encode_value(value: &str) -> String {
+    let mut value_encoded = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '%' | '\\' | '"' => value_encoded.push_str(&format!("%{:02X}", character as u32)),
+            character if is_reserved_character(character) => {
+                value_encoded.push_str(&format!("%{:02X}", character as u32))
+            }
+            character => value_encoded.push(character),
+        }
+    }
+
+    value_encoded
+}
+
+/// Decode a percent encoded value.
+fn decode_value(value: &str) -> String {
+    let mut value_decoded = String::with_capacity(value.len());
+    let mut characters = value.chars();
+    while let Some(character) = characters.next() {
+        if character != '%' {
+            value_decoded.push(character);
+            continue;
+        }
+
+        let code = characters.by_ref().take(2).collect::<String>();
+        match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+            Some(character) => value_decoded.push(character),
+            None => {
+                value_decoded.push('%');
+                value_decoded.push_str(&code);
+            }
+        }
+    }
+
+    value_decoded
+}
+
+/// Count of a runtime `LOOP` block, if the provided `WHILE` condition is one.
+pub fn loop_count(condition: &str) -> Option<String> {
+    condition
+        .strip_prefix('"')
+        .and_then(|condition| condition.strip_prefix(LOOP_MARKER))
+        .and_then(|condition| condition.strip_suffix('"'))
+        .map(decode_value)
+}
+
+/// Evaluate a compile time conditional directive.
+/// `#IF_DEFINED NAME` and `#IF_NOT_DEFINED NAME` check whether a define was provided.
+/// `#IF` compares values with `==` or `!=` after defines are substituted, or checks a single
+/// `TRUE`/`FALSE` or numeric value.
+fn evaluate_condition(
+    input_path: &str,
+    line_number: usize,
+    directive: &str,
+    argument: &str,
+    defines: &HashMap<String, String>,
+) -> Result<bool> {
+    if directive != "#IF" {
+        let name = argument.trim_start_matches('#');
+        if !is_define_name(name) {
+            return Err(anyhow!(
+                "Invalid define name '{}' at '{}:{}'.",
+                argument,
+                input_path,
+                line_number
+            ));
+        }
+
+        return Ok(defines.contains_key(name) == (directive == "#IF_DEFINED"));
+    }
+
+    let argument = substitute_defines(argument, defines, &[]);
+    if let Some((left, right)) = argument.split_once("!=") {
+        return Ok(left.trim() != right.trim());
+    }
+    if let Some((left, right)) = argument.split_once("==") {
+        return Ok(left.trim() == right.trim());
+    }
+
+    match argument.trim() {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        value => value.parse::<u64>().map(|value| value != 0).map_err(|_| {
+            anyhow!(
+                "Invalid condition '{}' at '{}:{}'.",
+                value,
+                input_path,
+                line_number
+            )
+        }),
+    }
+}
+
+/// Substitute compile time defines referenced as `#NAME` in a line of MallardScript source.
+/// References that aren't defined, or that the source defines with `DEFINE`, are left as they are
+/// so that `#` can still be typed.
+fn substitute_defines(
+    line: &str,
+    defines: &HashMap<String, String>,
+    defines_source: &[String],
+) -> String {
+    let mut output_line = String::with_capacity(line.len());
+    let mut characters = line.char_indices().peekable();
+
+    while let Some((index, character)) = characters.next() {
+        if character != '#' {
+            output_line.push(character);
+            continue;
+        }
+
+        // Collect the define name following the reference.
+        let mut name_end = index + 1;
+        while let Some(&(next_index, next_character)) = characters.peek() {
+            if !(next_character.is_ascii_alphanumeric() || next_character == '_') {
+                break;
+            }
+            name_end = next_index + next_character.len_utf8();
+            characters.next();
+        }
+        let name = &line[index + 1..name_end];
+
+        match defines.get(name) {
+            Some(value) if !defines_source.iter().any(|defined| defined == name) => {
+                log::info!("Substituting '#{}' with '{}'.", name, value);
+
+                output_line.push_str(value);
+            }
+            _ => output_line.push_str(&line[index..name_end]),
+        }
+    }
+
+    output_line
+}
+
+/// Is the provided name a valid define name.
+pub fn is_define_name(name: &str) -> bool {
+    let mut characters = name.chars();
+
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            characters.all(|character| character.is_ascii_alphanumeric() || character == '_')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pest_duckyscript::mallardscript::ast::StatementBlockWhile;
+
+    /// Preprocess source without defines or arguments.
+    fn preprocess_source(input_contents: &str) -> Result<Preprocessed> {
+        preprocess(
+            "index.ducky",
+            input_contents,
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn test_encode_value_round_trip() {
+        for value in [
+            "",
+            "plain",
+            "100%",
+            "%41",
+            "%zz",
+            "a \"quoted\" \\ value",
+            "\u{1c}\u{1d}\u{1e}\u{1f}",
+            "__define_reference_NAME",
+        ] {
+            let value_encoded = encode_value(value);
+
+            assert!(!value_encoded.contains(is_reserved_character));
+            assert!(!value_encoded.contains('"'));
+            assert_eq!(decode_value(&value_encoded), value);
+        }
+    }
+
+    #[test]
+    fn test_encode_import_round_trip() {
+        let line = encode_import(
+            "index.ducky",
+            1,
+            "  IMPORT \"open.ducky\" URL=\"https://example.com/?a=1&b=%20\" WAIT=500",
+        )
+        .unwrap();
+        assert!(line.starts_with("  IMPORT \"open.ducky\u{1f}"));
+
+        let (import_path, arguments) = decode_import(
+            line.trim()
+                .strip_prefix("IMPORT \"")
+                .unwrap()
+                .trim_end_matches('"'),
+        );
+        assert_eq!(import_path, "open.ducky");
+        assert_eq!(
+            arguments,
+            HashMap::from([
+                (
+                    String::from("URL"),
+                    String::from("https://example.com/?a=1&b=%20")
+                ),
+                (String::from("WAIT"), String::from("500")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_encode_expression_round_trip() {
+        let line = encode_expression("  VAR $x = (\"%\" + \"\\\\\") * #SIZE");
+        assert_eq!(
+            line,
+            "  VAR $x = \"\u{1e}(%22%25%22 + %22%5C%5C%22) * #SIZE\""
+        );
+
+        let expression = line.trim().strip_prefix("VAR $x = ").unwrap();
+        let mut statements = vec![Statement::BlockWhile(StatementBlockWhile {
+            expression: String::from(expression),
+            statements: vec![],
+        })];
+        decode_expressions(&mut statements);
+        match &statements[0] {
+            Statement::BlockWhile(block) => {
+                assert_eq!(block.expression, "(\"%\" + \"\\\\\") * #SIZE")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_loop_count_round_trip() {
+        let preprocessed = preprocess_source("LOOP $count\n  TAB\nEND_LOOP\n").unwrap();
+        let condition = preprocessed
+            .contents
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("WHILE "))
+            .unwrap();
+
+        assert_eq!(loop_count(condition), Some(String::from("$count")));
+        assert_eq!(loop_count("\"$count\""), None);
+        assert_eq!(loop_count("($count < 3)"), None);
+    }
+
+    #[test]
+    fn test_preprocess_reserved_characters() {
+        for character in ['\u{1c}', '\u{1d}', '\u{1e}', '\u{1f}'] {
+            let error = preprocess_source(&format!("STRING a\nSTRING b{}c\n", character))
+                .unwrap_err()
+                .to_string();
+
+            assert_eq!(
+                error,
+                format!(
+                    "Reserved character 'U+{:04X}' at 'index.ducky:2'.",
+                    character as u32
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_preprocess_reserved_define_values() {
+        let defines = HashMap::from([(String::from("NAME"), String::from("a\u{1f}b"))]);
+        let error = preprocess("index.ducky", "STRING #NAME\n", &defines, &HashMap::new())
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            error,
+            "Define 'NAME' used by 'index.ducky' contains a reserved character."
+        );
+    }
+
+    #[test]
+    fn test_preprocess_reserved_define_prefix() {
+        let error = preprocess_source("STRING a\nDEFINE __define_reference_NAME 1\n")
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            error,
+            "Define '__define_reference_NAME' at 'index.ducky:2' uses the reserved prefix '__define_reference_'."
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Context, Result};
+use expression::{Value, INTEGER_MAX};
+use simulator::Simulation;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Keys of a scenario file.
+static KEYS: &[&str] = &["variables", "inputs", "expect"];
+
+/// Keys of a scenario input.
+static INPUT_KEYS: &[&str] = &["time", "button", "variables"];
+
+/// Device input of a scenario at a time of the virtual clock.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Input {
+    /// Milliseconds since the payload started.
+    pub time: u64,
+    /// The device button is pressed.
+    pub button: bool,
+    /// Internal variables set by the device, such as `_CAPSLOCK_ON`.
+    pub variables: HashMap<String, Value>,
+}
+
+/// Simulated device and host state a payload runs against, with the actions it's expected to take.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    /// Internal variables set when the payload starts, such as `_OS`.
+    pub variables: HashMap<String, Value>,
+    /// Inputs sorted by time.
+    pub inputs: Vec<Input>,
+    /// Expected actions of the timeline, as they're displayed such as `TYPE 'cmd'`.
+    pub expect: Option<Vec<String>>,
+}
+
+impl Scenario {
+    /// Load a JSON scenario file.
+    pub fn load(path: &Path) -> Result<Scenario> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to load scenario file '{}'.", path.display()))?;
+
+        Scenario::parse(&contents)
+            .with_context(|| format!("Invalid scenario file '{}'.", path.display()))
+    }
+
+    /// Parse a JSON scenario.
+    pub fn parse(contents: &str) -> Result<Scenario> {
+        let scenario: serde_json::Value =
+            serde_json::from_str(contents).context("Unable to parse JSON.")?;
+        let scenario = object(&scenario, "scenario", KEYS)?;
+
+        let mut inputs = vec![];
+        if let Some(inputs_value) = scenario.get("inputs") {
+            let inputs_value = inputs_value
+                .as_array()
+                .ok_or_else(|| anyhow!("Scenario 'inputs' must be a list of inputs."))?;
+            for input in inputs_value {
+                let input = object(input, "input", INPUT_KEYS)?;
+                inputs.push(Input {
+                    time: input
+                        .get("time")
+                        .and_then(serde_json::Value::as_u64)
+                        .ok_or_else(|| anyhow!("Scenario input 'time' must be milliseconds."))?,
+                    button: match input.get("button") {
+                        Some(button) => button
+                            .as_bool()
+                            .ok_or_else(|| anyhow!("Scenario input 'button' must be a boolean."))?,
+                        None => false,
+                    },
+                    variables: variables(input.get("variables"))?,
+                });
+            }
+        }
+        inputs.sort_by_key(|input| input.time);
+
+        let expect = match scenario.get("expect") {
+            Some(expect) => Some(
+                expect
+                    .as_array()
+                    .and_then(|actions| {
+                        actions
+                            .iter()
+                            .map(|action| action.as_str().map(String::from))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .ok_or_else(|| anyhow!("Scenario 'expect' must be a list of actions."))?,
+            ),
+            None => None,
+        };
+
+        Ok(Scenario {
+            variables: variables(scenario.get("variables"))?,
+            inputs,
+            expect,
+        })
+    }
+
+    /// Check the actions of a simulation match the expected actions.
+    /// Returns the number of actions checked.
+    pub fn check(&self, simulation: &Simulation) -> Result<usize> {
+        let expect = match &self.expect {
+            Some(expect) => expect,
+            None => return Ok(0),
+        };
+
+        for (index, (action, event)) in expect.iter().zip(&simulation.events).enumerate() {
+            let event_action = event.action.to_string();
+            if *action != event_action {
+                return Err(anyhow!(
+                    "Expected action {} to be '{}' but found '{}' at '{}'.",
+                    index + 1,
+                    action,
+                    event_action,
+                    event.location
+                ));
+            }
+        }
+        if expect.len() != simulation.events.len() {
+            return Err(anyhow!(
+                "Expected {} action(s) but found {}.",
+                expect.len(),
+                simulation.events.len()
+            ));
+        }
+
+        Ok(expect.len())
+    }
+}
+
+/// Fields of a JSON object, only the provided keys are allowed.
+fn object<'a>(
+    value: &'a serde_json::Value,
+    name: &str,
+    keys: &[&str],
+) -> Result<&'a serde_json::Map<String, serde_json::Value>> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| anyhow!("Scenario {} must be an object.", name))?;
+    if let Some(key) = object.keys().find(|key| !keys.contains(&key.as_str())) {
+        return Err(anyhow!(
+            "Unknown {} key '{}', expected one of '{}'.",
+            name,
+            key,
+            keys.join("', '")
+        ));
+    }
+
+    Ok(object)
+}
+
+/// Variables of a JSON object, names may start with `$`.
+fn variables(value: Option<&serde_json::Value>) -> Result<HashMap<String, Value>> {
+    let mut variables = HashMap::new();
+    let object = match value {
+        Some(value) => value
+            .as_object()
+            .ok_or_else(|| anyhow!("Scenario 'variables' must be an object."))?,
+        None => return Ok(variables),
+    };
+
+    for (name, value) in object {
+        let value = match value {
+            serde_json::Value::Bool(value) => Value::Boolean(*value),
+            serde_json::Value::String(value) => Value::String(value.clone()),
+            serde_json::Value::Number(number) => match number.as_u64() {
+                Some(value) if value <= INTEGER_MAX => Value::Integer(value),
+                _ => {
+                    return Err(anyhow!(
+                        "Variable '{}' is outside of DuckyScript's range 0 to {}.",
+                        name,
+                        INTEGER_MAX
+                    ))
+                }
+            },
+            _ => {
+                return Err(anyhow!(
+                    "Variable '{}' must be a boolean, integer or string.",
+                    name
+                ))
+            }
+        };
+        variables.insert(String::from(name.trim_start_matches('$')), value);
+    }
+
+    Ok(variables)
+}
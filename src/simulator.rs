@@ -0,0 +1,533 @@
+use analysis;
+use anyhow::{anyhow, Context, Result};
+use cache::Cache;
+use diagnostic::{self, Location};
+use expression::{self, Value};
+use pest_duckyscript::mallardscript::ast::Statement;
+use preprocessor;
+use scenario::{Input, Scenario};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use {collect_command_key_values, load, CompileOptions};
+
+/// Statements and loop iterations run before a simulation stops, payloads may loop forever.
+pub static STEPS_MAX: usize = 100000;
+
+/// Commands stopping the payload.
+static COMMANDS_TERMINATING: &[&str] = &["STOP_PAYLOAD", "RESTART_PAYLOAD"];
+
+/// Internal variables set by the device when the payload starts, unless a scenario sets them.
+static DEVICE_VARIABLES: &[(&str, bool)] = &[
+    ("_CAPSLOCK_ON", false),
+    ("_NUMLOCK_ON", false),
+    ("_SCROLLLOCK_ON", false),
+    ("_BUTTON_ENABLED", true),
+    (BUTTON_PUSH_RECEIVED, false),
+];
+
+/// Internal variable set when the button is pressed, reset once the payload consumes the press.
+static BUTTON_PUSH_RECEIVED: &str = "_BUTTON_PUSH_RECEIVED";
+
+/// Lock keys of the `WAIT_FOR_*` commands with their internal variable.
+static LOCK_KEYS: &[(&str, &str)] = &[
+    ("CAPS", "_CAPSLOCK_ON"),
+    ("NUM", "_NUMLOCK_ON"),
+    ("SCROLL", "_SCROLLLOCK_ON"),
+];
+
+/// Action of the simulated device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Press a chord of keys together, such as `GUI r`.
+    Press(Vec<String>),
+    /// Type text, a keystroke for each character.
+    Type(String),
+    /// Run a device command, such as `ATTACKMODE HID`.
+    Command(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Press(keys) => write!(f, "PRESS {}", keys.join(" ")),
+            Action::Type(text) => write!(f, "TYPE '{}'", text),
+            Action::Command(command) => write!(f, "COMMAND {}", command),
+        }
+    }
+}
+
+/// Action of the simulated device at a time of its virtual clock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Milliseconds since the payload started.
+    pub time: u64,
+    pub location: Location,
+    pub action: Action,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} ms {} at '{}'",
+            self.time, self.action, self.location
+        )
+    }
+}
+
+impl Event {
+    /// JSON representation of the event.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut event = serde_json::json!({
+            "time": self.time,
+            "location": self.location.to_string(),
+        });
+        let fields = match &self.action {
+            Action::Press(keys) => serde_json::json!({ "action": "press", "keys": keys }),
+            Action::Type(text) => serde_json::json!({ "action": "type", "text": text }),
+            Action::Command(command) => {
+                serde_json::json!({ "action": "command", "command": command })
+            }
+        };
+        for (key, value) in fields.as_object().unwrap() {
+            event[key] = value.clone();
+        }
+
+        event
+    }
+}
+
+/// Timeline of a simulated program.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Simulation {
+    pub events: Vec<Event>,
+    /// Milliseconds the payload ran for.
+    pub duration: u64,
+}
+
+impl Simulation {
+    /// JSON representation of the timeline.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "events": self.events.iter().map(Event::to_json).collect::<Vec<serde_json::Value>>(),
+            "duration": self.duration,
+        })
+    }
+}
+
+/// Device state of a running program.
+struct Simulator<'a> {
+    /// Files being run, used to detect circular dependencies.
+    imports_stack: Vec<PathBuf>,
+    cache: &'a mut Cache,
+    characters_per_second: u64,
+    /// Milliseconds waited after each command, set by `DEFAULTDELAY`.
+    default_delay: u64,
+    /// Virtual clock in milliseconds.
+    time: u64,
+    steps: usize,
+    stopped: bool,
+    variables: HashMap<String, Value>,
+    events: Vec<Event>,
+    /// Scenario inputs sorted by time, and the index of the next one to apply.
+    inputs: Vec<Input>,
+    input_index: usize,
+}
+
+/// Simulate MallardScript input path and its imports against a virtual clock.
+/// Variables, `IF` and `WHILE` blocks run as they would on the device, `DELAY` and `DEFAULTDELAY`
+/// advance the clock and string and key commands type at the provided characters per second.
+/// The scenario sets internal variables and provides inputs over time, `WAIT_FOR_*` commands wait
+/// for the next input they're waiting on.
+pub fn simulate(
+    current_directory: PathBuf,
+    input_path: &str,
+    options: &CompileOptions,
+    characters_per_second: u64,
+    scenario: &Scenario,
+    cache: &mut Cache,
+) -> Result<Simulation> {
+    if characters_per_second == 0 {
+        return Err(anyhow!(
+            "Typing speed must be at least 1 character per second."
+        ));
+    }
+
+    let mut variables = DEVICE_VARIABLES
+        .iter()
+        .map(|(name, value)| (String::from(*name), Value::Boolean(*value)))
+        .collect::<HashMap<String, Value>>();
+    variables.extend(scenario.variables.clone());
+
+    let mut simulator = Simulator {
+        imports_stack: vec![],
+        cache,
+        characters_per_second,
+        default_delay: 0,
+        time: 0,
+        steps: 0,
+        stopped: false,
+        variables,
+        events: vec![],
+        inputs: scenario.inputs.clone(),
+        input_index: 0,
+    };
+    simulator.run_file(&current_directory, input_path, &HashMap::new(), options)?;
+
+    Ok(Simulation {
+        events: simulator.events,
+        duration: simulator.time,
+    })
+}
+
+impl Simulator<'_> {
+    /// Run MallardScript input path with its import arguments.
+    fn run_file(
+        &mut self,
+        current_directory: &Path,
+        input_path: &str,
+        arguments: &HashMap<String, String>,
+        options: &CompileOptions,
+    ) -> Result<()> {
+        let source = load(
+            current_directory,
+            input_path,
+            arguments,
+            options,
+            self.cache,
+        )?;
+        if self.imports_stack.contains(&source.path) {
+            return Err(anyhow!("Circular dependency detected."));
+        }
+
+        self.imports_stack.push(source.path.clone());
+        self.run_statements(
+            input_path,
+            &source.path,
+            &source.statements,
+            &source.line_numbers,
+            options,
+        )?;
+        self.imports_stack.pop();
+
+        Ok(())
+    }
+
+    /// Run statements, given the line numbers of the statements and their blocks.
+    fn run_statements(
+        &mut self,
+        input_path: &str,
+        input_path_expanded: &Path,
+        statements: &[Statement],
+        line_numbers: &[usize],
+        options: &CompileOptions,
+    ) -> Result<()> {
+        let mut line_index = 0;
+
+        for statement in statements {
+            if self.stopped {
+                break;
+            }
+            let location = Location {
+                input_path: String::from(input_path),
+                line_number: line_numbers.get(line_index).copied().unwrap_or_default(),
+            };
+            let block_line_numbers = line_numbers.get(line_index + 1..).unwrap_or_default();
+            line_index += diagnostic::count_statements(statement);
+
+            self.step(&location)?;
+
+            match statement {
+                Statement::VariableDeclaration(variable) => {
+                    let value = self.evaluate(&variable.assignment, &location)?;
+                    self.variables.insert(variable.name.clone(), value);
+                }
+                Statement::VariableAssignment(variable) => {
+                    let value = self.evaluate(&variable.assignment, &location)?;
+                    self.variables.insert(variable.name.clone(), value);
+                }
+                Statement::CommandDefaultDelay(command) => {
+                    self.default_delay = self.evaluate_integer(&command.value, &location)?;
+                }
+                Statement::CommandDelay(command) => {
+                    self.time += self.evaluate_integer(&command.value, &location)?;
+                }
+                Statement::CommandString(command) => {
+                    let text = self.string_text(&command.value, &location)?;
+                    self.type_text(text, &location);
+                    self.time += self.default_delay;
+                }
+                Statement::CommandStringln(command) => {
+                    let text = self.string_text(&command.value, &location)?;
+                    self.type_text(text, &location);
+                    self.press(vec![String::from("ENTER")], &location);
+                    self.time += self.default_delay;
+                }
+                Statement::CommandKey(command) => {
+                    self.press(collect_command_key_values(command), &location);
+                    self.time += self.default_delay;
+                }
+                Statement::CommandExfil(command) => {
+                    self.command(format!("EXFIL ${}", command.name), &location);
+                }
+                Statement::SingleCommand(command) => {
+                    self.command(command.name.clone(), &location);
+                    if let Some(wait) = command.name.strip_prefix("WAIT_FOR_") {
+                        self.wait_for(wait, &location)?;
+                    }
+                    self.time += self.default_delay;
+                    if COMMANDS_TERMINATING.contains(&command.name.as_str()) {
+                        self.stopped = true;
+                    }
+                }
+                Statement::CommandImport(command) => {
+                    let mut import_directory = input_path_expanded.to_path_buf();
+                    import_directory.pop();
+                    let (import_path, import_arguments) =
+                        preprocessor::decode_import(&command.value);
+                    self.run_file(&import_directory, &import_path, &import_arguments, options)
+                        .context(format!(
+                            "Unable to import file '{}' from '{}'.",
+                            import_path, input_path
+                        ))?;
+                }
+                Statement::BlockIf(block) => {
+                    let statements_true_count = block
+                        .statements_true
+                        .iter()
+                        .map(diagnostic::count_statements)
+                        .sum::<usize>();
+
+                    if self.evaluate_condition(&block.expression, &location)? {
+                        self.run_statements(
+                            input_path,
+                            input_path_expanded,
+                            &block.statements_true,
+                            block_line_numbers,
+                            options,
+                        )?;
+                    } else {
+                        self.run_statements(
+                            input_path,
+                            input_path_expanded,
+                            &block.statements_false,
+                            block_line_numbers
+                                .get(statements_true_count..)
+                                .unwrap_or_default(),
+                            options,
+                        )?;
+                    }
+                }
+                Statement::BlockWhile(block) => {
+                    // Runtime loops run their count, evaluated once.
+                    let count = match preprocessor::loop_count(&block.expression) {
+                        Some(count) => Some(self.evaluate_integer(&count, &location)?),
+                        None => None,
+                    };
+
+                    let mut iteration = 0;
+                    while !self.stopped
+                        && match count {
+                            Some(count) => iteration < count,
+                            None => self.evaluate_condition(&block.expression, &location)?,
+                        }
+                    {
+                        self.run_statements(
+                            input_path,
+                            input_path_expanded,
+                            &block.statements,
+                            block_line_numbers,
+                            options,
+                        )?;
+                        iteration += 1;
+                        self.step(&location)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Count a step of the program, stopping payloads that may loop forever.
+    /// Scenario inputs up to the current time are applied first.
+    fn step(&mut self, location: &Location) -> Result<()> {
+        self.apply_inputs();
+        self.steps += 1;
+        if self.steps > STEPS_MAX {
+            return Err(anyhow!(
+                "Simulation stopped after {} steps at '{}', the payload may loop forever.",
+                STEPS_MAX,
+                location
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the scenario inputs up to the current time.
+    fn apply_inputs(&mut self) {
+        while let Some(input) = self.inputs.get(self.input_index) {
+            if input.time > self.time {
+                break;
+            }
+
+            if input.button {
+                self.variables
+                    .insert(String::from(BUTTON_PUSH_RECEIVED), Value::Boolean(true));
+            }
+            self.variables.extend(input.variables.clone());
+            self.input_index += 1;
+        }
+    }
+
+    /// Wait for the button or a lock key, such as `BUTTON_PRESS` or `CAPS_ON`.
+    /// The clock advances to the next scenario input satisfying the wait.
+    fn wait_for(&mut self, wait: &str, location: &Location) -> Result<()> {
+        let inputs_remaining = &self.inputs[self.input_index..];
+        let position = if wait == "BUTTON_PRESS" {
+            inputs_remaining.iter().position(|input| input.button)
+        } else {
+            let (lock_key, state) = wait.rsplit_once('_').unwrap_or((wait, ""));
+            let variable = LOCK_KEYS
+                .iter()
+                .find(|(key, _)| *key == lock_key)
+                .map(|(_, variable)| *variable)
+                .ok_or_else(|| {
+                    anyhow!("Unable to simulate 'WAIT_FOR_{}' at '{}'.", wait, location)
+                })?;
+            let current = self.variables.get(variable).and_then(Value::as_boolean);
+            let target = match state {
+                "ON" => Some(true),
+                "OFF" => Some(false),
+                _ => None,
+            };
+            if target.is_some() && current == target {
+                return Ok(());
+            }
+
+            inputs_remaining.iter().position(|input| {
+                input
+                    .variables
+                    .get(variable)
+                    .and_then(Value::as_boolean)
+                    .is_some_and(|value| match target {
+                        Some(target) => value == target,
+                        None => Some(value) != current,
+                    })
+            })
+        };
+
+        match position {
+            Some(position) => {
+                self.time = self.time.max(self.inputs[self.input_index + position].time);
+                self.apply_inputs();
+                if wait == "BUTTON_PRESS" {
+                    self.consume_button();
+                }
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "'WAIT_FOR_{}' at '{}' waits forever, no scenario input after {} ms satisfies it.",
+                wait,
+                location,
+                self.time
+            )),
+        }
+    }
+
+    /// Evaluate an expression with the values of the variables set so far.
+    fn evaluate(&self, expression: &str, location: &Location) -> Result<Value> {
+        expression::parse(expression)
+            .and_then(|parsed| expression::substitute(&parsed, &self.variables))
+            .and_then(|substituted| {
+                expression::evaluate(&substituted).ok_or_else(|| {
+                    anyhow!("Result is outside of DuckyScript's range or types don't match.")
+                })
+            })
+            .with_context(|| format!("Unable to evaluate '{}' at '{}'.", expression, location))
+    }
+
+    /// Evaluate an integer expression, such as a delay.
+    fn evaluate_integer(&self, expression: &str, location: &Location) -> Result<u64> {
+        self.evaluate(expression, location)?
+            .as_integer()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Expected an integer for '{}' at '{}'.",
+                    expression,
+                    location
+                )
+            })
+    }
+
+    /// Evaluate a block condition, any non-zero integer is `TRUE`.
+    /// A condition reading a button press consumes it.
+    fn evaluate_condition(&mut self, expression: &str, location: &Location) -> Result<bool> {
+        let condition = self
+            .evaluate(expression, location)?
+            .as_boolean()
+            .ok_or_else(|| anyhow!("Expected a boolean for '{}' at '{}'.", expression, location))?;
+        if analysis::variables(expression)
+            .iter()
+            .any(|name| name == BUTTON_PUSH_RECEIVED)
+        {
+            self.consume_button();
+        }
+
+        Ok(condition)
+    }
+
+    /// Consume a button press, the device reports each press once.
+    fn consume_button(&mut self) {
+        self.variables
+            .insert(String::from(BUTTON_PUSH_RECEIVED), Value::Boolean(false));
+    }
+
+    /// Text typed by a string command, expressions referencing variables type their result.
+    fn string_text(&self, value: &str, location: &Location) -> Result<String> {
+        if !value.contains('$') || expression::parse(value).is_err() {
+            return Ok(String::from(value));
+        }
+
+        Ok(match self.evaluate(value, location)? {
+            Value::Integer(value) => value.to_string(),
+            Value::Boolean(value) => String::from(if value { "TRUE" } else { "FALSE" }),
+            Value::String(value) => value,
+        })
+    }
+
+    /// Type text, advancing the clock a keystroke for each character.
+    fn type_text(&mut self, text: String, location: &Location) {
+        let keystrokes = text.chars().count() as u64;
+        self.event(Action::Type(text), location);
+        self.time += self.typing(keystrokes);
+    }
+
+    /// Press a chord of keys, advancing the clock a keystroke.
+    fn press(&mut self, keys: Vec<String>, location: &Location) {
+        self.event(Action::Press(keys), location);
+        self.time += self.typing(1);
+    }
+
+    /// Run a device command.
+    fn command(&mut self, command: String, location: &Location) {
+        self.event(Action::Command(command), location);
+    }
+
+    /// Record an action at the current time.
+    fn event(&mut self, action: Action, location: &Location) {
+        self.events.push(Event {
+            time: self.time,
+            location: location.clone(),
+            action,
+        });
+    }
+
+    /// Milliseconds to type a number of keystrokes.
+    fn typing(&self, keystrokes: u64) -> u64 {
+        keystrokes.saturating_mul(1000) / self.characters_per_second
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::{anyhow, Context, Result};
+use cache::Cache;
+use diagnostic::Location;
+use expression;
+use pest_duckyscript::mallardscript::ast::Statement;
+use preprocessor;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use {load, CompileOptions};
+
+/// Typing speed of `STRING` and key commands when none is provided, in characters per second.
+pub static CHARACTERS_PER_SECOND: u64 = 50;
+
+/// Commands that type a single keystroke, like a key command.
+static COMMANDS_KEYSTROKE: &[&str] = &[
+    "INJECT_MOD",
+    "RANDOM_LOWERCASE_LETTER",
+    "RANDOM_UPPERCASE_LETTER",
+    "RANDOM_LETTER",
+    "RANDOM_NUMBER",
+    "RANDOM_SPECIAL",
+    "RANDOM_CHAR",
+];
+
+/// Estimated execution time of a file, excluding the files it imports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub input_path: String,
+    pub milliseconds: u64,
+    /// Some statements of the file run for an unknown time and aren't included.
+    pub unknown: bool,
+}
+
+/// Estimated execution time of a program and its imports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Estimate of each file, in the order they first run.
+    pub files: Vec<Estimate>,
+    /// Statements running for an unknown time, with the reason.
+    pub unknowns: Vec<(Location, String)>,
+}
+
+impl Report {
+    /// Total estimated milliseconds, a lower bound when some statements are unknown.
+    pub fn milliseconds(&self) -> u64 {
+        self.files
+            .iter()
+            .fold(0, |total, file| total.saturating_add(file.milliseconds))
+    }
+
+    /// Does some statement run for an unknown time.
+    pub fn is_unknown(&self) -> bool {
+        !self.unknowns.is_empty()
+    }
+
+    /// Estimate of a file, added when it's not estimated yet.
+    fn file(&mut self, input_path: &str) -> &mut Estimate {
+        match self
+            .files
+            .iter()
+            .position(|file| file.input_path == input_path)
+        {
+            Some(index) => &mut self.files[index],
+            None => {
+                self.files.push(Estimate {
+                    input_path: String::from(input_path),
+                    milliseconds: 0,
+                    unknown: false,
+                });
+                self.files.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Add milliseconds to the estimate of a file.
+    fn add(&mut self, input_path: &str, milliseconds: u64) {
+        let file = self.file(input_path);
+        file.milliseconds = file.milliseconds.saturating_add(milliseconds);
+    }
+
+    /// Mark a statement as running for an unknown time.
+    fn add_unknown(&mut self, location: Location, reason: String) {
+        self.file(&location.input_path).unknown = true;
+        self.unknowns.push((location, reason));
+    }
+
+    /// Add the estimates of statements that run after these ones.
+    fn extend(&mut self, report: Report) {
+        for estimate in report.files {
+            let file = self.file(&estimate.input_path);
+            file.milliseconds = file.milliseconds.saturating_add(estimate.milliseconds);
+            file.unknown |= estimate.unknown;
+        }
+        self.unknowns.extend(report.unknowns);
+    }
+
+    /// Repeat the estimates, as when the statements run in a loop.
+    fn repeat(mut self, count: u64) -> Report {
+        for file in &mut self.files {
+            file.milliseconds = file.milliseconds.saturating_mul(count);
+        }
+
+        self
+    }
+}
+
+/// Execution state of a program and its imports.
+struct Estimator<'a> {
+    /// Files being estimated, used to detect circular dependencies.
+    imports_stack: Vec<PathBuf>,
+    cache: &'a mut Cache,
+    characters_per_second: u64,
+    /// Milliseconds waited after each command, set by `DEFAULTDELAY`.
+    default_delay: u64,
+}
+
+/// Estimate the execution time of MallardScript input path and its imports.
+/// `DELAY` and `DEFAULTDELAY` wait their value, string commands type at the provided characters
+/// per second and every key press, including commands typing a random character, is one keystroke.
+/// An `IF` with a runtime condition is unknown and only its shorter branch is included, so the total
+/// stays a lower bound. Loops with runtime bounds and `WAIT_FOR_*` commands run for an unknown time
+/// and aren't included.
+/// The source is estimated rather than the compiled program, so unknown statements are reported at
+/// the lines they're written on, and `LOOP` counts and `FOR` iterations are known before they're
+/// lowered into `WHILE` loops over runtime counters.
+pub fn estimate(
+    current_directory: PathBuf,
+    input_path: &str,
+    options: &CompileOptions,
+    characters_per_second: u64,
+    cache: &mut Cache,
+) -> Result<Report> {
+    if characters_per_second == 0 {
+        return Err(anyhow!(
+            "Typing speed must be at least 1 character per second."
+        ));
+    }
+
+    let mut estimator = Estimator {
+        imports_stack: vec![],
+        cache,
+        characters_per_second,
+        default_delay: 0,
+    };
+
+    estimator.estimate_file(&current_directory, input_path, &HashMap::new(), options)
+}
+
+impl Estimator<'_> {
+    /// Estimate MallardScript input path with its import arguments.
+    fn estimate_file(
+        &mut self,
+        current_directory: &Path,
+        input_path: &str,
+        arguments: &HashMap<String, String>,
+        options: &CompileOptions,
+    ) -> Result<Report> {
+        let source = load(
+            current_directory,
+            input_path,
+            arguments,
+            options,
+            self.cache,
+        )?;
+        if self.imports_stack.contains(&source.path) {
+            return Err(anyhow!("Circular dependency detected."));
+        }
+
+        self.imports_stack.push(source.path.clone());
+        let mut report = Report::default();
+        report.file(input_path);
+        report.extend(self.estimate_statements(
+            input_path,
+            &source.path,
+            &source.statements,
+            &mut source.line_numbers.into_iter(),
+            options,
+        )?);
+        self.imports_stack.pop();
+
+        Ok(report)
+    }
+
+    /// Estimate statements in the order they run, following imports.
+    fn estimate_statements(
+        &mut self,
+        input_path: &str,
+        input_path_expanded: &Path,
+        statements: &[Statement],
+        line_numbers: &mut impl Iterator<Item = usize>,
+        options: &CompileOptions,
+    ) -> Result<Report> {
+        let mut report = Report::default();
+
+        for statement in statements {
+            let location = Location {
+                input_path: String::from(input_path),
+                line_number: line_numbers.next().unwrap_or_default(),
+            };
+
+            match statement {
+                Statement::CommandDefaultDelay(command) => match milliseconds(&command.value) {
+                    Some(delay) => self.default_delay = delay,
+                    None => {
+                        self.default_delay = 0;
+                        report.add_unknown(
+                            location,
+                            format!("DEFAULTDELAY '{}' is set at runtime.", command.value),
+                        );
+                    }
+                },
+                Statement::CommandDelay(command) => match milliseconds(&command.value) {
+                    Some(delay) => report.add(input_path, delay),
+                    None => report.add_unknown(
+                        location,
+                        format!("DELAY '{}' is set at runtime.", command.value),
+                    ),
+                },
+                Statement::CommandString(command) => {
+                    self.estimate_string(&mut report, &command.value, 0, location);
+                }
+                Statement::CommandStringln(command) => {
+                    self.estimate_string(&mut report, &command.value, 1, location);
+                }
+                Statement::CommandKey(_) => {
+                    report.add(input_path, self.typing(1) + self.default_delay);
+                }
+                Statement::SingleCommand(command) if command.name.starts_with("WAIT_FOR_") => {
+                    report.add_unknown(
+                        location,
+                        format!("'{}' waits for the device at runtime.", command.name),
+                    );
+                }
+                Statement::SingleCommand(command)
+                    if COMMANDS_KEYSTROKE.contains(&command.name.as_str()) =>
+                {
+                    report.add(input_path, self.typing(1) + self.default_delay);
+                }
+                Statement::SingleCommand(_) => report.add(input_path, self.default_delay),
+                Statement::CommandImport(command) => {
+                    let mut import_directory = input_path_expanded.to_path_buf();
+                    import_directory.pop();
+                    let (import_path, import_arguments) =
+                        preprocessor::decode_import(&command.value);
+                    report.extend(
+                        self.estimate_file(
+                            &import_directory,
+                            &import_path,
+                            &import_arguments,
+                            options,
+                        )
+                        .context(format!(
+                            "Unable to import file '{}' from '{}'.",
+                            import_path, input_path
+                        ))?,
+                    );
+                }
+                Statement::BlockIf(block) => {
+                    // Both branches are walked to follow their line numbers.
+                    let default_delay = self.default_delay;
+                    let report_true = self.estimate_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_true,
+                        line_numbers,
+                        options,
+                    )?;
+                    let default_delay_true =
+                        std::mem::replace(&mut self.default_delay, default_delay);
+                    let report_false = self.estimate_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements_false,
+                        line_numbers,
+                        options,
+                    )?;
+
+                    let condition = match condition(&block.expression) {
+                        Some(condition) => condition,
+                        None => {
+                            report.add_unknown(
+                                location,
+                                format!(
+                                    "IF '{}' is decided at runtime, only its shorter branch is included.",
+                                    block.expression
+                                ),
+                            );
+                            report_true.milliseconds() <= report_false.milliseconds()
+                        }
+                    };
+                    if condition {
+                        self.default_delay = default_delay_true;
+                        report.extend(report_true);
+                    } else {
+                        report.extend(report_false);
+                    }
+                }
+                Statement::BlockWhile(block) => {
+                    let default_delay = self.default_delay;
+                    let report_loop = self.estimate_statements(
+                        input_path,
+                        input_path_expanded,
+                        &block.statements,
+                        line_numbers,
+                        options,
+                    )?;
+
+                    if let Some(count) = preprocessor::loop_count(&block.expression) {
+                        match count.parse::<u64>() {
+                            Ok(count) => report.extend(report_loop.repeat(count)),
+                            Err(_) => {
+                                self.default_delay = default_delay;
+                                report.add_unknown(
+                                    location,
+                                    format!("LOOP runs '{}' times at runtime.", count),
+                                );
+                            }
+                        }
+                    } else if condition(&block.expression) == Some(false) {
+                        self.default_delay = default_delay;
+                    } else {
+                        self.default_delay = default_delay;
+                        report.add_unknown(
+                            location,
+                            format!(
+                                "WHILE runs until '{}' is FALSE at runtime.",
+                                block.expression
+                            ),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Estimate a string command typing its value and additional keystrokes.
+    fn estimate_string(
+        &self,
+        report: &mut Report,
+        value: &str,
+        keystrokes: u64,
+        location: Location,
+    ) {
+        // Only values referencing variables are expressions, like they're lowered by `downlevel`.
+        if value.contains('$') && expression::parse(value).is_ok() {
+            report.add_unknown(location, format!("'{}' is typed at runtime.", value));
+            return;
+        }
+
+        report.add(
+            &location.input_path,
+            self.typing(value.chars().count() as u64 + keystrokes) + self.default_delay,
+        );
+    }
+
+    /// Milliseconds to type a number of keystrokes.
+    fn typing(&self, keystrokes: u64) -> u64 {
+        keystrokes.saturating_mul(1000) / self.characters_per_second
+    }
+}
+
+/// Milliseconds of a delay, if it's known at compile time.
+fn milliseconds(value: &str) -> Option<u64> {
+    expression::parse(value)
+        .ok()
+        .and_then(|expression| expression::evaluate(&expression))
+        .and_then(|value| value.as_integer())
+}
+
+/// Value of a block condition, if it's known at compile time.
+fn condition(condition: &str) -> Option<bool> {
+    expression::parse(condition)
+        .ok()
+        .and_then(|expression| expression::evaluate(&expression))
+        .and_then(|value| value.as_boolean())
+}
@@ -1,11 +1,14 @@
 extern crate assert_cmd;
 extern crate predicates;
 extern crate pretty_assertions;
+extern crate serde_json;
+extern crate sha2;
 extern crate tempfile;
 
 use assert_cmd::prelude::*;
 use predicates::prelude::*;
 use pretty_assertions::assert_eq;
+use sha2::{Digest, Sha256};
 use std::{io::Write, process::Command};
 use tempfile::tempdir;
 use tempfile::NamedTempFile;
@@ -176,13 +179,14 @@ VAR $MY_VARIABLE = 34
     let temp_output_path = tempdir().unwrap();
     let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
 
-    // When the user builds the script.
+    // When the user builds the script without variable analysis.
     let result = cmd
         .arg("build")
         .arg("--input")
         .arg(input_file.path())
         .arg("--output")
         .arg(output_path)
+        .arg("--no-analyze")
         .assert();
 
     result
@@ -449,13 +453,14 @@ END_IF"#,
     let temp_output_path = tempdir().unwrap();
     let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
 
-    // When the user builds the script.
+    // When the user builds the script without variable analysis.
     let result = cmd
         .arg("build")
         .arg("--input")
         .arg(input_file.path())
         .arg("--output")
         .arg(output_path)
+        .arg("--no-analyze")
         .assert();
 
     result
@@ -513,13 +518,14 @@ END_IF"#,
     let temp_output_path = tempdir().unwrap();
     let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
 
-    // When the user builds the script.
+    // When the user builds the script without variable analysis.
     let result = cmd
         .arg("build")
         .arg("--input")
         .arg(input_file.path())
         .arg("--output")
         .arg(output_path)
+        .arg("--no-analyze")
         .assert();
 
     result
@@ -583,13 +589,14 @@ END_IF"#,
     let temp_output_path = tempdir().unwrap();
     let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
 
-    // When the user builds the script.
+    // When the user builds the script without variable analysis.
     let result = cmd
         .arg("build")
         .arg("--input")
         .arg(input_file.path())
         .arg("--output")
         .arg(output_path)
+        .arg("--no-analyze")
         .assert();
 
     result
@@ -756,6 +763,110 @@ END_WHILE"#,
     Ok(())
 }
 
+#[test]
+fn test_command_build_duckyscript_valid_exfil() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file exfiltrating a variable.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+VAR $total = 2
+EXFIL $total
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output keeps the variable reference.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $total = 2
+EXFIL $total"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_define() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a DuckyScript define.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+DEFINE URL example.com
+STRING URL
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output keeps the define name.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"DEFINE URL example.com
+STRING URL"#,
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_command_build_duckyscript_valid_multi_command_key() -> Result<(), Box<dyn std::error::Error>>
 {
@@ -1162,3 +1273,3473 @@ IMPORT "./__non_existant.ducky"
 
     Ok(())
 }
+
+#[test]
+fn test_command_build_duckyscript_valid_defines() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file referencing defines.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+REM Target #TARGET_URL.
+STRING #TARGET_URL
+DELAY #SLOW
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And a define file.
+    let mut define_file = NamedTempFile::new()?;
+    define_file.write_all(
+        String::from(
+            r#"
+# Defaults.
+TARGET_URL=http://localhost
+SLOW=500
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with defines.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--define-file")
+        .arg(define_file.path())
+        .arg("-D")
+        .arg("TARGET_URL=http://example.com")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output is correct.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"REM Target http://example.com.
+STRING http://example.com
+DELAY 500"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_defines_missing() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file requiring defines.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+#REQUIRE TARGET_URL
+#REQUIRE SLOW
+STRING #TARGET_URL
+DELAY #SLOW
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script without all the defines.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("-D")
+        .arg("TARGET_URL=http://example.com")
+        .assert();
+
+    result
+        // Then errors occurred.
+        .failure()
+        // Then the build failed.
+        .stderr(
+            predicate::str::is_match("Failed to compile to output file '.+index\\.ducky'\\.")
+                .unwrap(),
+        )
+        .stderr(
+            predicate::str::is_match("Missing required define '#SLOW' declared at '.+:3'\\.")
+                .unwrap(),
+        );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_defines_undeclared(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file typing '#' and using a DuckyScript define.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"DEFINE #WAIT 500
+STRING Color #FF0000 and issue #A1
+DELAY #WAIT
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script without defines.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the references are left for the device.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"DEFINE #WAIT 500
+STRING Color #FF0000 and issue #A1
+DELAY #WAIT"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_conditional_compilation(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with compile time conditionals.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+REM Open a terminal.
+#IF #OS == WINDOWS
+GUI r
+STRING cmd
+#ELSE_IF #OS == MACOS
+COMMAND SPACE
+STRING terminal
+#ELSE
+CTRL ALT t
+#END_IF
+ENTER
+IF ($_CAPSLOCK_ON == TRUE) THEN
+  #IF_DEFINED SLOW
+  DELAY #SLOW
+  #ELSE
+  DELAY 100
+  #END_IF
+  CAPSLOCK
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for a specific operating system.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("-D")
+        .arg("OS=MACOS")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output only contains the selected branches.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"REM Open a terminal.
+COMMAND SPACE
+STRING terminal
+ENTER
+IF ($_CAPSLOCK_ON == TRUE) THEN
+  DELAY 100
+  CAPSLOCK
+END_IF"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_conditional_compilation_unterminated(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with an unterminated compile time conditional.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+#IF_DEFINED OS
+STRING Hello, Friend.
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then errors occurred.
+        .failure()
+        // Then the build failed.
+        .stderr(
+            predicate::str::is_match("Failed to compile to output file '.+index\\.ducky'\\.")
+                .unwrap(),
+        )
+        .stderr(predicate::str::is_match("Missing '#END_IF' for '#IF' at '.+:2'\\.").unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_optimize() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with constant conditions.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+IF TRUE THEN
+  STRING Reachable.
+ELSE
+  STRING Unreachable.
+END_IF
+IF $MY_VARIABLE > 0 && TRUE THEN
+  WHILE FALSE
+    STRING Unreachable.
+  END_WHILE
+  IF NOT FALSE THEN
+    DELAY 100
+  END_IF
+END_IF
+IF ($MY_VARIABLE == 1) THEN
+END_IF
+WHILE ($_CAPSLOCK_ON == FALSE)
+END_WHILE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with optimizations and without variable analysis.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--no-analyze")
+        .arg("--optimize")
+        .arg("-v")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the removed blocks were reported.
+        .stdout(predicate::str::contains("Removed 'IF TRUE' in '"))
+        .stdout(predicate::str::contains("Removed 'WHILE FALSE' in '"))
+        .stdout(predicate::str::contains(
+            "Folded condition '$MY_VARIABLE > 0 && TRUE' into '$MY_VARIABLE > 0'.",
+        ))
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output is optimized.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"STRING Reachable.
+IF $MY_VARIABLE > 0 THEN
+  DELAY 100
+END_IF
+WHILE ($_CAPSLOCK_ON == FALSE)
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_macros() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with macros, and a statement written like a call to an undeclared one.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+MACRO open_url($url, $wait)
+  VAR $attempts = 0
+  GUI r
+  DELAY $wait
+  STRING $url
+  ENTER
+END_MACRO
+MACRO pause
+  DELAY 100
+END_MACRO
+open_url("http://example.com", 500)
+IF TRUE THEN
+  open_url("http://example.com/a,b", 100)
+  pause()
+END_IF
+DELAY(200)
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has the macros expanded.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $attempts_open_url_1 = 0
+GUI r
+DELAY 500
+STRING http://example.com
+ENTER
+IF TRUE THEN
+  VAR $attempts_open_url_2 = 0
+  GUI r
+  DELAY 100
+  STRING http://example.com/a,b
+  ENTER
+  DELAY 100
+END_IF
+DELAY (200)"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_macros_recursive(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with recursive macros.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+MACRO a
+  b()
+END_MACRO
+MACRO b
+  a()
+END_MACRO
+a()
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then errors occurred.
+        .failure()
+        // Then the build failed with the call sites and definitions.
+        .stderr(
+            predicate::str::is_match("Failed to compile to output file '.+index\\.ducky'\\.")
+                .unwrap(),
+        )
+        .stderr(
+            predicate::str::is_match(
+                "Unable to expand macro 'a' called at '.+:8', defined at '.+:2'\\.",
+            )
+            .unwrap(),
+        )
+        .stderr(
+            predicate::str::is_match("Recursive macro 'a' called at '.+:6', defined at '.+:2'\\.")
+                .unwrap(),
+        );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_import_arguments() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency with parameters.
+    let mut input_file_dependency = NamedTempFile::new()?;
+    input_file_dependency.write_all(
+        String::from(
+            r#"#PARAMETER URL
+#PARAMETER WAIT
+GUI r
+DELAY #WAIT
+STRING #URL
+ENTER"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And DuckyScript file with IMPORT commands passing arguments.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"
+REM Hello, Friend.
+IMPORT "{}" URL="https://example.com/?q=a b" WAIT=500
+"#,
+            input_file_dependency.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has the arguments substituted.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"REM Hello, Friend.
+GUI r
+DELAY 500
+STRING https://example.com/?q=a b
+ENTER"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_import_arguments_missing(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency with parameters.
+    let mut input_file_dependency = NamedTempFile::new()?;
+    input_file_dependency.write_all(
+        String::from(
+            r#"#PARAMETER URL
+#PARAMETER WAIT
+STRING #URL"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And DuckyScript file with IMPORT command missing an argument.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"
+IMPORT "{}" URL="https://example.com"
+"#,
+            input_file_dependency.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then errors occurred.
+        .failure()
+        // Then the build failed.
+        .stderr(
+            predicate::str::is_match("Failed to compile to output file '.+index\\.ducky'\\.")
+                .unwrap(),
+        )
+        .stderr(predicate::str::is_match("0: Unable to import file '.+' from '.+'\\.").unwrap())
+        .stderr(
+            predicate::str::is_match("1: Missing parameter 'WAIT' declared at '.+:2'\\.").unwrap(),
+        );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_for_unrolled() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with compile time loops.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+FOR $i = 1 TO 2
+  STRING Field $i
+  FOR $j = 2 TO 1
+    TAB
+  END_FOR
+END_FOR
+IF TRUE THEN
+  FOR $i = 1 TO 3
+    DELAY $i
+  END_FOR
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has the loops unrolled.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"STRING Field 1
+TAB
+TAB
+STRING Field 2
+TAB
+TAB
+IF TRUE THEN
+  DELAY 1
+  DELAY 2
+  DELAY 3
+END_IF"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_for_unrolled_large(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a large compile time loop.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+FOR $i = 1 TO 200
+  TAB
+END_FOR
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the output size was reported.
+        .stdout(
+            predicate::str::is_match(
+                "Unrolled 'FOR \\$i = 1 TO 200' at '.+:2' into 200 iterations, adding 200 lines \\(800 bytes\\)\\.",
+            )
+            .unwrap(),
+        )
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_for_unrolled_large(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a compile time loop too large to unroll.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+FOR $i = 1 TO 4000000000
+  TAB
+END_FOR
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    // Then the build failed before unrolling the loop.
+    result.failure().stderr(
+        predicate::str::is_match(
+            "Unable to unroll 'FOR \\$i = 1 TO 4000000000' at '.+:2', unrolling exceeds 10000 iterations\\.",
+        )
+        .unwrap(),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_loop_lowered() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with nested runtime loops.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+VAR $fields = 3
+LOOP $fields
+  STRING Field
+  LOOP 2
+    TAB
+  END_LOOP
+END_LOOP
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has the loops lowered into while loops.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $fields = 3
+VAR $__loop_1 = 0
+WHILE ($__loop_1 < $fields)
+  STRING Field
+  VAR $__loop_2 = 0
+  WHILE ($__loop_2 < 2)
+    TAB
+    $__loop_2 = ($__loop_2 + 1)
+  END_WHILE
+  $__loop_1 = ($__loop_1 + 1)
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_while_loop_named() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a hand written loop shaped like a lowered LOOP.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"VAR $__loop = 0
+WHILE ($__loop < 5)
+  $__loop = ($__loop + 1)
+END_WHILE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output keeps the loop as it's written.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $__loop = 0
+WHILE ($__loop < 5)
+  $__loop = ($__loop + 1)
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_loop_counter() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file declaring a variable named like a loop counter.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"VAR $__loop_1 = 3
+LOOP 2
+  TAB
+END_LOOP
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    // Then the build failed at the variable.
+    result.failure().stderr(
+        predicate::str::is_match(
+            "Variable '\\$__loop_1' at '.+:1' is reserved for the counters of runtime loops\\.",
+        )
+        .unwrap(),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_loop_lowered_optimized(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a runtime loop counting to a constant expression.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+LOOP 2 + 3
+  TAB
+END_LOOP
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script optimized.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("-O")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has the loop lowered with its counter and the count folded.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $__loop_1 = 0
+WHILE $__loop_1 < 5
+  TAB
+  $__loop_1 = ($__loop_1 + 1)
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_variable_analysis() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with duplicate and unused variables.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+VAR $total = 0
+VAR $unused = 1
+VAR $total = 2
+IF $total > $_RANDOM_INT THEN
+  DELAY $total
+END_IF
+VAR $_unused = 3
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script, analyzing variables by default.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then each variable warning is reported with its location.
+        .stdout(
+            predicate::str::is_match(
+                "warning 'duplicate-declaration' at '.+:4': Variable '\\$total' is already declared at '.+:2'\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'unused-variable' at '.+:3': Variable '\\$unused' is declared but never read\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'unused-variable' at '.+:8': Variable '\\$_unused' is declared but never read\\.",
+            )
+            .unwrap(),
+        )
+        // Then internal variables aren't reported.
+        .stdout(predicate::str::contains("_RANDOM_INT").not())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_variable_analysis(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file importing a file that reads a variable declared after the import.
+    let temp_input_path = tempdir().unwrap();
+    let import_file_path = temp_input_path.path().join("exfil.ducky");
+    std::fs::write(&import_file_path, "EXFIL $secret\n")?;
+    let input_file_path = temp_input_path.path().join("index.ducky");
+    std::fs::write(
+        &input_file_path,
+        "IMPORT \"./exfil.ducky\"\n$secret = 2\nVAR $secret = 1\nDELAY $missing\n",
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with variable analysis.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(&input_file_path)
+        .arg("--output")
+        .arg(output_path)
+        .arg("--analyze")
+        .assert();
+
+    result
+        // Then each variable error is reported with its location, including imported files.
+        .stdout(
+            predicate::str::is_match(
+                "error 'undeclared-variable' at '\\./exfil\\.ducky:1': Variable '\\$secret' is used before it's declared at '.+index\\.ducky:3'\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "error 'assignment-before-declaration' at '.+index\\.ducky:2': Variable '\\$secret' is assigned before it's declared at '.+index\\.ducky:3'\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "error 'undeclared-variable' at '.+index\\.ducky:4': Variable '\\$missing' is used but never declared\\.",
+            )
+            .unwrap(),
+        )
+        // Then the build failed.
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("Variable analysis found 3 error(s)."));
+
+    // Then no output was written.
+    assert!(!temp_output_path.path().join("index.ducky").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_expressions() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with arithmetic expressions.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+VAR $attempts = 0
+VAR $done = FALSE
+WHILE ($attempts * 2) < 10 && NOT $done
+  $attempts = $attempts + 1
+  IF $attempts % 3 == 0 THEN
+    $done = TRUE
+  END_IF
+END_WHILE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output keeps the expressions.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $attempts = 0
+VAR $done = FALSE
+WHILE ($attempts * 2) < 10 && NOT $done
+  $attempts = $attempts + 1
+  IF $attempts % 3 == 0 THEN
+    $done = TRUE
+  END_IF
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_expressions() -> Result<(), Box<dyn std::error::Error>> {
+    for (input, error) in [
+        (
+            "VAR $wait = 60000 + 6000\n",
+            "Integer overflow in '60000 \\+ 6000', result is outside of DuckyScript's range 0 to 65535\\.",
+        ),
+        (
+            "VAR $ready = TRUE\n$ready = $ready + 1\n",
+            "Type mismatch, '\\+' expects integers but found boolean '\\$ready'\\.",
+        ),
+        (
+            "VAR $count = 1\n$count = FALSE\n",
+            "Type mismatch at '.+:2', variable '\\$count' is integer but is assigned boolean 'FALSE'\\.",
+        ),
+        ("IF 1 + THEN\n  DELAY 1\nEND_IF\n", "Unexpected end of expression '1 \\+'\\."),
+    ] {
+        // Given the CLI.
+        let mut cmd = Command::cargo_bin("mallardscript")?;
+
+        // And DuckyScript file with an invalid expression.
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(input.as_bytes())?;
+
+        // And an output directory.
+        let temp_output_path = tempdir().unwrap();
+        let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+        // When the user builds the script.
+        let result = cmd
+            .arg("build")
+            .arg("--input")
+            .arg(input_file.path())
+            .arg("--output")
+            .arg(output_path)
+            .assert();
+
+        result
+            // Then the build failed with the expression error.
+            .failure()
+            .code(2)
+            .stderr(predicate::str::is_match(error).unwrap());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_command_lint_duckyscript_valid() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file importing the same file twice, allowing a missing DEFAULTDELAY and
+    // lowering a busy loop to a warning.
+    let temp_input_path = tempdir().unwrap();
+    std::fs::write(temp_input_path.path().join("wait.ducky"), "DELAY 100\n")?;
+    let input_file_path = temp_input_path.path().join("index.ducky");
+    std::fs::write(
+        &input_file_path,
+        r#"REM mallard: allow(missing-defaultdelay)
+DELAY 5
+STRING hello 
+IMPORT "./wait.ducky"
+IMPORT "./wait.ducky"
+IF TRUE THEN
+END_IF
+STOP_PAYLOAD
+STRING never
+REM mallard: warning(while-true-without-delay)
+WHILE TRUE
+  STRING spam
+END_WHILE
+"#,
+    )?;
+
+    // When the user lints the script.
+    let result = cmd
+        .arg("lint")
+        .arg("--input")
+        .arg(&input_file_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then each warning is reported with its rule and location.
+        .stdout(
+            predicate::str::is_match(
+                "warning 'suspicious-delay' at '.+:2': 'DELAY 5' is outside of 10 to 60000 ms\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'trailing-whitespace' at '.+:3': 'STRING' ends with whitespace\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'duplicate-import' at '.+:5': File '\\./wait\\.ducky' is already imported at '.+:4'\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match("warning 'empty-branch' at '.+:6': 'IF TRUE' has no statements\\.")
+                .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'unreachable-code' at '.+:9': Statement is unreachable after 'STOP_PAYLOAD' at '.+:8'\\.",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "warning 'while-true-without-delay' at '.+:11': 'WHILE TRUE' loops forever without a 'DELAY'\\.",
+            )
+            .unwrap(),
+        )
+        // Then allowed rules aren't reported.
+        .stdout(predicate::str::contains("missing-defaultdelay").not())
+        // Then the lint completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_lint_duckyscript_valid_while_import() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a loop waiting in an imported file.
+    let temp_input_path = tempdir().unwrap();
+    std::fs::write(temp_input_path.path().join("wait.ducky"), "DELAY 100\n")?;
+    let input_file_path = temp_input_path.path().join("index.ducky");
+    std::fs::write(
+        &input_file_path,
+        r#"DEFAULTDELAY 20
+WHILE TRUE
+  STRING spam
+  IMPORT "./wait.ducky"
+END_WHILE
+"#,
+    )?;
+
+    // When the user lints the script.
+    let result = cmd
+        .arg("lint")
+        .arg("--input")
+        .arg(&input_file_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the loop isn't reported.
+        .stdout(predicate::str::contains("while-true-without-delay").not())
+        // Then the lint completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_lint_duckyscript_invalid_file_levels() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file configuring rules for the whole file and for one statement.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"DEFAULTDELAY 20
+DELAY 5
+STRING first 
+REM mallard: warning(trailing-whitespace)
+STRING second 
+REM mallard: file allow(suspicious-delay) error(trailing-whitespace)
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user lints the script.
+    let result = cmd
+        .arg("lint")
+        .arg("--input")
+        .arg(input_file.path())
+        .assert();
+
+    result
+        // Then file levels apply to every statement of the file.
+        .stdout(predicate::str::contains("suspicious-delay").not())
+        .stdout(
+            predicate::str::is_match(
+                "error 'trailing-whitespace' at '.+:3': 'STRING' ends with whitespace\\.",
+            )
+            .unwrap(),
+        )
+        // Then statement levels take precedence over file levels.
+        .stdout(
+            predicate::str::is_match(
+                "warning 'trailing-whitespace' at '.+:5': 'STRING' ends with whitespace\\.",
+            )
+            .unwrap(),
+        )
+        // Then the lint failed.
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_command_lint_duckyscript_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a busy loop and a rule raised to an error.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"REM mallard: error(missing-defaultdelay)
+WHILE TRUE
+  STRING spam
+END_WHILE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user lints the script.
+    let result = cmd
+        .arg("lint")
+        .arg("--input")
+        .arg(input_file.path())
+        .assert();
+
+    result
+        // Then each error is reported with its rule and location.
+        .stdout(
+            predicate::str::is_match(
+                "error 'missing-defaultdelay' at '.+:2': Payload never sets 'DEFAULTDELAY'",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(
+                "error 'while-true-without-delay' at '.+:2': 'WHILE TRUE' loops forever without a 'DELAY'\\.",
+            )
+            .unwrap(),
+        )
+        // Then the lint failed.
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("Lint found 2 error(s)."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_directives() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with compiler directives.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+REM mallard: allow(unused-variable)
+VAR $debug = 1
+VAR $unused = 1
+REM mallard: strip
+IF TRUE THEN
+  STRING debugging
+END_IF
+REM Open the terminal.
+REM mallard: strip
+DELAY 1
+STRING done
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with variable analysis.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--analyze")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then only diagnostics that aren't allowed are reported.
+        .stdout(predicate::str::contains("'$debug'").not())
+        .stdout(
+            predicate::str::is_match(
+                "warning 'unused-variable' at '.+:4': Variable '\\$unused' is declared but never read\\.",
+            )
+            .unwrap(),
+        )
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output has no directives or stripped statements.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $debug = 1
+VAR $unused = 1
+REM Open the terminal.
+STRING done"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_directives() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file allowing an unknown rule.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"
+REM mallard: allow(unused-variables)
+VAR $debug = 1
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then the build failed with the unknown rule.
+        .failure()
+        .code(2)
+        .stderr(predicate::str::is_match("Unknown rule 'unused-variables' at '.+:2'\\.").unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_minify() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with comments, blocks, imports and consecutive strings.
+    let temp_input_path = tempdir().unwrap();
+    std::fs::write(
+        temp_input_path.path().join("greet.ducky"),
+        "REM Greet.\nSTRING Hello, \nSTRINGLN World!\n",
+    )?;
+    let input_file_path = temp_input_path.path().join("index.ducky");
+    std::fs::write(
+        &input_file_path,
+        r#"REM Open the terminal.
+DELAY 500
+VAR $count = 2
+IMPORT "./greet.ducky"
+IF $count > 1 THEN
+  REM Greet again.
+  STRING $count
+END_IF
+"#,
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script minified.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(&input_file_path)
+        .arg("--output")
+        .arg(output_path)
+        .arg("--minify")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the size saved is reported.
+        .stdout(predicate::str::contains(
+            "Minified: 150 bytes to 87 bytes, saved 63 bytes.",
+        ))
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the build output is minified.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"DELAY 500
+VAR $count = 2
+STRINGLN Hello, World!
+IF $count > 1 THEN
+STRING $count
+END_IF"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_indentation() -> Result<(), Box<dyn std::error::Error>> {
+    for (indentation, output_expected) in [
+        (
+            "tab",
+            "VAR $count = 0\nWHILE $count < 3\n\t$count = $count + 1\n\tIF $count == 2 THEN\n\t\tENTER\n\tELSE\n\t\tTAB\n\tEND_IF\nEND_WHILE",
+        ),
+        (
+            "4",
+            "VAR $count = 0\nWHILE $count < 3\n    $count = $count + 1\n    IF $count == 2 THEN\n        ENTER\n    ELSE\n        TAB\n    END_IF\nEND_WHILE",
+        ),
+        (
+            "none",
+            "VAR $count = 0\nWHILE $count < 3\n$count = $count + 1\nIF $count == 2 THEN\nENTER\nELSE\nTAB\nEND_IF\nEND_WHILE",
+        ),
+    ] {
+        // Given the CLI.
+        let mut cmd = Command::cargo_bin("mallardscript")?;
+
+        // And DuckyScript file with nested blocks.
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            String::from(
+                r#"
+VAR $count = 0
+WHILE $count < 3
+  $count = $count + 1
+  IF $count == 2 THEN
+    ENTER
+  ELSE
+    TAB
+  END_IF
+END_WHILE
+"#,
+            )
+            .as_bytes(),
+        )?;
+
+        // And an output directory.
+        let temp_output_path = tempdir().unwrap();
+        let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+        // When the user builds the script with an indentation style.
+        let result = cmd
+            .arg("build")
+            .arg("--input")
+            .arg(input_file.path())
+            .arg("--output")
+            .arg(output_path)
+            .arg("--indent")
+            .arg(indentation)
+            .assert();
+
+        result
+            // Then no errors occurred.
+            .success()
+            .stderr(predicate::str::is_empty())
+            // Then the build completed successfully.
+            .stdout(predicate::str::contains("Done."));
+
+        // Then the build output blocks use the indentation style.
+        let mut output_file_path =
+            std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+        output_file_path.push("index.ducky");
+
+        let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+        assert_eq!(output_contents, output_expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_import_in_block() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency A with an IF block.
+    let mut input_file_dependency_a = NamedTempFile::new()?;
+    input_file_dependency_a.write_all(
+        String::from(
+            r#"
+STRINGLN Typing From A...
+IF $count == 2 THEN
+  ENTER
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And empty DuckyScript file dependency B.
+    let input_file_dependency_b = NamedTempFile::new()?;
+
+    // And DuckyScript file importing both dependencies inside a WHILE block.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"
+VAR $count = 0
+WHILE $count < 3
+  $count = $count + 1
+  IMPORT "{}"
+  IMPORT "{}"
+  STRINGLN Typing From Index...
+END_WHILE
+"#,
+            input_file_dependency_a.path().as_os_str().to_str().unwrap(),
+            input_file_dependency_b.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then every imported line is indented inside the block, without blank lines.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"VAR $count = 0
+WHILE $count < 3
+  $count = $count + 1
+  STRINGLN Typing From A...
+  IF $count == 2 THEN
+    ENTER
+  END_IF
+  STRINGLN Typing From Index...
+END_WHILE"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_import_in_block() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency with a DEFINE command.
+    let mut input_file_dependency = NamedTempFile::new()?;
+    input_file_dependency.write_all(
+        String::from(
+            r#"STRINGLN Typing From Dependency...
+DEFINE WAIT 500
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And DuckyScript file importing the dependency inside an IF block.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"IF TRUE THEN
+  IMPORT "{}"
+END_IF
+"#,
+            input_file_dependency.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    // Then the build failed because DEFINE can't be nested.
+    result.failure().stderr(predicate::str::contains(format!(
+        "DEFINE 'WAIT' at '{}:2' can't be nested in a block, defines are global.",
+        input_file_dependency.path().display()
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_target_duckyscript1(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency with an IF block.
+    let mut input_file_dependency = NamedTempFile::new()?;
+    input_file_dependency.write_all(
+        String::from(
+            r#"IF $count == 2 THEN
+  DELAY $count
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And DuckyScript file with variables, defines, loops and the dependency.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"DEFINE URL example.com
+VAR $count = 0
+WHILE $count < 2
+  $count = $count + 1
+  STRING $count
+END_WHILE
+IF $count == 2 THEN
+  STRINGLN open URL
+ELSE
+  STRINGLN never
+END_IF
+IMPORT "{}"
+GUI r
+"#,
+            input_file_dependency.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for DuckyScript 1.0.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--target")
+        .arg("duckyscript1")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the DuckyScript 3.0 statements are lowered at compile time.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"STRING 1
+STRING 2
+STRING open example.com
+ENTER
+DELAY 2
+GUI r"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_target_duckyscript1(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with a condition on the device state.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"STRING start
+IF $_CAPSLOCK_ON == TRUE THEN
+  CAPSLOCK
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for DuckyScript 1.0.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--target")
+        .arg("duckyscript1")
+        .assert();
+
+    // Then the build failed because the condition isn't known at compile time.
+    result
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "IF at '{}:2' can't be lowered to DuckyScript 1.0, it isn't known at compile time.",
+            input_file.path().display()
+        )))
+        .stderr(predicate::str::contains(
+            "Variable '$_CAPSLOCK_ON' isn't set.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_target_flipper() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with key commands and a runtime loop.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"WAIT_FOR_BUTTON_PRESS
+CTRL ALT DELETE
+COMMAND SPACE
+LOOP 2
+  DOWNARROW
+END_LOOP
+ESCAPE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for Flipper Zero.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--target")
+        .arg("flipper")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the keys are written as Flipper Zero BadUSB presses them.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"WAIT_FOR_BUTTON_PRESS
+CTRL-ALT DELETE
+GUI SPACE
+DOWN
+DOWN
+ESC"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_target_flipper() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file pressing three modifiers together.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("STRING start\nCTRL SHIFT ALT t\n").as_bytes())?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for Flipper Zero.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--target")
+        .arg("flipper")
+        .assert();
+
+    // Then the build failed at the key command.
+    result
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "'CTRL SHIFT ALT t' at '{}:2' can't be lowered to Flipper Zero BadUSB.",
+            input_file.path().display()
+        )))
+        .stderr(predicate::str::contains(
+            "Modifiers 'CTRL SHIFT ALT' can't be pressed together, at most two are supported.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_target_flipper_commands(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with Flipper Zero BadUSB commands MallardScript can't express.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"DEFAULT_STRING_DELAY 50
+HOLD CTRL
+ALTCHAR 65
+RELEASE CTRL
+LOOP 2
+  ALTSTRING abc
+END_LOOP
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for Flipper Zero.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--target")
+        .arg("flipper")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    // Then the commands are written as they are, unrolled with their loop.
+    let mut output_file_path =
+        std::path::PathBuf::from(shellexpand::tilde(output_path).into_owned());
+    output_file_path.push("index.ducky");
+
+    let output_contents = std::fs::read_to_string(output_file_path).unwrap();
+    assert_eq!(
+        output_contents,
+        r#"DEFAULT_STRING_DELAY 50
+HOLD CTRL
+ALTCHAR 65
+RELEASE CTRL
+ALTSTRING abc
+ALTSTRING abc"#,
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_device() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file setting the LED.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("STRING start\nLED_R\n").as_bytes())?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for the Bash Bunny.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--device")
+        .arg("bash-bunny")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then a warning is reported for the LED command.
+        .stdout(predicate::str::contains(format!(
+            "'LED_R' at '{}:2' may not run on the Bash Bunny, LED is a bash payload command.",
+            input_file.path().display()
+        )))
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_device() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file waiting for the button in a block.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"VAR $ready = TRUE
+IF $ready THEN
+  WAIT_FOR_BUTTON_PRESS
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for the O.MG cable.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--device")
+        .arg("omg")
+        .assert();
+
+    // Then the build failed because the device has no button.
+    result.failure().stderr(predicate::str::contains(format!(
+        "'WAIT_FOR_BUTTON_PRESS' at '{}:3' isn't supported by the O.MG cable, it has no button.",
+        input_file.path().display()
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_device_constructs(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given DuckyScript file with a WHILE loop.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"STRING start
+WHILE FALSE
+  TAB
+END_WHILE
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script for the Bash Bunny.
+    let result = Command::cargo_bin("mallardscript")?
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--device")
+        .arg("bash-bunny")
+        .assert();
+
+    // Then the build failed because the device runs DuckyScript 1.0.
+    result.failure().stderr(predicate::str::contains(format!(
+        "'WHILE' at '{}:2' isn't supported by the Bash Bunny, it runs DuckyScript 1.0, compile with '--target duckyscript1'.",
+        input_file.path().display()
+    )));
+
+    // When the user builds the script for the Bash Bunny as DuckyScript 1.0.
+    let result = Command::cargo_bin("mallardscript")?
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--device")
+        .arg("bash-bunny")
+        .arg("--target")
+        .arg("duckyscript1")
+        .assert();
+
+    // Then the loop is lowered before it reaches the device.
+    result
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And a metadata file of the project.
+    let mut metadata_file = NamedTempFile::new()?;
+    metadata_file.write_all(
+        String::from("# Project\nauthor=Red Team\ntarget=Windows\nversion=1.0\ntitle=Untitled\n")
+            .as_bytes(),
+    )?;
+
+    // And DuckyScript file declaring its metadata.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"REM meta: title=Open URL
+REM meta: description=Opens example.com
+GUI r
+STRING example.com
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with the metadata file.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--metadata-file")
+        .arg(metadata_file.path())
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the metadata is reported.
+        .stdout(predicate::str::contains(
+            "  Metadata: 'Open URL' version '1.0'.",
+        ));
+
+    // Then the output starts with the metadata header.
+    let output_contents = std::fs::read_to_string(temp_output_path.path().join("index.ducky"))?;
+    assert_eq!(
+        output_contents,
+        r#"REM Title: Open URL
+REM Author: Red Team
+REM Description: Opens example.com
+REM Target: Windows
+REM Version: 1.0
+GUI r
+STRING example.com"#,
+    );
+
+    // Then the metadata is written to the manifest.
+    let manifest: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(
+        temp_output_path.path().join("manifest.json"),
+    )?)?;
+    assert_eq!(manifest["artifact"], "index.ducky");
+    assert_eq!(manifest["metadata"]["title"], "Open URL");
+    assert_eq!(manifest["metadata"]["author"], "Red Team");
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file declaring only some metadata.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("REM meta: title=Open URL\nGUI r\n").as_bytes())?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    // Then the build failed because required metadata is missing.
+    result.failure().stderr(predicate::str::contains(
+        "Missing required metadata 'author', 'description', 'target', 'version', declare it with 'REM meta: key=value'.",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_metadata_import() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And a project importing a DuckyScript file declaring metadata.
+    let project = tempdir()?;
+    std::fs::write(
+        project.path().join("index.ducky"),
+        "IMPORT \"part.ducky\"\n",
+    )?;
+    std::fs::write(
+        project.path().join("part.ducky"),
+        "ENTER\nREM meta: title=Open URL\n",
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the project.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(project.path().join("index.ducky"))
+        .arg("--output")
+        .arg(output_path)
+        .assert();
+
+    // Then the build failed at the metadata of the imported file.
+    result.failure().stderr(predicate::str::contains(
+        "Metadata at 'part.ducky:2' must be declared in the entry file.",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a project importing a DuckyScript file, with a define file.
+    let project = tempdir()?;
+    std::fs::write(
+        project.path().join("index.ducky"),
+        "STRING #NAME\nIMPORT \"part.ducky\"\n",
+    )?;
+    std::fs::write(project.path().join("part.ducky"), "ENTER\n")?;
+    std::fs::write(project.path().join("defines.env"), "NAME=duck\n")?;
+    let hash = |contents: &[u8]| -> String {
+        Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    };
+
+    // When the user builds the project twice with a manifest.
+    let mut manifests = vec![];
+    for output in ["first", "second"] {
+        std::fs::create_dir(project.path().join(output))?;
+        Command::cargo_bin("mallardscript")?
+            .current_dir(project.path())
+            .arg("build")
+            .arg("--input")
+            .arg("index.ducky")
+            .arg("--output")
+            .arg(output)
+            .arg("--define-file")
+            .arg("defines.env")
+            .arg("--manifest")
+            .assert()
+            // Then no errors occurred.
+            .success()
+            .stderr(predicate::str::is_empty())
+            // Then the manifest is reported.
+            .stdout(predicate::str::contains("  Sources: 2 file(s) hashed."));
+
+        manifests.push((
+            std::fs::read(project.path().join(output).join("index.ducky"))?,
+            std::fs::read(project.path().join(output).join("manifest.json"))?,
+        ));
+    }
+
+    // Then both builds are byte-identical.
+    assert_eq!(manifests[0], manifests[1]);
+
+    // Then the manifest hashes the build inputs and output.
+    let (artifact, manifest) = &manifests[0];
+    let manifest: serde_json::Value = serde_json::from_slice(manifest)?;
+    assert_eq!(manifest["compiler"]["name"], "mallardscript");
+    assert_eq!(manifest["compiler"]["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(manifest["target"], "duckyscript3");
+    assert_eq!(manifest["device"], serde_json::Value::Null);
+    assert_eq!(manifest["defines"]["NAME"], "duck");
+    assert_eq!(
+        manifest["define_file"],
+        serde_json::json!({
+            "path": "defines.env",
+            "sha256": hash(b"NAME=duck\n"),
+        })
+    );
+    assert_eq!(
+        manifest["sources"],
+        serde_json::json!([
+            {
+                "path": "index.ducky",
+                "sha256": hash(b"STRING #NAME\nIMPORT \"part.ducky\"\n"),
+            },
+            {
+                "path": "part.ducky",
+                "sha256": hash(b"ENTER\n"),
+            },
+        ])
+    );
+    assert_eq!(manifest["artifact_sha256"], hash(artifact).as_str());
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_invalid_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file importing a missing file.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("IMPORT \"missing.ducky\"\n").as_bytes())?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with a manifest.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--manifest")
+        .assert();
+
+    // Then the build failed.
+    result.failure();
+
+    // Then no manifest is written.
+    assert!(!temp_output_path.path().join("manifest.json").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_cache() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a project importing a DuckyScript file twice.
+    let project = tempdir()?;
+    std::fs::write(
+        project.path().join("index.ducky"),
+        "STRING start\nIMPORT \"part.ducky\"\nIMPORT \"part.ducky\"\n",
+    )?;
+    std::fs::write(project.path().join("part.ducky"), "ENTER\n")?;
+    std::fs::create_dir(project.path().join("output"))?;
+    let build = |cache: &str| -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin("mallardscript")?
+            .current_dir(project.path())
+            .arg("build")
+            .arg("--input")
+            .arg("index.ducky")
+            .arg("--output")
+            .arg("output")
+            .arg("--cache-dir")
+            .arg(".cache")
+            .output()?;
+        // Then no errors occurred.
+        assert!(output.status.success());
+        assert!(predicate::str::contains(cache).eval(&String::from_utf8(output.stdout)?));
+        Ok(std::fs::read_to_string(
+            project.path().join("output").join("index.ducky"),
+        )?)
+    };
+
+    // When the user builds the project, each file is parsed once.
+    let output_uncached = build("  Cache: 4 hit(s), 2 miss(es).")?;
+
+    // When the user builds it again, every file is cached with the same output.
+    assert_eq!(build("  Cache: 6 hit(s), 0 miss(es).")?, output_uncached);
+
+    // When the user changes the imported file, only it is parsed again.
+    std::fs::write(project.path().join("part.ducky"), "TAB\n")?;
+    assert_eq!(
+        build("  Cache: 5 hit(s), 1 miss(es).")?,
+        "STRING start\nTAB\nTAB"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_cache_unwritable() -> Result<(), Box<dyn std::error::Error>>
+{
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("ENTER\n").as_bytes())?;
+
+    // And a cache directory that is a file.
+    let cache_file = NamedTempFile::new()?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with the cache.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--cache-dir")
+        .arg(cache_file.path())
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the cache directory that can't be created is warned about.
+        .stdout(predicate::str::contains(format!(
+            "Unable to create cache directory '{}'.",
+            cache_file.path().display()
+        )))
+        // Then the build completed successfully.
+        .stdout(predicate::str::contains("Done."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_cache_pruned() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a project with a DuckyScript file.
+    let project = tempdir()?;
+    std::fs::write(project.path().join("index.ducky"), "ENTER\n")?;
+    std::fs::create_dir(project.path().join("output"))?;
+
+    // And a cache directory full of entries of other builds.
+    let cache_directory = project.path().join(".cache");
+    std::fs::create_dir(&cache_directory)?;
+    let modified = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    for index in 0..1024 {
+        let entry_path = cache_directory.join(format!("{:064}.json", index));
+        std::fs::write(&entry_path, "[]")?;
+        std::fs::File::options()
+            .write(true)
+            .open(&entry_path)?
+            .set_modified(modified)?;
+    }
+
+    // When the user builds the project.
+    Command::cargo_bin("mallardscript")?
+        .current_dir(project.path())
+        .arg("build")
+        .arg("--input")
+        .arg("index.ducky")
+        .arg("--output")
+        .arg("output")
+        .arg("--cache-dir")
+        .arg(".cache")
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stdout(predicate::str::contains("  Cache: 1 hit(s), 1 miss(es)."));
+
+    // Then the least recently used entry was removed, keeping the entry of the build.
+    let entries = std::fs::read_dir(&cache_directory)?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<String>, std::io::Error>>()?;
+    assert_eq!(entries.len(), 1024);
+    let entry = format!(
+        "{:x}.json",
+        Sha256::digest(format!("{}\0ENTER\n", env!("CARGO_PKG_VERSION")).as_bytes())
+    );
+    assert!(entries.contains(&entry));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_report_timing() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file dependency with delays and typing.
+    let mut input_file_dependency = NamedTempFile::new()?;
+    input_file_dependency.write_all(
+        String::from(
+            r#"
+STRINGLN hello
+ENTER
+DELAY 500
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And DuckyScript file importing the dependency in a loop, with a runtime loop.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        format!(
+            r#"
+DEFAULTDELAY 100
+VAR $count = 0
+LOOP 3
+  IMPORT "{}"
+END_LOOP
+WHILE $count < 3
+  $count = $count + 1
+END_WHILE
+"#,
+            input_file_dependency.path().as_os_str().to_str().unwrap()
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with a timing report.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--report")
+        .arg("timing")
+        .arg("--chars-per-second")
+        .arg("10")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the estimated time of each file is reported, with the runtime loop unknown.
+        .stdout(predicate::str::contains(format!(
+            r#"  Timing at 10 characters per second:
+    '{}': at least 0 ms
+    '{}': 4200 ms
+    Unknown at '{}:7': WHILE runs until '$count < 3' is FALSE at runtime.
+    Total: at least 4200 ms
+Done."#,
+            input_file.path().display(),
+            input_file_dependency.path().display(),
+            input_file.path().display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_report_timing_literals(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file typing values that look like expressions.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(String::from("STRING 42\nSTRING TRUE\n").as_bytes())?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with a timing report.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--report")
+        .arg("timing")
+        .arg("--chars-per-second")
+        .arg("10")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the values are typed as text, known at compile time.
+        .stdout(predicate::str::contains("Unknown").not())
+        .stdout(predicate::str::contains("    Total: 600 ms\nDone."));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_build_duckyscript_valid_report_timing_runtime_if(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file branching at runtime and typing a random character.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"IF $_CAPSLOCK_ON THEN
+  DELAY 500
+ELSE
+  DELAY 100
+END_IF
+RANDOM_NUMBER
+ENTER
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And an output directory.
+    let temp_output_path = tempdir().unwrap();
+    let output_path = temp_output_path.path().as_os_str().to_str().unwrap();
+
+    // When the user builds the script with a timing report.
+    let result = cmd
+        .arg("build")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--output")
+        .arg(output_path)
+        .arg("--report")
+        .arg("timing")
+        .arg("--chars-per-second")
+        .arg("10")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the runtime branch is unknown, counting its shorter branch and each key press.
+        .stdout(predicate::str::contains(format!(
+            r#"    Unknown at '{}:1': IF '$_CAPSLOCK_ON' is decided at runtime, only its shorter branch is included.
+    Total: at least 300 ms
+Done."#,
+            input_file.path().display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_simulate_duckyscript_valid() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file with variables, blocks, delays and keys.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"DEFAULTDELAY 100
+VAR $count = 0
+GUI r
+WHILE $count < 2
+  $count = $count + 1
+  IF $count == 2 THEN
+    STRINGLN done
+  ELSE
+    DELAY 500
+  END_IF
+END_WHILE
+STOP_PAYLOAD
+STRING unreachable
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user simulates the script.
+    let result = cmd
+        .arg("simulate")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--chars-per-second")
+        .arg("10")
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the keystroke timeline is printed.
+        .stdout(predicate::str::contains(format!(
+            r#"Simulate MallardScript.
+  Current Directory: '{}'
+  Input: '{}'
+         0 ms PRESS GUI r at '{}:3'
+       700 ms TYPE 'done' at '{}:7'
+      1100 ms PRESS ENTER at '{}:7'
+      1300 ms COMMAND STOP_PAYLOAD at '{}:12'
+  Duration: 1400 ms
+Done."#,
+            std::env::current_dir().unwrap().display(),
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_simulate_duckyscript_valid_json() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file typing a variable.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"VAR $count = 40 + 2
+STRING $count
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user simulates the script as JSON.
+    let output = cmd
+        .arg("simulate")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--format")
+        .arg("json")
+        .output()?;
+
+    // Then the timeline is printed alone as JSON.
+    assert!(output.status.success());
+    let timeline: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        timeline,
+        serde_json::json!({
+            "events": [{
+                "time": 0,
+                "location": format!("{}:2", input_file.path().display()),
+                "action": "type",
+                "text": "42",
+            }],
+            "duration": 40,
+        })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_simulate_duckyscript_valid_scenario() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file branching on the host and waiting for the device.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"IF $_OS == "WINDOWS" THEN
+  GUI r
+ELSE
+  STRING terminal
+END_IF
+WAIT_FOR_BUTTON_PRESS
+WAIT_FOR_CAPS_ON
+IF $_CAPSLOCK_ON THEN
+  CAPSLOCK
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And a scenario of a Windows host with the button pressed then caps lock turned on.
+    let mut scenario_file = NamedTempFile::new()?;
+    scenario_file.write_all(
+        String::from(
+            r#"{
+  "variables": { "_OS": "WINDOWS" },
+  "inputs": [
+    { "time": 3000, "button": true },
+    { "time": 5000, "variables": { "_CAPSLOCK_ON": true } }
+  ],
+  "expect": [
+    "PRESS GUI r",
+    "COMMAND WAIT_FOR_BUTTON_PRESS",
+    "COMMAND WAIT_FOR_CAPS_ON",
+    "PRESS CAPSLOCK"
+  ]
+}"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user simulates the script with the scenario.
+    let result = cmd
+        .arg("simulate")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--scenario")
+        .arg(scenario_file.path())
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the payload waited for the scenario inputs and matched the expected actions.
+        .stdout(predicate::str::contains(format!(
+            r#"         0 ms PRESS GUI r at '{}:2'
+        20 ms COMMAND WAIT_FOR_BUTTON_PRESS at '{}:6'
+      3000 ms COMMAND WAIT_FOR_CAPS_ON at '{}:7'
+      5000 ms PRESS CAPSLOCK at '{}:9'
+  Duration: 5020 ms
+  Expectations: 4 action(s) matched.
+Done."#,
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_simulate_duckyscript_valid_scenario_button_presses(
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file polling the button then waiting for it.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"LOOP 4
+  DELAY 1000
+  IF $_BUTTON_PUSH_RECEIVED THEN
+    STRING pressed
+  END_IF
+END_LOOP
+WAIT_FOR_BUTTON_PRESS
+IF $_BUTTON_PUSH_RECEIVED THEN
+  STRING again
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And a scenario of the button pressed three times.
+    let mut scenario_file = NamedTempFile::new()?;
+    scenario_file.write_all(
+        String::from(
+            r#"{
+  "inputs": [
+    { "time": 1500, "button": true },
+    { "time": 3500, "button": true },
+    { "time": 6000, "button": true }
+  ]
+}"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // When the user simulates the script with the scenario.
+    let result = cmd
+        .arg("simulate")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--scenario")
+        .arg(scenario_file.path())
+        .assert();
+
+    result
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then each press is consumed once, by a condition or the wait.
+        .stdout(predicate::str::contains(format!(
+            r#"      2000 ms TYPE 'pressed' at '{}:4'
+      4140 ms TYPE 'pressed' at '{}:4'
+      4280 ms COMMAND WAIT_FOR_BUTTON_PRESS at '{}:7'
+  Duration: 6000 ms
+Done."#,
+            input_file.path().display(),
+            input_file.path().display(),
+            input_file.path().display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_simulate_duckyscript_invalid_scenario() -> Result<(), Box<dyn std::error::Error>> {
+    // Given the CLI.
+    let mut cmd = Command::cargo_bin("mallardscript")?;
+
+    // And DuckyScript file branching on the host.
+    let mut input_file = NamedTempFile::new()?;
+    input_file.write_all(
+        String::from(
+            r#"IF $_OS == "WINDOWS" THEN
+  STRING cmd
+ELSE
+  STRING terminal
+END_IF
+"#,
+        )
+        .as_bytes(),
+    )?;
+
+    // And a scenario of a Linux host expecting Windows actions.
+    let mut scenario_file = NamedTempFile::new()?;
+    scenario_file.write_all(
+        String::from(r#"{ "variables": { "_OS": "LINUX" }, "expect": ["TYPE 'cmd'"] }"#).as_bytes(),
+    )?;
+
+    // When the user simulates the script with the scenario.
+    let result = cmd
+        .arg("simulate")
+        .arg("--input")
+        .arg(input_file.path())
+        .arg("--scenario")
+        .arg(scenario_file.path())
+        .assert();
+
+    // Then the simulation failed with the mismatched action.
+    result.failure().stderr(predicate::str::contains(format!(
+        "Expected action 1 to be 'TYPE 'cmd'' but found 'TYPE 'terminal'' at '{}:4'.",
+        input_file.path().display()
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_test_fixtures_valid_update() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a fixtures directory.
+    let fixtures_directory = tempdir()?;
+
+    // And a fixture file.
+    std::fs::write(
+        fixtures_directory.path().join("hello.test.ducky"),
+        "STRING hello\nENTER\n",
+    )?;
+
+    // And a fixture directory with a scenario.
+    let fixture_directory = fixtures_directory.path().join("windows");
+    std::fs::create_dir(&fixture_directory)?;
+    std::fs::write(
+        fixture_directory.join("input.ducky"),
+        "IF $_OS == \"WINDOWS\" THEN\n  GUI r\nEND_IF\n",
+    )?;
+    std::fs::write(
+        fixture_directory.join("scenario.json"),
+        r#"{ "variables": { "_OS": "WINDOWS" } }"#,
+    )?;
+
+    // When the user updates the fixtures.
+    Command::cargo_bin("mallardscript")?
+        .arg("test")
+        .arg("--path")
+        .arg(fixtures_directory.path())
+        .arg("--update")
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    // Then the expected files are written.
+    assert_eq!(
+        std::fs::read_to_string(fixtures_directory.path().join("hello.expected.ducky"))?,
+        "STRING hello\nENTER"
+    );
+    assert_eq!(
+        std::fs::read_to_string(fixture_directory.join("expected.ducky"))?,
+        "IF $_OS == \"WINDOWS\" THEN\n  GUI r\nEND_IF"
+    );
+    assert_eq!(
+        std::fs::read_to_string(fixture_directory.join("expected.keys"))?,
+        "       0 ms PRESS GUI r at 'input.ducky:2'\n"
+    );
+
+    // When the user tests the fixtures.
+    Command::cargo_bin("mallardscript")?
+        .arg("test")
+        .arg("--path")
+        .arg(fixtures_directory.path())
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the fixtures passed.
+        .stdout(predicate::str::contains(format!(
+            r#"  ok '{}'
+  ok '{}'
+  Fixtures: 2 passed, 0 failed.
+Done."#,
+            fixtures_directory.path().join("hello.test.ducky").display(),
+            fixture_directory.join("input.ducky").display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_test_fixtures_valid_options() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a fixtures directory.
+    let fixtures_directory = tempdir()?;
+
+    // And a fixture file with a define and a loop.
+    std::fs::write(
+        fixtures_directory.path().join("hello.test.ducky"),
+        "LOOP 2\n  STRINGLN #GREETING\nEND_LOOP\n",
+    )?;
+
+    // And options compiling it to DuckyScript 1.0 for the Bash Bunny.
+    std::fs::write(
+        fixtures_directory.path().join("hello.options.json"),
+        r#"{ "defines": { "GREETING": "hello" }, "target": "duckyscript1", "device": "bash-bunny" }"#,
+    )?;
+
+    // When the user updates the fixtures.
+    Command::cargo_bin("mallardscript")?
+        .arg("test")
+        .arg("--path")
+        .arg(fixtures_directory.path())
+        .arg("--update")
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    // Then the expected output is compiled with the options.
+    assert_eq!(
+        std::fs::read_to_string(fixtures_directory.path().join("hello.expected.ducky"))?,
+        "STRING hello\nENTER\nSTRING hello\nENTER"
+    );
+
+    // When the options have an unknown key.
+    std::fs::write(
+        fixtures_directory.path().join("hello.options.json"),
+        r#"{ "optimise": true }"#,
+    )?;
+
+    // When the user tests the fixtures.
+    Command::cargo_bin("mallardscript")?
+        .arg("test")
+        .arg("--path")
+        .arg(fixtures_directory.path())
+        .assert()
+        // Then the fixture failed.
+        .failure()
+        .stderr(predicate::str::contains("1 fixture(s) failed."))
+        // Then the invalid options are printed.
+        .stdout(predicate::str::contains(format!(
+            "Invalid options file '{}'.",
+            fixtures_directory
+                .path()
+                .join("hello.options.json")
+                .display()
+        )))
+        .stdout(predicate::str::contains(
+            "Unknown options key 'optimise', expected one of 'defines', 'target', 'device'.",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_test_fixtures_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a fixtures directory.
+    let fixtures_directory = tempdir()?;
+
+    // And a fixture file with an outdated expected output.
+    std::fs::write(
+        fixtures_directory.path().join("hello.test.ducky"),
+        "STRING hello\nTAB\nENTER\n",
+    )?;
+    std::fs::write(
+        fixtures_directory.path().join("hello.expected.ducky"),
+        "STRING hello\nENTER",
+    )?;
+
+    // When the user tests the fixtures.
+    let result = Command::cargo_bin("mallardscript")?
+        .arg("test")
+        .arg("--path")
+        .arg(fixtures_directory.path())
+        .assert();
+
+    result
+        // Then the fixture failed.
+        .failure()
+        .stderr(predicate::str::contains("1 fixture(s) failed."))
+        // Then a diff of the expected output is printed.
+        .stdout(predicate::str::contains(format!(
+            r#"  FAILED '{}'
+    '{}' doesn't match:
+       STRING hello
+      +TAB
+       ENTER
+  Fixtures: 0 passed, 1 failed."#,
+            fixtures_directory.path().join("hello.test.ducky").display(),
+            fixtures_directory
+                .path()
+                .join("hello.expected.ducky")
+                .display()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn test_command_convert() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a legacy DuckyScript payload.
+    let input_directory = tempdir()?;
+    let input_path = input_directory.path().join("payload.txt");
+    std::fs::write(
+        &input_path,
+        "DEFAULT_DELAY 0\nGUI r\nSTRING cmd\nENTER\nTAB\nREPEAT 2\nIF ($x == 1) THEN\n    STRING x\nEND_IF\n",
+    )?;
+    let output_directory = input_directory.path().join("converted");
+
+    // When the user converts the payload.
+    Command::cargo_bin("mallardscript")?
+        .arg("convert")
+        .arg("--input")
+        .arg(&input_path)
+        .arg("--output")
+        .arg(&output_directory)
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    // Then idiomatic MallardScript is written.
+    assert_eq!(
+        std::fs::read_to_string(output_directory.join("index.ducky"))?,
+        r#"DEFAULTDELAY 0
+GUI r
+STRINGLN cmd
+LOOP 3
+  TAB
+END_LOOP
+IF ($x == 1) THEN
+  STRING x
+END_IF
+"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_convert_extract() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a DuckyScript payload repeating a sequence of statements.
+    let input_directory = tempdir()?;
+    let input_path = input_directory.path().join("payload.txt");
+    std::fs::write(
+        &input_path,
+        "VAR $a = 1\nSTRING one\nDELAY 100\nTAB\nGUI r\nSTRING one\nDELAY 100\nTAB\n",
+    )?;
+    let output_directory = input_directory.path().join("converted");
+
+    // When the user converts the payload extracting modules.
+    Command::cargo_bin("mallardscript")?
+        .arg("convert")
+        .arg("--input")
+        .arg(&input_path)
+        .arg("--output")
+        .arg(&output_directory)
+        .arg("--extract")
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the extracted module is reported.
+        .stdout(predicate::str::contains(
+            "  Extracted: 'module_1.ducky' imported 2 times.\nDone.",
+        ));
+
+    // Then the repeated sequence is imported from a module.
+    assert_eq!(
+        std::fs::read_to_string(output_directory.join("index.ducky"))?,
+        "VAR $a = 1\nIMPORT \"module_1.ducky\"\nGUI r\nIMPORT \"module_1.ducky\"\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(output_directory.join("module_1.ducky"))?,
+        "STRING one\nDELAY 100\nTAB\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_command_convert_extract_large() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a large DuckyScript payload repeating a sequence between unique statements.
+    // And a statement repeated more times than a compile time loop can unroll.
+    let input_directory = tempdir()?;
+    let input_path = input_directory.path().join("payload.txt");
+    let input_contents = (0..500)
+        .map(|index| format!("STRING one\nDELAY 100\nTAB\nSTRING field {}\n", index))
+        .collect::<String>()
+        + "TAB\nREPEAT 20000\n";
+    std::fs::write(&input_path, input_contents)?;
+    let output_directory = input_directory.path().join("converted");
+
+    // When the user converts the payload extracting modules.
+    Command::cargo_bin("mallardscript")?
+        .arg("convert")
+        .arg("--input")
+        .arg(&input_path)
+        .arg("--output")
+        .arg(&output_directory)
+        .arg("--extract")
+        .assert()
+        // Then no errors occurred.
+        .success()
+        .stderr(predicate::str::is_empty())
+        // Then the repeated sequence is extracted once.
+        .stdout(predicate::str::contains(
+            "  Extracted: 'module_1.ducky' imported 500 times.\nDone.",
+        ));
+
+    // Then the repeated sequence is imported from a module.
+    assert_eq!(
+        std::fs::read_to_string(output_directory.join("module_1.ducky"))?,
+        "STRING one\nDELAY 100\nTAB\n"
+    );
+
+    // Then the repeated statement runs in a runtime loop.
+    assert!(
+        std::fs::read_to_string(output_directory.join("index.ducky"))?
+            .ends_with("LOOP 20001\n  TAB\nEND_LOOP\n")
+    );
+
+    // Then the converted payload builds.
+    let build_directory = tempdir()?;
+    Command::cargo_bin("mallardscript")?
+        .arg("build")
+        .arg("--input")
+        .arg(output_directory.join("index.ducky"))
+        .arg("--output")
+        .arg(build_directory.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+
+    Ok(())
+}